@@ -0,0 +1,710 @@
+//! The `mark` command: its arguments, usage, and subcommands, see [`run`].
+
+use super::*;
+
+/// Printed by `mark help`, `-h`, and `--help`. Keep it in sync with [`parse_flags`].
+const USAGE: &str = "\
+Usage: mark <subcommand> [flags] [arguments]
+
+Subcommands:
+  pack <paths>...      Archive files and directories to -output (or stdout)
+  unpack [names]...    Extract the archive at -input (or stdin) into -output (or the current
+                       directory), or only the named files
+  read                 List the files in an archive, with all of their headers, on stderr
+  list                 List the files in an archive like ls -l, on stdout
+  cat <name>           Print the contents of the named file in the archive at -input (or
+                       stdin) to stdout
+  manifest             Print the SHA-256 of every file in the archive at -input (or stdin),
+                       like sha256sum, to -output (or stdout)
+  verify               Check that every file in an archive is intact
+  append <paths>...    Add files to the end of the archive at -input
+  remove <names>...    Copy the archive at -input to -output without the named files
+  export-tar           Convert the archive at -input (or stdin) to a tar archive at -output
+                       (or stdout)
+  import-tar           Convert the tar archive at -input (or stdin) to an archive at -output
+                       (or stdout)
+  keygen               Make an Ed25519 key pair for -sign and -key (needs the signing feature)
+  help                 Show this message
+
+Flags, which can also be spelled with two dashes, like --input:
+  -i, -input <file>            The archive to read, instead of stdin
+  -o, -output <path>           Where to write the archive or unpacked files. Archives named
+                               *.gz or *.zst are compressed as a whole, and read back that way
+  -include-dotfiles            Pack files whose names start with a dot
+  -dereference                 Pack the files and directories symlinks point to, instead of
+                               storing the symlinks
+  -no-dereference              Store symlinks as symlinks (the default)
+  -compress <method>           Compress with none, brotli (the default), zstd, gzip, or xz
+  -level <level>               The compression level, on the scale of the method
+  -level-map <map>             Levels by file extension, like \"txt=11,log=5,*=9\"
+  -compress-map <map>          Methods by file extension, like \"jpg=none,log=zstd,*=brotli\"
+  -buffer-size <size>          How much brotli buffers at a time, from 1K to 16M (64K)
+  -brotli-window <bits>        The brotli window size as a power of two, from 10 to 24 (22)
+  -dict <train|file>           Compress with a zstd dictionary shared by the files, trained on
+                               them or read from a file (needs -compress zstd)
+  -auto-compress               Pick whichever method works best for each file
+  -force-compress              Compress files even if they look already compressed
+  -no-auto-store               Keep compressing files that compression makes bigger
+  -jobs <n>                    How many files to compress at once (the number of CPUs)
+  -block-size <size>           Compress files bigger than this in blocks of this size, using
+                               -jobs threads for each file
+  -stream                      Write an archive without a file count up front
+  -encode, -decode <encoding>  Write or read the archive as base64 or hex text
+  -crlf <mode>                 Convert text line endings: auto, to-lf, or to-crlf
+  -name <name>                 Pack stdin as a single file with this name, given no paths
+  -files-from <list>           Also pack the paths listed one per line in this file, or stdin
+                               for -
+  -files-from0 <list>          Like -files-from, with the paths separated by NUL bytes
+  -include <glob>              Only pack files whose names match, like '*.rs' (repeatable)
+  -exclude <glob>              Don't pack files or directories whose names match (repeatable),
+                               even if they're included
+  -exclude-if-present <name>   Skip directories containing a file with this name
+  -exclude-vcs                 Skip .git, .svn, .hg, .bzr, and CVS directories, even with
+                               -include-dotfiles
+  -min-size, -max-size <size>  Only pack files at least or at most this big, like 512K
+  -newer-than <time>           Only pack files modified after this time, in seconds since 1970
+  -newer-than-archive <file>   Only pack files that aren't in this archive, or that were
+                               modified since they were packed into it
+  -no-gitignore                Pack files even if a .gitignore says to ignore them
+  -ignore-missing              Skip paths to pack that don't exist
+  -skip-errors                 Leave out files that can't be read when packing, with a warning
+  -sparse                      Leave out the holes of sparse files, recreating them on unpack
+  -sort <order>                Store files in order of their path (the default), name, size, or
+                               ext(ension)
+  -max-depth <n>               Only pack files this many levels of directories below the paths
+                               given, with 0 packing only the paths themselves
+  -base <dir>                  Name packed files by their path inside of this directory,
+                               instead of the one each path given is in
+  -require-utf8-names          Refuse file names that aren't valid UTF-8
+  -strict                      Fail on archives with anything but their end after the last
+                               file when reading or unpacking
+  -subsec-times                Store timestamps with subsecond precision
+  -dedup                       Store the contents of identical files only once
+  -index                       Write an index of the files, so single ones can be found quickly
+  -digest                      End the archive with a SHA-256 of all of it, checked by verify
+  -reproducible                Pack the same files into the same archive on any machine, with
+                               times clamped to $SOURCE_DATE_EPOCH (or 0)
+  -max-memory <size>           Roughly how much memory file data may use, like 512M
+  -max-member-size <size>      Refuse archives with a file claiming more data than this, like 4G
+  -on-conflict <policy>        When unpacking over existing files: skip, overwrite, merge, or
+                               keep-newer
+  -overwrite                   Like -on-conflict merge
+  -strip-components <n>        Drop this many leading directories from unpacked names
+  -preserve-owner              Give unpacked files their packed owner and group (needs root)
+  -no-preserve-mode            Leave unpacked files with the default permissions instead of
+                               their packed ones
+  -mode-mask <bits>            Clear these octal permission bits, like 022, from unpacked files
+  -dir-mode <bits>             Give directories made for unpacked files, but not in the archive,
+                               these octal permissions, like 700
+  -salvage                     Unpack the whole files of a truncated archive, stopping where it
+                               ends
+  -allow-external-symlinks     Unpack symlinks that point outside of the output directory
+  -duplicates                  Report files with identical contents when reading
+  -json                        Print the contents of an archive as JSON when reading
+  -H                           List sizes like 1.5 MiB instead of in bytes
+  -dry-run                     Only list what would be packed or unpacked, writing nothing
+  -progress                    Report how many files and bytes are done on stderr
+  -progress-json               Report progress as lines of JSON on stderr
+  -stats                       Report how long walking, compressing, and writing took when
+                               packing, or decompression throughput when verifying
+  -manifest <file>             Write the SHA-256 of every packed file to this file, like
+                               sha256sum, so unpacked files can be checked with sha256sum -c
+  -sign <key>                  Sign the packed archive (needs the signing feature)
+  -key <key>                   Check the signature when verifying (needs the signing feature)
+  -passphrase <passphrase>     Encrypt the packed files with this, or decrypt unpacked ones
+                               (needs the encryption feature)
+  -passphrase-file <file>      Like -passphrase, with the passphrase read from the file
+  -verbose                     Report more of what's going on, like files that are skipped
+  -quiet                       Only report warnings and errors, not each file packed or unpacked
+  -h, --help                   Show this message
+  -V, --version                Show the version of mark and of the archives it writes
+  --                           Treat everything after this as arguments
+
+Exit codes:
+  0  Success
+  1  The arguments are wrong (-passphrase among them), or -max-memory is too low
+  2  Reading or writing files failed
+  3  The archive is malformed or truncated
+  4  Files in the archive don't match their checksums or fail to decrypt, or the signature
+     doesn't match
+  5  -salvage unpacked only the files before where the archive is truncated
+";
+
+/// Splits `args` into flags and positional arguments. With -h or -V, what they ask for is
+/// printed right away and there's nothing left to do, so it's `None`.
+pub(crate) fn parse_flags(args: Vec<String>) -> Result<Option<(Opts, Vec<String>)>, MarkError> {
+    let mut opts = Opts::default();
+    let mut positionals = vec![];
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            positionals.push(arg);
+            continue;
+        }
+
+        // Flags can be spelled with two dashes too, like `--input`
+        let original = arg;
+        let arg = match original.strip_prefix("--") {
+            Some(name) if !name.is_empty() => format!("-{name}"),
+            _ => original.clone(),
+        };
+        match arg.as_str() {
+            "--" => {
+                positionals.extend_from_slice(&args.collect::<Vec<_>>());
+                break;
+            }
+            "-h" | "-help" => {
+                print!("{USAGE}");
+                return Ok(None);
+            }
+            "-H" => {
+                opts.human_sizes = true;
+            }
+            "-V" | "-version" => {
+                println!(
+                    "mark {} (writes archive format version {CURRENT_VERSION})",
+                    env!("CARGO_PKG_VERSION")
+                );
+                return Ok(None);
+            }
+            "-i" | "-input" => {
+                let Some(input) = args.next() else {
+                    return Err(MarkError::Usage(
+                        "After -input, I expected a file path!".into(),
+                    ));
+                };
+                opts.input = Some(input);
+            }
+            "-o" | "-output" => {
+                let Some(output) = args.next() else {
+                    return Err(MarkError::Usage(
+                        "After -output, I expected a file path!".into(),
+                    ));
+                };
+                opts.output = Some(output);
+            }
+            "-include-dotfiles" => {
+                opts.include_dotfiles = true;
+            }
+            "-dereference" => {
+                opts.dereference = true;
+            }
+            "-no-dereference" => {
+                opts.dereference = false;
+            }
+            "-crlf" => {
+                let Some(line_endings) = args
+                    .next()
+                    .map(|x| x.to_lowercase())
+                    .and_then(|x| LineEndings::from_str(&x).ok())
+                else {
+                    return Err(MarkError::Usage(
+                        "I expected auto, to-lf, or to-crlf after -crlf".into(),
+                    ));
+                };
+                opts.line_endings = Some(line_endings);
+            }
+            "-files-from" | "-files-from0" => {
+                let Some(list) = args.next() else {
+                    return Err(MarkError::Usage(format!(
+                        "After {arg}, I expected a file path, or - for stdin!"
+                    )));
+                };
+                let separator = if arg == "-files-from0" { b'\0' } else { b'\n' };
+                opts.files_from = Some((list, separator));
+            }
+            "-include" | "-exclude" => {
+                let Some(pattern) = args.next() else {
+                    return Err(MarkError::Usage(format!(
+                        "After {arg}, I expected a pattern like '*.rs'!"
+                    )));
+                };
+                if arg == "-include" {
+                    opts.include.push(pattern);
+                } else {
+                    opts.exclude.push(pattern);
+                }
+            }
+            "-exclude-if-present" => {
+                let Some(marker) = args.next() else {
+                    return Err(MarkError::Usage(
+                        "After -exclude-if-present, I expected a file name!".into(),
+                    ));
+                };
+                opts.exclude_if_present.push(marker);
+            }
+            "-exclude-vcs" => {
+                opts.exclude_vcs = true;
+            }
+            "-sign" | "-key" => {
+                let Some(key) = args.next() else {
+                    return Err(MarkError::Usage(format!(
+                        "After {arg}, I expected a key file!"
+                    )));
+                };
+                #[cfg(feature = "signing")]
+                if arg == "-sign" {
+                    opts.sign = Some(key);
+                } else {
+                    opts.key = Some(key);
+                }
+                #[cfg(not(feature = "signing"))]
+                {
+                    return Err(MarkError::Usage(format!(
+                        "{arg} {key} needs mark to be built with the \"signing\" feature"
+                    )));
+                }
+            }
+            "-passphrase" | "-passphrase-file" => {
+                let Some(value) = args.next() else {
+                    return Err(MarkError::Usage(format!(
+                        "After {arg}, I expected a passphrase!"
+                    )));
+                };
+                #[cfg(feature = "encryption")]
+                {
+                    let passphrase = if arg == "-passphrase-file" {
+                        let passphrase = std::fs::read_to_string(&value)
+                            .context(|| format!("Failed to read \"{value}\""))?;
+                        passphrase.trim_end_matches(['\r', '\n']).to_string()
+                    } else {
+                        value
+                    };
+                    if passphrase.is_empty() {
+                        return Err(MarkError::Usage("The passphrase is empty!".into()));
+                    }
+                    opts.passphrase = Some(passphrase);
+                }
+                #[cfg(not(feature = "encryption"))]
+                {
+                    let _ = value;
+                    return Err(MarkError::Usage(format!(
+                        "{arg} needs mark to be built with the \"encryption\" feature"
+                    )));
+                }
+            }
+            "-no-gitignore" => {
+                opts.no_gitignore = true;
+            }
+            "-json" => {
+                opts.json = true;
+            }
+            "-dry-run" => {
+                opts.dry_run = true;
+            }
+            "-progress" => {
+                opts.progress = true;
+            }
+            "-progress-json" => {
+                opts.progress_json = true;
+            }
+            "-stats" => {
+                opts.stats = true;
+            }
+            "-name" => {
+                let Some(name) = args.next() else {
+                    return Err(MarkError::Usage(
+                        "After -name, I expected a file name!".into(),
+                    ));
+                };
+                opts.name = Some(name);
+            }
+            "-manifest" => {
+                let Some(manifest) = args.next() else {
+                    return Err(MarkError::Usage(
+                        "After -manifest, I expected a file path!".into(),
+                    ));
+                };
+                opts.manifest = Some(manifest);
+            }
+            "-reproducible" => {
+                // https://reproducible-builds.org/specs/source-date-epoch/
+                let epoch = match std::env::var("SOURCE_DATE_EPOCH") {
+                    Ok(epoch) => epoch.trim().parse().map_err(|_| {
+                        MarkError::Usage(format!(
+                            "SOURCE_DATE_EPOCH should be a number of seconds, not \"{epoch}\""
+                        ))
+                    })?,
+                    Err(_) => 0,
+                };
+                opts.reproducible = Some(epoch);
+            }
+            "-dedup" => {
+                opts.dedup = true;
+            }
+            "-index" => {
+                opts.index = true;
+            }
+            "-digest" => {
+                opts.digest = true;
+            }
+            "-subsec-times" => {
+                opts.subsec_times = true;
+            }
+            "-min-size" | "-max-size" => {
+                let Some(size) = args.next().as_deref().and_then(parse_size) else {
+                    return Err(MarkError::Usage(format!(
+                        "I expected a size like 10M after {arg}"
+                    )));
+                };
+                if arg == "-min-size" {
+                    opts.min_size = Some(size);
+                } else {
+                    opts.max_size = Some(size);
+                }
+            }
+            "-newer-than" => {
+                let Some((secs, nanos)) = args
+                    .next()
+                    .and_then(|x| parse_timestamp(x.trim().as_bytes()).ok())
+                else {
+                    return Err(MarkError::Usage(
+                        "I expected a time in seconds since 1970, like 1700000000, after -newer-than"
+                            .into(),
+                    ));
+                };
+                opts.newer_than = Some(Duration::new(secs, nanos));
+            }
+            "-newer-than-archive" => {
+                let Some(archive) = args.next() else {
+                    return Err(MarkError::Usage(
+                        "After -newer-than-archive, I expected an archive!".into(),
+                    ));
+                };
+                opts.newer_than_archive = Some(archive);
+            }
+            "-verbose" => {
+                opts.verbose = true;
+            }
+            "-quiet" => {
+                opts.quiet = true;
+            }
+            "-max-memory" => {
+                let Some(max_memory) = args.next().as_deref().and_then(parse_size) else {
+                    return Err(MarkError::Usage(
+                        "I expected a size like 512M after -max-memory".into(),
+                    ));
+                };
+                opts.max_memory = Some(max_memory);
+            }
+            "-max-member-size" => {
+                let Some(max_member_size) = args.next().as_deref().and_then(parse_size) else {
+                    return Err(MarkError::Usage(
+                        "I expected a size like 4G after -max-member-size".into(),
+                    ));
+                };
+                opts.max_member_size = Some(max_member_size);
+            }
+            "-jobs" => {
+                let Some(jobs) = args.next().and_then(|x| x.parse().ok()).filter(|&x| x > 0) else {
+                    return Err(MarkError::Usage(
+                        "I expected a number of jobs above 0 after -jobs".into(),
+                    ));
+                };
+                opts.jobs = Some(jobs);
+            }
+            "-block-size" => {
+                let Some(block_size) = args
+                    .next()
+                    .as_deref()
+                    .and_then(parse_size)
+                    .filter(|x| BLOCK_SIZES.contains(x))
+                else {
+                    return Err(MarkError::Usage(
+                        "I expected a size from 64K to 256M after -block-size".into(),
+                    ));
+                };
+                opts.block_size = Some(block_size as usize);
+            }
+            "-on-conflict" => {
+                let Some(on_conflict) = args
+                    .next()
+                    .map(|x| x.to_lowercase())
+                    .and_then(|x| OnConflict::from_str(&x).ok())
+                else {
+                    return Err(MarkError::Usage(
+                        "I expected skip, overwrite, merge, or keep-newer after -on-conflict"
+                            .into(),
+                    ));
+                };
+                opts.on_conflict = on_conflict;
+            }
+            "-strip-components" => {
+                let Some(strip_components) = args.next().and_then(|x| x.parse().ok()) else {
+                    return Err(MarkError::Usage(
+                        "I expected a number of path components after -strip-components".into(),
+                    ));
+                };
+                opts.strip_components = strip_components;
+            }
+            "-preserve-owner" => {
+                opts.preserve_owner = true;
+            }
+            "-no-preserve-mode" => {
+                opts.no_preserve_mode = true;
+            }
+            "-mode-mask" => {
+                let Some(mode_mask) = args.next().as_deref().and_then(parse_mode) else {
+                    return Err(MarkError::Usage(
+                        "I expected octal permission bits like 022 after -mode-mask".into(),
+                    ));
+                };
+                opts.mode_mask = mode_mask;
+            }
+            "-dir-mode" => {
+                let Some(dir_mode) = args.next().as_deref().and_then(parse_mode) else {
+                    return Err(MarkError::Usage(
+                        "I expected octal permissions like 700 after -dir-mode".into(),
+                    ));
+                };
+                opts.dir_mode = Some(dir_mode);
+            }
+            "-overwrite" => {
+                // Existing files are rewritten, but a directory is never replaced by a file
+                opts.on_conflict = OnConflict::Merge;
+            }
+            "-duplicates" => {
+                opts.duplicates = true;
+            }
+            "-level" => {
+                let Some(level) = args.next().and_then(|x| x.parse().ok()) else {
+                    return Err(MarkError::Usage(
+                        "I expected a compression level after -level".into(),
+                    ));
+                };
+                opts.level = Some(level);
+            }
+            "-level-map" => {
+                let Some(level_map) = args.next().as_deref().and_then(parse_extension_map) else {
+                    return Err(MarkError::Usage(
+                        "I expected a map like \"txt=11,log=5,*=9\" after -level-map".into(),
+                    ));
+                };
+                opts.level_map = level_map;
+            }
+            "-compress-map" => {
+                let Some(compress_map) = args.next().as_deref().and_then(parse_extension_map)
+                else {
+                    return Err(MarkError::Usage(
+                        "I expected a map like \"jpg=none,txt=brotli\" after -compress-map".into(),
+                    ));
+                };
+                opts.compress_map = compress_map;
+            }
+            "-buffer-size" => {
+                let Some(buffer_size) = args
+                    .next()
+                    .as_deref()
+                    .and_then(parse_size)
+                    .filter(|x| BUFFER_SIZES.contains(x))
+                else {
+                    return Err(MarkError::Usage(
+                        "I expected a size from 1K to 16M after -buffer-size".into(),
+                    ));
+                };
+                opts.buffer_size = Some(buffer_size as usize);
+            }
+            "-brotli-window" => {
+                let Some(brotli_window) = args
+                    .next()
+                    .and_then(|x| x.parse().ok())
+                    .filter(|x| BROTLI_WINDOWS.contains(x))
+                else {
+                    return Err(MarkError::Usage(
+                        "I expected a window size from 10 to 24 after -brotli-window".into(),
+                    ));
+                };
+                opts.brotli_window = Some(brotli_window);
+            }
+            "-dict" => {
+                let Some(dict) = args.next() else {
+                    return Err(MarkError::Usage(
+                        "I expected train or a dictionary file after -dict".into(),
+                    ));
+                };
+                opts.dict = Some(dict);
+            }
+            "-allow-external-symlinks" => {
+                opts.allow_external_symlinks = true;
+            }
+            "-auto-compress" => {
+                opts.auto_compress = true;
+            }
+            "-stream" => {
+                opts.stream = true;
+            }
+            "-skip-errors" => {
+                opts.skip_errors = true;
+            }
+            "-sort" => {
+                let Some(sort) = args
+                    .next()
+                    .map(|x| x.to_lowercase())
+                    .and_then(|x| SortOrder::from_str(&x).ok())
+                else {
+                    return Err(MarkError::Usage(
+                        "I expected path, name, size, or ext after -sort".into(),
+                    ));
+                };
+                opts.sort = sort;
+            }
+            "-max-depth" => {
+                let Some(max_depth) = args.next().and_then(|x| x.parse().ok()) else {
+                    return Err(MarkError::Usage(
+                        "I expected a number of directory levels after -max-depth".into(),
+                    ));
+                };
+                opts.max_depth = Some(max_depth);
+            }
+            "-base" => {
+                let Some(base) = args.next() else {
+                    return Err(MarkError::Usage(
+                        "After -base, I expected a directory!".into(),
+                    ));
+                };
+                opts.base = Some(base);
+            }
+            "-sparse" => {
+                opts.sparse = true;
+            }
+            "-strict" => {
+                opts.strict = true;
+            }
+            "-salvage" => {
+                opts.salvage = true;
+            }
+            "-ignore-missing" => {
+                opts.ignore_missing = true;
+            }
+            "-require-utf8-names" => {
+                opts.require_utf8_names = true;
+            }
+            "-no-auto-store" => {
+                opts.no_auto_store = true;
+            }
+            "-force-compress" => {
+                opts.force_compress = true;
+            }
+            "-compress" => {
+                let Some(compression_method) = args
+                    .next()
+                    .map(|x| x.to_lowercase())
+                    .and_then(|x| DataCompression::from_str(&x).ok())
+                else {
+                    return Err(MarkError::Usage(
+                        "I expected a valid compression type after -compress".into(),
+                    ));
+                };
+                opts.compression_method = compression_method;
+            }
+            "-encode" | "-decode" => {
+                let Some(encoding) = args
+                    .next()
+                    .map(|x| x.to_lowercase())
+                    .and_then(|x| TextEncoding::from_str(&x).ok())
+                else {
+                    return Err(MarkError::Usage(format!(
+                        "I expected a valid text encoding (base64, hex) after {arg}"
+                    )));
+                };
+                if arg == "-encode" {
+                    opts.encode = Some(encoding);
+                } else {
+                    opts.decode = Some(encoding);
+                }
+            }
+            _ => {
+                eprintln!("Unrecognized flag \"{original}\", treating it like a positional.");
+                positionals.push(original);
+            }
+        }
+    }
+    Ok(Some((opts, positionals)))
+}
+
+/// The sizes `-buffer-size` accepts. Smaller buffers make brotli slow, and bigger ones only
+/// take more memory.
+const BUFFER_SIZES: std::ops::RangeInclusive<u64> = 1 << 10..=16 << 20;
+
+/// The window sizes brotli supports, as powers of two, for `-brotli-window`.
+const BROTLI_WINDOWS: std::ops::RangeInclusive<u32> = 10..=24;
+
+/// Parses a size in bytes with an optional binary suffix, like `4096`, `512K`, or `10M`.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.char_indices().last()? {
+        (i, 'k' | 'K') => (&size[..i], 1 << 10),
+        (i, 'm' | 'M') => (&size[..i], 1 << 20),
+        (i, 'g' | 'G') => (&size[..i], 1 << 30),
+        (i, 't' | 'T') => (&size[..i], 1 << 40),
+        _ => (size, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Parses permission bits in octal, like `022` or `0o022`.
+fn parse_mode(mode: &str) -> Option<u32> {
+    let mode = mode.trim();
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    u32::from_str_radix(digits, 8).ok().filter(|&x| x <= 0o7777)
+}
+
+/// Has `archive` print what's wrong with it as it's read, for commands that read archives.
+pub(crate) fn reporting_warnings(mut archive: ArchiveReader) -> ArchiveReader {
+    archive.report = Some(|warning| eprintln!("Warning: {warning}"));
+    archive
+}
+
+/// Runs the `mark` command with the arguments it was started with.
+pub fn run() -> Result<(), MarkError> {
+    let Some((opts, positionals)) = parse_flags(std::env::args().skip(1).collect::<Vec<_>>())?
+    else {
+        return Ok(());
+    };
+    let mut positionals = positionals.into_iter();
+    let Some(subcommand) = positionals.next() else {
+        return Err(MarkError::Usage(format!(
+            "Expected a subcommand!\n\n{USAGE}"
+        )));
+    };
+
+    match subcommand.as_str() {
+        "pack" => pack(opts, &positionals.collect::<Vec<_>>()),
+        "unpack" => unpack(opts, &positionals.collect::<Vec<_>>()),
+        "append" => append(opts, &positionals.collect::<Vec<_>>()),
+        "remove" => remove(opts, &positionals.collect::<Vec<_>>()),
+        "cat" => cat(opts, &positionals.collect::<Vec<_>>()),
+        "export-tar" => export_tar(opts),
+        "import-tar" => import_tar(opts),
+        "read" => read_archive(opts),
+        "list" => list(opts),
+        "verify" => verify(opts),
+        "manifest" => manifest(opts),
+        #[cfg(feature = "signing")]
+        "keygen" => signing::keygen(opts),
+        "help" => {
+            print!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(MarkError::Usage(format!(
+            "Invalid subcommand \"{subcommand}\"! Run \"mark help\" to see them all."
+        ))),
+    }
+}
+
+/// Parses a map like the `txt=11,log=5,*=9` of `-level-map` or the `jpg=none,txt=brotli` of
+/// `-compress-map` into extension and value pairs.
+pub(crate) fn parse_extension_map<T: FromStr>(map: &str) -> Option<Vec<(String, T)>> {
+    map.split(',')
+        .map(|entry| {
+            let (extension, value) = entry.split_once('=')?;
+            let extension = extension.trim().trim_start_matches('.');
+            if extension.is_empty() {
+                return None;
+            }
+            let value = value.trim().to_lowercase().parse().ok()?;
+            Some((extension.to_string(), value))
+        })
+        .collect()
+}
@@ -0,0 +1,422 @@
+//! Signing archives and encrypting the files in them, each behind its own feature.
+
+use super::*;
+
+/// Derives the key of an encrypted archive from `-passphrase`, checking that it's the right
+/// one. Without one, that's an error if `required`, and otherwise the files' data is left
+/// encrypted. Returns whether the files' data can be read.
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+pub(crate) fn unlock(
+    opts: &mut Opts,
+    header: ArchiveHeader,
+    required: bool,
+) -> Result<bool, MarkError> {
+    #[cfg(feature = "encryption")]
+    match (header.encryption, &opts.passphrase) {
+        (Some(encryption), Some(passphrase)) => {
+            opts.cipher = Some(cipher_for(passphrase, encryption)?);
+            return Ok(true);
+        }
+        (None, Some(_)) => {
+            eprintln!("Warning: the archive isn't encrypted, so -passphrase isn't needed")
+        }
+        _ => {}
+    }
+    if header.encryption.is_some() && required {
+        let how = match cfg!(feature = "encryption") {
+            true => "give its -passphrase",
+            false => "which needs mark to be built with the \"encryption\" feature",
+        };
+        return Err(MarkError::Usage(format!("The archive is encrypted, {how}")));
+    }
+    Ok(header.encryption.is_none())
+}
+
+/// Derives the key of an archive encrypted with `encryption` from `passphrase`, checking that
+/// it's the right one.
+#[cfg(feature = "encryption")]
+pub(crate) fn cipher_for(
+    passphrase: &str,
+    encryption: Encryption,
+) -> Result<encryption::Cipher, MarkError> {
+    let cipher = encryption::Cipher::derive(passphrase, &encryption.salt)?;
+    if cipher.check() != encryption.check {
+        return Err(MarkError::Usage("The passphrase is wrong".into()));
+    }
+    Ok(cipher)
+}
+
+/// Whether `error` is encrypted data failing its authentication tag, which makes the file as
+/// corrupt as one that fails its checksum.
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+pub(crate) fn failed_authentication(error: &io::Error) -> bool {
+    #[cfg(feature = "encryption")]
+    if error
+        .get_ref()
+        .is_some_and(|x| x.is::<encryption::Unauthentic>())
+    {
+        return true;
+    }
+    false
+}
+
+/// Decrypts the data of `file` read from `data`, if it's encrypted.
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+pub(crate) fn decrypted<'a>(
+    opts: &Opts,
+    file: &FileHeaderRepr,
+    data: impl Read + 'a,
+) -> io::Result<Box<dyn Read + 'a>> {
+    if !file.inner.is_encrypted() {
+        return Ok(Box::new(data));
+    }
+    #[cfg(feature = "encryption")]
+    if let Some(cipher) = &opts.cipher {
+        return Ok(cipher.decryptor(file.name_bytes(), Box::new(data)));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the file is encrypted",
+    ))
+}
+
+/// Detached Ed25519 signatures over the SHA-256 of an archive. Keys and signatures are stored as
+/// raw bytes: a 32 byte secret key, a 32 byte public key, and a 64 byte signature in
+/// `<archive>.sig`.
+#[cfg(feature = "signing")]
+pub(crate) mod signing {
+    use crate::{Context, MarkError, Opts};
+    use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+
+    pub enum Signature {
+        Valid,
+        Invalid,
+        Missing,
+    }
+
+    pub fn signature_path(archive: &str) -> String {
+        format!("{archive}.sig")
+    }
+
+    fn read_key<const N: usize>(path: &str) -> Result<[u8; N], MarkError> {
+        let key = std::fs::read(path).context(|| format!("Failed to read the key \"{path}\""))?;
+        key.try_into().map_err(|key: Vec<u8>| {
+            MarkError::Usage(format!(
+                "The key \"{path}\" should be {N} bytes long, not {}",
+                key.len()
+            ))
+        })
+    }
+
+    /// Signs `digest` with the secret key at `key`, writing the signature to `signature`.
+    pub fn sign(key: &str, digest: &[u8; 32], signature: &str) -> Result<(), MarkError> {
+        let key = SigningKey::from_bytes(&read_key(key)?);
+        std::fs::write(signature, key.sign(digest).to_bytes())
+            .context(|| format!("Failed to write the signature \"{signature}\""))?;
+        eprintln!("Wrote the signature to {signature}");
+        Ok(())
+    }
+
+    /// Checks the signature at `signature` of `digest` against the public key at `key`.
+    pub fn verify(key: &str, digest: &[u8; 32], signature: &str) -> Result<Signature, MarkError> {
+        let key = VerifyingKey::from_bytes(&read_key(key)?)
+            .map_err(|e| MarkError::Usage(format!("The public key isn't valid: {e}")))?;
+        let signature = match std::fs::read(signature) {
+            Ok(signature) => signature,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Signature::Missing),
+            Err(e) => {
+                return Err(e).context(|| format!("Failed to read the signature \"{signature}\""))
+            }
+        };
+        Ok(match ed25519_dalek::Signature::from_slice(&signature) {
+            Ok(signature) if key.verify(digest, &signature).is_ok() => Signature::Valid,
+            _ => Signature::Invalid,
+        })
+    }
+
+    /// Generates a key pair, writing the secret key to the output and the public key next to it
+    /// in `<output>.pub`.
+    pub fn keygen(opts: Opts) -> Result<(), MarkError> {
+        let Some(output) = opts.output else {
+            return Err(MarkError::Usage(
+                "keygen needs an -output for the secret key".into(),
+            ));
+        };
+        let mut seed = [0u8; 32];
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut random| std::io::Read::read_exact(&mut random, &mut seed))
+            .context(|| "Failed to read random bytes for the key".into())?;
+        let key = SigningKey::from_bytes(&seed);
+        let public = format!("{output}.pub");
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut secret = options
+            .open(&output)
+            .context(|| format!("Failed to create \"{output}\""))?;
+        std::io::Write::write_all(&mut secret, &key.to_bytes())
+            .context(|| format!("Failed to write \"{output}\""))?;
+        std::fs::write(&public, key.verifying_key().to_bytes())
+            .context(|| format!("Failed to write \"{public}\""))?;
+        eprintln!("Wrote the secret key to {output} and the public key to {public}");
+        Ok(())
+    }
+}
+
+/// Encryption of file data with a key derived from a passphrase.
+///
+/// The key is derived with Argon2id from the passphrase and the archive's salt. Each file's data is
+/// encrypted with ChaCha20-Poly1305 after it's compressed, in 64 KiB chunks following the STREAM
+/// construction: the data starts with a random 7 byte nonce prefix, and each chunk's nonce is the
+/// prefix, the chunk's number as a big-endian u32, and a byte that's 1 for the last chunk and 0
+/// otherwise. Every chunk but the last is full, so a file whose data fills its last chunk gets an
+/// empty one after it. The file's name is authenticated along with each chunk, but names aren't
+/// encrypted.
+#[cfg(feature = "encryption")]
+pub(crate) mod encryption {
+    use crate::{read_up_to, Context, MarkError, ENCRYPTION_CHECK_LEN, SALT_LEN};
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+    use std::io::{self, Read};
+
+    /// How much of a file's data is encrypted at once.
+    const CHUNK_LEN: usize = 64 * 1024;
+    /// The length of the authentication tag after each chunk.
+    const TAG_LEN: usize = 16;
+    const NONCE_PREFIX_LEN: usize = 7;
+
+    #[derive(Clone)]
+    pub struct Cipher {
+        key: Key,
+    }
+
+    impl std::fmt::Debug for Cipher {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("Cipher { .. }")
+        }
+    }
+
+    fn random<const N: usize>() -> Result<[u8; N], MarkError> {
+        let mut bytes = [0u8; N];
+        std::fs::File::open("/dev/urandom")
+            .and_then(|mut random| random.read_exact(&mut bytes))
+            .context(|| "Failed to read random bytes".into())?;
+        Ok(bytes)
+    }
+
+    /// Makes a salt for a new archive.
+    pub fn new_salt() -> Result<[u8; SALT_LEN], MarkError> {
+        random()
+    }
+
+    /// The length of `len` bytes of data once they're encrypted.
+    pub fn encrypted_len(len: u64) -> u64 {
+        let chunks = len / CHUNK_LEN as u64 + 1;
+        NONCE_PREFIX_LEN as u64 + len + chunks * TAG_LEN as u64
+    }
+
+    impl Cipher {
+        /// Derives the key from `passphrase` and the archive's `salt`. This takes a moment on
+        /// purpose.
+        pub fn derive(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<Self, MarkError> {
+            let params = argon2::Params::new(19 * 1024, 2, 1, Some(32))
+                .map_err(|e| MarkError::Usage(format!("Failed to derive the key: {e}")))?;
+            let argon2 =
+                argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+            let mut key = Key::default();
+            argon2
+                .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                .map_err(|e| MarkError::Usage(format!("Failed to derive the key: {e}")))?;
+            Ok(Self { key })
+        }
+
+        /// A value stored in the archive to tell whether a passphrase is the right one, without
+        /// giving away anything about the key.
+        pub fn check(&self) -> [u8; ENCRYPTION_CHECK_LEN] {
+            use sha2::Digest;
+
+            let hash = sha2::Sha256::new()
+                .chain_update(b"mark passphrase check")
+                .chain_update(self.key)
+                .finalize();
+            hash[..ENCRYPTION_CHECK_LEN].try_into().unwrap()
+        }
+
+        /// Encrypts the data read from `data` of the file named `name`.
+        pub fn encryptor<'a>(
+            &self,
+            name: &[u8],
+            data: Box<dyn Read + 'a>,
+        ) -> Result<Box<dyn Read + 'a>, MarkError> {
+            let prefix = random()?;
+            Ok(Box::new(Encryptor {
+                chunks: Chunks::new(&self.key, name, prefix),
+                data,
+                out: prefix.to_vec(),
+                pos: 0,
+                done: false,
+            }))
+        }
+
+        /// Decrypts the data of the file named `name` read from `data`.
+        pub fn decryptor<'a>(&self, name: &[u8], data: Box<dyn Read + 'a>) -> Box<dyn Read + 'a> {
+            Box::new(Decryptor {
+                key: self.key,
+                name: name.to_vec(),
+                chunks: None,
+                data,
+                out: vec![],
+                pos: 0,
+                done: false,
+            })
+        }
+    }
+
+    /// Seals or opens the chunks of one file's data, in order.
+    struct Chunks {
+        cipher: ChaCha20Poly1305,
+        name: Vec<u8>,
+        prefix: [u8; NONCE_PREFIX_LEN],
+        counter: u32,
+    }
+
+    impl Chunks {
+        fn new(key: &Key, name: &[u8], prefix: [u8; NONCE_PREFIX_LEN]) -> Self {
+            Self {
+                cipher: ChaCha20Poly1305::new(key),
+                name: name.to_vec(),
+                prefix,
+                counter: 0,
+            }
+        }
+
+        fn next_nonce(&mut self, last: bool) -> io::Result<Nonce> {
+            let mut nonce = Nonce::default();
+            nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.prefix);
+            nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&self.counter.to_be_bytes());
+            nonce[11] = last as u8;
+            self.counter = self.counter.checked_add(1).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "too much data to encrypt")
+            })?;
+            Ok(nonce)
+        }
+
+        fn seal(&mut self, chunk: &[u8], last: bool) -> io::Result<Vec<u8>> {
+            let nonce = self.next_nonce(last)?;
+            let payload = Payload {
+                msg: chunk,
+                aad: &self.name,
+            };
+            self.cipher
+                .encrypt(&nonce, payload)
+                .map_err(|_| io::Error::other("failed to encrypt"))
+        }
+
+        fn open(&mut self, chunk: &[u8], last: bool) -> io::Result<Vec<u8>> {
+            let nonce = self.next_nonce(last)?;
+            let payload = Payload {
+                msg: chunk,
+                aad: &self.name,
+            };
+            self.cipher
+                .decrypt(&nonce, payload)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, Unauthentic))
+        }
+    }
+
+    /// The error for encrypted data that fails its authentication tag, see
+    /// [`failed_authentication`](crate::failed_authentication).
+    #[derive(Debug)]
+    pub struct Unauthentic;
+
+    impl std::fmt::Display for Unauthentic {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(
+                "the encrypted data doesn't authenticate, it was changed or the passphrase is wrong",
+            )
+        }
+    }
+
+    impl std::error::Error for Unauthentic {}
+
+    struct Encryptor<'a> {
+        chunks: Chunks,
+        data: Box<dyn Read + 'a>,
+        /// Encrypted data not yet read, from `pos` on
+        out: Vec<u8>,
+        pos: usize,
+        done: bool,
+    }
+
+    impl Read for Encryptor<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.pos == self.out.len() {
+                if self.done {
+                    return Ok(0);
+                }
+                let mut chunk = vec![0u8; CHUNK_LEN];
+                let n = read_up_to(&mut self.data, &mut chunk)?;
+                self.done = n < CHUNK_LEN;
+                self.out = self.chunks.seal(&chunk[..n], self.done)?;
+                self.pos = 0;
+            }
+            let n = buf.len().min(self.out.len() - self.pos);
+            buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    struct Decryptor<'a> {
+        key: Key,
+        name: Vec<u8>,
+        /// Set up once the nonce prefix has been read
+        chunks: Option<Chunks>,
+        data: Box<dyn Read + 'a>,
+        /// Decrypted data not yet read, from `pos` on
+        out: Vec<u8>,
+        pos: usize,
+        done: bool,
+    }
+
+    impl Read for Decryptor<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let truncated = || {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the encrypted data is cut short",
+                )
+            };
+            while self.pos == self.out.len() {
+                if self.done {
+                    return Ok(0);
+                }
+                let chunks = match &mut self.chunks {
+                    Some(chunks) => chunks,
+                    None => {
+                        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+                        if read_up_to(&mut self.data, &mut prefix)? < prefix.len() {
+                            return Err(truncated());
+                        }
+                        self.chunks
+                            .insert(Chunks::new(&self.key, &self.name, prefix))
+                    }
+                };
+                let mut chunk = vec![0u8; CHUNK_LEN + TAG_LEN];
+                let n = read_up_to(&mut self.data, &mut chunk)?;
+                if n < TAG_LEN {
+                    return Err(truncated());
+                }
+                self.done = n < chunk.len();
+                self.out = chunks.open(&chunk[..n], self.done)?;
+                self.pos = 0;
+            }
+            let n = buf.len().min(self.out.len() - self.pos);
+            buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+}
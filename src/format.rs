@@ -0,0 +1,1237 @@
+//! The archive format: the archive header, file headers and how they're laid out in each
+//! format version, and the other records an archive can hold.
+
+use super::*;
+
+/// The archive format version written by `pack`.
+pub(crate) const CURRENT_VERSION: u32 = 14;
+
+/// The first format version whose header starts with [`MAGIC`]. Older archives start with their
+/// version straight away.
+const MAGIC_VERSION: u32 = 3;
+
+/// Identifies a file as a mark archive.
+const MAGIC: [u8; 4] = *b"MARK";
+
+/// The first format version whose file headers have a CRC32 of the file data.
+const CRC32_VERSION: u32 = 4;
+
+/// The first format version whose file headers have the owner and group of the file.
+const OWNER_VERSION: u32 = 5;
+
+/// The first format version whose files can share the data of an earlier file, see
+/// [`FileHeader::link`].
+const LINK_VERSION: u32 = 6;
+
+/// The first format version whose header has [`ArchiveHeader::flags`].
+const FLAGS_VERSION: u32 = 7;
+
+/// The first format version whose files can be hard links, see [`FileHeader::hard_link`].
+const HARD_LINK_VERSION: u32 = 8;
+
+/// The first format version whose file data can be encrypted, see [`ENCRYPTED`].
+const ENCRYPTION_VERSION: u32 = 9;
+
+/// The first format version whose header can be followed by a zstd dictionary, see
+/// [`DICTIONARY`].
+const DICTIONARY_VERSION: u32 = 10;
+
+/// The first format version whose files can be sparse, see [`SparseMap`].
+pub(crate) const SPARSE_VERSION: u32 = 11;
+
+/// The first format version whose file data can be compressed in blocks, see [`BLOCKS_DATA`].
+pub(crate) const BLOCKS_VERSION: u32 = 12;
+
+/// The first format version whose archives can end with a digest, see [`DIGEST`].
+const DIGEST_VERSION: u32 = 13;
+
+/// The first format version whose file data can be written in frames, see [`FRAMED_DATA`].
+pub(crate) const FRAMES_VERSION: u32 = 14;
+
+/// The first format version whose archives end with [`END_OF_ARCHIVE`].
+pub(crate) const END_OF_ARCHIVE_VERSION: u32 = 1;
+
+/// Written after the last file so readers can tell a complete archive from one that happens to
+/// have been truncated at a file boundary.
+pub(crate) const END_OF_ARCHIVE: [u8; 8] = *b"MARK-EOF";
+
+/// The first format version that may contain [`ExtendedMetadata`] records.
+pub(crate) const EXTENDED_METADATA_VERSION: u32 = 2;
+
+/// Tags an [`ExtendedMetadata`] record, which is written in place of a file header, followed by
+/// the header it applies to.
+pub(crate) const EXTENDED_METADATA: [u8; 8] = *b"MARK-EXT";
+
+/// The file count of an archive that doesn't know how many files it contains, leaving readers to
+/// read until [`END_OF_ARCHIVE`].
+pub(crate) const UNKNOWN_FILE_COUNT: u32 = u32::MAX;
+
+/// Set in [`ArchiveHeader::flags`] when the archive has an [`ArchiveIndex`].
+pub(crate) const INDEXED: u32 = 1;
+
+/// Set in [`ArchiveHeader::flags`] when the archive's file data is encrypted, in which case the
+/// header is followed by its [`Encryption`].
+pub(crate) const ENCRYPTED: u32 = 2;
+
+/// Set in [`ArchiveHeader::flags`] when the header (and its [`Encryption`]) is followed by a
+/// zstd dictionary that all of the files compressed with zstd were compressed with, see
+/// [`write_dictionary`].
+pub(crate) const DICTIONARY: u32 = 4;
+
+/// Set in [`ArchiveHeader::flags`] when the archive ends with a digest, after its index if it has
+/// one, see [`write_digest`].
+pub(crate) const DIGEST: u32 = 8;
+
+/// Tags the digest at the end of an archive with [`DIGEST`].
+const DIGEST_TAG: [u8; 8] = *b"MARK-SHA";
+
+/// How many bytes the digest at the end of an archive takes up, with its tag.
+pub(crate) const DIGEST_LEN: usize = DIGEST_TAG.len() + 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ArchiveHeader {
+    pub(crate) version: u32,
+    pub(crate) file_count: u32,
+    /// Bits like [`INDEXED`], only stored from [`FLAGS_VERSION`] on.
+    pub(crate) flags: u32,
+    /// Set along with [`ENCRYPTED`].
+    pub(crate) encryption: Option<Encryption>,
+}
+
+/// How the key of an encrypted archive is derived from its passphrase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Encryption {
+    pub(crate) salt: [u8; SALT_LEN],
+    /// Tells whether a passphrase is the right one
+    pub(crate) check: [u8; ENCRYPTION_CHECK_LEN],
+}
+
+pub(crate) const SALT_LEN: usize = 16;
+
+pub(crate) const ENCRYPTION_CHECK_LEN: usize = 8;
+
+/// Set in [`FileHeader::data_compression`], along with the compression method, for encrypted
+/// data.
+pub(crate) const ENCRYPTED_DATA: u8 = 0x80;
+
+/// Set in [`FileHeader::data_compression`], along with the compression method, for data
+/// compressed in blocks that can be decompressed on their own, see [`block_compressor`]. Each
+/// block is its compressed length as a little-endian `u32` followed by that much compressed data.
+/// Encrypted data is encrypted after it's split into blocks.
+pub(crate) const BLOCKS_DATA: u8 = 0x40;
+
+/// Set in [`FileHeader::data_compression`], along with the compression method, for data written
+/// in frames because the archive couldn't be seeked in to fill in its length and CRC32, which
+/// are 0 in the file header. Each frame is its length as a little-endian `u32` followed by that
+/// much of the data as it's stored. A frame of length 0 ends the data, followed by the CRC32 of
+/// all of it.
+pub(crate) const FRAMED_DATA: u8 = 0x20;
+
+/// How much data each frame holds when a file is written in frames, other than the last.
+pub(crate) const FRAME_LEN: usize = 64 * 1024;
+
+impl ArchiveHeader {
+    /// The size of the header as written, including [`MAGIC`].
+    pub(crate) fn size(self) -> u64 {
+        let encryption = match self.encryption {
+            Some(_) => (SALT_LEN + ENCRYPTION_CHECK_LEN) as u64,
+            None => 0,
+        };
+        if self.version >= FLAGS_VERSION {
+            16 + encryption
+        } else {
+            12
+        }
+    }
+
+    /// Whether the archive is terminated by [`END_OF_ARCHIVE`] rather than its file count.
+    pub(crate) fn is_streamed(self) -> bool {
+        self.version >= END_OF_ARCHIVE_VERSION && self.file_count == UNKNOWN_FILE_COUNT
+    }
+
+    /// Whether an [`ArchiveIndex`] follows the end of the archive.
+    pub(crate) fn is_indexed(self) -> bool {
+        self.flags & INDEXED != 0
+    }
+
+    /// Whether a dictionary follows the header.
+    pub(crate) fn has_dictionary(self) -> bool {
+        self.version >= DICTIONARY_VERSION && self.flags & DICTIONARY != 0
+    }
+
+    /// Whether the archive ends with a digest.
+    pub(crate) fn has_digest(self) -> bool {
+        self.version >= DIGEST_VERSION && self.flags & DIGEST != 0
+    }
+
+    /// How many bytes come after the index (or the end-of-archive marker without one).
+    pub(crate) fn trailer_len(self) -> u64 {
+        match self.has_digest() {
+            true => DIGEST_LEN as u64,
+            false => 0,
+        }
+    }
+
+    pub(crate) fn read(reader: &mut dyn Read) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let version = if magic == MAGIC {
+            reader.read_u32::<LittleEndian>()?
+        } else {
+            // Archives from before the magic number start with their version, which can only be
+            // one of a few small numbers
+            let version = u32::from_le_bytes(magic);
+            if version >= MAGIC_VERSION {
+                let reason = if u32::from_be_bytes(magic) <= CURRENT_VERSION {
+                    "it looks like an archive written with the wrong byte order"
+                } else {
+                    "it doesn't start with \"MARK\""
+                };
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("This isn't a mark archive, {reason}"),
+                ));
+            }
+            version
+        };
+        // Older versions are all still understood, but nothing is known about the layout of a
+        // newer one
+        if version > CURRENT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("This is a version {version} archive, but this mark only understands versions up to {CURRENT_VERSION}. Upgrade mark to read it."),
+            ));
+        }
+        let file_count = reader.read_u32::<LittleEndian>()?;
+        let flags = match version >= FLAGS_VERSION {
+            true => reader.read_u32::<LittleEndian>()?,
+            false => 0,
+        };
+        let encryption = if version >= ENCRYPTION_VERSION && flags & ENCRYPTED != 0 {
+            let mut encryption = Encryption {
+                salt: [0; SALT_LEN],
+                check: [0; ENCRYPTION_CHECK_LEN],
+            };
+            reader.read_exact(&mut encryption.salt)?;
+            reader.read_exact(&mut encryption.check)?;
+            Some(encryption)
+        } else {
+            None
+        };
+        Ok(Self {
+            version,
+            file_count,
+            flags,
+            encryption,
+        })
+    }
+
+    pub(crate) fn write(self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut header = [0u8; 16 + SALT_LEN + ENCRYPTION_CHECK_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&self.version.to_le_bytes());
+        header[8..12].copy_from_slice(&self.file_count.to_le_bytes());
+        header[12..16].copy_from_slice(&self.flags.to_le_bytes());
+        if let Some(encryption) = self.encryption {
+            header[16..16 + SALT_LEN].copy_from_slice(&encryption.salt);
+            header[16 + SALT_LEN..].copy_from_slice(&encryption.check);
+        }
+        writer.write_all(&header[..self.size() as usize])
+    }
+}
+
+/// Writes the zstd dictionary of an archive with [`DICTIONARY`], as its length as a u32 and then
+/// the dictionary itself. Returns how many bytes that took.
+pub(crate) fn write_dictionary(writer: &mut dyn Write, dictionary: &[u8]) -> io::Result<u64> {
+    let len = u32::try_from(dictionary.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "the dictionary is too big"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(dictionary)?;
+    Ok(4 + u64::from(len))
+}
+
+/// Reads a dictionary written by [`write_dictionary`].
+pub(crate) fn read_dictionary(reader: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32::<LittleEndian>()?;
+    let mut dictionary = vec![];
+    reader.take(len.into()).read_to_end(&mut dictionary)?;
+    if dictionary.len() != len as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the archive's dictionary is cut short",
+        ));
+    }
+    Ok(dictionary)
+}
+
+/// Writes the digest at the end of an archive with [`DIGEST`]: [`DIGEST_TAG`] and then the
+/// SHA-256 of everything in the archive before it.
+pub(crate) fn write_digest(writer: &mut dyn Write, digest: &[u8; 32]) -> io::Result<()> {
+    writer.write_all(&DIGEST_TAG)?;
+    writer.write_all(digest)
+}
+
+/// Reads a digest written by [`write_digest`].
+pub(crate) fn read_digest(reader: &mut dyn Read) -> io::Result<[u8; 32]> {
+    let mut tag = [0u8; DIGEST_TAG.len()];
+    reader.read_exact(&mut tag)?;
+    if tag != DIGEST_TAG {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the archive's digest is missing",
+        ));
+    }
+    let mut digest = [0u8; 32];
+    reader.read_exact(&mut digest)?;
+    Ok(digest)
+}
+
+/// Where each file of an archive starts, written after [`END_OF_ARCHIVE`] by `pack -index` so
+/// that a file can be found without reading through the ones before it.
+///
+/// It's stored as [`INDEX`] and the number of files as a u32, then for each file where it starts
+/// (its header, or the [`ExtendedMetadata`] in front of it) and the length of its data as u64s,
+/// followed by the length of its name as a u32 and the name itself. Last comes where the index
+/// starts as a u64 and [`INDEX`] again, so that it can be found from the end of the archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ArchiveIndex {
+    pub(crate) entries: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub(crate) name: Vec<u8>,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+/// Tags the start and end of an [`ArchiveIndex`].
+const INDEX: [u8; 8] = *b"MARK-IDX";
+
+impl ArchiveIndex {
+    /// Adds `file`, which starts at `offset`.
+    pub(crate) fn push(&mut self, file: &FileHeaderRepr, offset: u64) {
+        self.entries.push(IndexEntry {
+            name: file.name_bytes().to_vec(),
+            offset,
+            len: file.inner.len,
+        });
+    }
+
+    /// How many bytes the index takes up in the archive.
+    pub(crate) fn size(&self) -> u64 {
+        let entries = self.entries.iter().map(|x| 20 + x.name.len() as u64);
+        2 * INDEX.len() as u64 + 12 + entries.sum::<u64>()
+    }
+
+    /// Writes the index, `offset` being where in the archive it's written.
+    pub(crate) fn write(&self, writer: &mut dyn Write, offset: u64) -> io::Result<()> {
+        let too_big = || io::Error::new(io::ErrorKind::InvalidInput, "too many files to index");
+        writer.write_all(&INDEX)?;
+        let count = u32::try_from(self.entries.len()).map_err(|_| too_big())?;
+        writer.write_all(&count.to_le_bytes())?;
+        for entry in &self.entries {
+            writer.write_all(&entry.offset.to_le_bytes())?;
+            writer.write_all(&entry.len.to_le_bytes())?;
+            let name_len = u32::try_from(entry.name.len()).map_err(|_| too_big())?;
+            writer.write_all(&name_len.to_le_bytes())?;
+            writer.write_all(&entry.name)?;
+        }
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&INDEX)
+    }
+
+    /// Reads an index from its start, returning it along with where its end says it starts.
+    pub(crate) fn read(reader: &mut dyn Read) -> io::Result<(Self, u64)> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "the index is corrupt");
+        let mut tag = [0u8; 8];
+        reader.read_exact(&mut tag)?;
+        if tag != INDEX {
+            return Err(invalid());
+        }
+        let count = reader.read_u32::<LittleEndian>()?;
+        let mut entries = vec![];
+        for _ in 0..count {
+            let offset = reader.read_u64::<LittleEndian>()?;
+            let len = reader.read_u64::<LittleEndian>()?;
+            let name_len = reader.read_u32::<LittleEndian>()?;
+            let mut name = vec![];
+            reader.take(name_len as u64).read_to_end(&mut name)?;
+            if name.len() != name_len as usize {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            entries.push(IndexEntry { name, offset, len });
+        }
+        let offset = reader.read_u64::<LittleEndian>()?;
+        reader.read_exact(&mut tag)?;
+        if tag != INDEX {
+            return Err(invalid());
+        }
+        Ok((Self { entries }, offset))
+    }
+
+    /// Finds and reads the index at the end of `reader`, an archive with `header`.
+    pub(crate) fn find(reader: &mut dyn ReadSeek, header: ArchiveHeader) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "the index is corrupt");
+        let from_end = INDEX.len() as i64 + 8 + header.trailer_len() as i64;
+        let end = reader.seek(io::SeekFrom::End(-from_end))?;
+        let offset = reader.read_u64::<LittleEndian>()?;
+        if offset >= end {
+            return Err(invalid());
+        }
+        reader.seek(io::SeekFrom::Start(offset))?;
+        let (index, end_offset) = Self::read(reader)?;
+        if end_offset != offset {
+            return Err(invalid());
+        }
+        Ok(index)
+    }
+}
+
+/// How the data of a file is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum DataCompression {
+    None = 0,
+    #[default]
+    Brotli = 1,
+    Zstd = 2,
+    Gzip = 3,
+    Xz = 4,
+}
+
+impl DataCompression {
+    /// Every compression method, in order of their values.
+    pub(crate) const ALL: [Self; 5] = [Self::None, Self::Brotli, Self::Zstd, Self::Gzip, Self::Xz];
+
+    /// The compression levels the method supports, if it has any.
+    pub(crate) fn level_range(self) -> Option<std::ops::RangeInclusive<u32>> {
+        match self {
+            Self::None => None,
+            Self::Brotli => Some(0..=11),
+            Self::Zstd => Some(1..=22),
+            Self::Gzip => Some(0..=9),
+            Self::Xz => Some(0..=9),
+        }
+    }
+}
+
+impl TryFrom<u8> for DataCompression {
+    type Error = ();
+    fn try_from(x: u8) -> Result<DataCompression, Self::Error> {
+        match x {
+            0 => Ok(DataCompression::None),
+            1 => Ok(Self::Brotli),
+            2 => Ok(Self::Zstd),
+            3 => Ok(Self::Gzip),
+            4 => Ok(Self::Xz),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::str::FromStr for DataCompression {
+    type Err = &'static str;
+
+    // Required method
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "default" => Self::default(),
+            "none" => Self::None,
+            "brotli" => Self::Brotli,
+            "zstd" => Self::Zstd,
+            "gzip" => Self::Gzip,
+            "xz" => Self::Xz,
+            _ => return Err("unspported compression format"),
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct FileHeader {
+    /// The modification time in seconds since the UNIX epoch, which `unpack` restores
+    pub(crate) modified: u64,
+    /// The access time in seconds since the UNIX epoch, which `unpack` restores
+    pub(crate) accessed: u64,
+    /// The UNIX file permissions
+    pub(crate) mode: u32,
+    /// The length of the name following the header, or 0 if it's longer than fits here, in
+    /// which case the name is stored in the [`ExtendedMetadata`] instead. That leaves the whole
+    /// record to hold it, up to 4 GiB.
+    pub(crate) name_len: u16,
+    pub(crate) data_compression: u8,
+    /// The size of the file data prior to compression, if any has been applied.
+    pub(crate) uncompressed_len: u64,
+    /// The size of the file data within the archive
+    pub(crate) len: u64,
+    /// The CRC32 of the file data within the archive
+    pub(crate) crc32: u32,
+    /// The user ID of the file's owner, which `unpack -preserve-owner` restores
+    pub(crate) uid: u32,
+    /// The group ID of the file's group, which `unpack -preserve-owner` restores
+    pub(crate) gid: u32,
+    /// The 1-based index of an earlier file in the archive whose data this one shares, storing
+    /// none of its own, or 0. The compression and uncompressed length are the earlier file's.
+    pub(crate) link: u32,
+    /// Whether this is a hard link to the file at [`FileHeader::link`], rather than a copy of it
+    pub(crate) hard_link: bool,
+}
+
+/// The bits of [`FileHeader::mode`] holding the file type.
+pub(crate) const S_IFMT: u32 = 0o170000;
+
+/// The file type of a symlink, whose data is its target.
+pub(crate) const S_IFLNK: u32 = 0o120000;
+
+/// The file type of a directory.
+pub(crate) const S_IFDIR: u32 = 0o040000;
+
+/// The file type of a regular file.
+pub(crate) const S_IFREG: u32 = 0o100000;
+
+/// Runs a file as its owner.
+pub(crate) const S_ISUID: u32 = 0o4000;
+
+/// Runs a file as its group.
+pub(crate) const S_ISGID: u32 = 0o2000;
+
+/// Only lets the owners of the files in a directory remove them.
+pub(crate) const S_ISVTX: u32 = 0o1000;
+
+impl FileHeader {
+    pub(crate) fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    /// Whether this is an empty directory, which has no data.
+    pub(crate) fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    /// The 0-based index of the earlier file this one shares the data of, if any.
+    pub(crate) fn link(&self) -> Option<usize> {
+        (self.link != 0).then(|| self.link as usize - 1)
+    }
+
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.data_compression & ENCRYPTED_DATA != 0
+    }
+
+    /// Whether the data was compressed in blocks, see [`BLOCKS_DATA`].
+    pub(crate) fn has_blocks(&self) -> bool {
+        self.data_compression & BLOCKS_DATA != 0
+    }
+
+    /// Whether the data was written in frames, see [`FRAMED_DATA`].
+    pub(crate) fn is_framed(&self) -> bool {
+        self.data_compression & FRAMED_DATA != 0
+    }
+
+    pub(crate) fn compression(&self) -> io::Result<DataCompression> {
+        let method = self.data_compression & !(ENCRYPTED_DATA | BLOCKS_DATA | FRAMED_DATA);
+        DataCompression::try_from(method).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown compression method {}", self.data_compression),
+            )
+        })
+    }
+
+    /// Writes the header's fields in the order given by `layout`.
+    pub(crate) fn write(self, writer: &mut dyn Write, layout: &Layout) -> std::io::Result<()> {
+        for field in layout.fields {
+            match field {
+                Field::Modified => writer.write_all(&self.modified.to_le_bytes())?,
+                Field::Accessed => writer.write_all(&self.accessed.to_le_bytes())?,
+                Field::Mode => writer.write_all(&self.mode.to_le_bytes())?,
+                Field::NameLen => writer.write_all(&self.name_len.to_le_bytes())?,
+                Field::DataCompression => writer.write_all(&self.data_compression.to_le_bytes())?,
+                Field::UncompressedLen => writer.write_all(&self.uncompressed_len.to_le_bytes())?,
+                Field::Len => writer.write_all(&self.len.to_le_bytes())?,
+                Field::Crc32 => writer.write_all(&self.crc32.to_le_bytes())?,
+                Field::Uid => writer.write_all(&self.uid.to_le_bytes())?,
+                Field::Gid => writer.write_all(&self.gid.to_le_bytes())?,
+                Field::Link => writer.write_all(&self.link.to_le_bytes())?,
+                Field::HardLink => writer.write_all(&[self.hard_link as u8])?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the header's fields in the order given by `layout`. Fields missing from the layout
+    /// are left at their defaults.
+    pub(crate) fn read(reader: &mut dyn Read, layout: &Layout) -> std::io::Result<Self> {
+        let mut header = Self::default();
+        for field in layout.fields {
+            match field {
+                Field::Modified => header.modified = reader.read_u64::<LittleEndian>()?,
+                Field::Accessed => header.accessed = reader.read_u64::<LittleEndian>()?,
+                Field::Mode => header.mode = reader.read_u32::<LittleEndian>()?,
+                Field::NameLen => header.name_len = reader.read_u16::<LittleEndian>()?,
+                Field::DataCompression => header.data_compression = reader.read_u8()?,
+                Field::UncompressedLen => {
+                    header.uncompressed_len = reader.read_u64::<LittleEndian>()?
+                }
+                Field::Len => header.len = reader.read_u64::<LittleEndian>()?,
+                Field::Crc32 => header.crc32 = reader.read_u32::<LittleEndian>()?,
+                Field::Uid => header.uid = reader.read_u32::<LittleEndian>()?,
+                Field::Gid => header.gid = reader.read_u32::<LittleEndian>()?,
+                Field::Link => header.link = reader.read_u32::<LittleEndian>()?,
+                Field::HardLink => header.hard_link = reader.read_u8()? != 0,
+            }
+        }
+        Ok(header)
+    }
+}
+
+/// A field of a [`FileHeader`], as stored in an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Field {
+    Modified,
+    Accessed,
+    Mode,
+    NameLen,
+    DataCompression,
+    UncompressedLen,
+    Len,
+    Crc32,
+    Uid,
+    Gid,
+    Link,
+    HardLink,
+}
+
+/// How the files of an archive are laid out, which depends on the archive's format version.
+///
+/// Changing the layout means adding a new entry to [`LAYOUTS`] for the version that introduces
+/// it, which keeps archives of older versions readable.
+#[derive(Debug)]
+pub(crate) struct Layout {
+    /// The fields of each file header, in the order they're stored.
+    pub(crate) fields: &'static [Field],
+    /// Whether each file's name comes before its data, rather than after.
+    pub(crate) name_before_data: bool,
+}
+
+/// Every file layout, along with the first format version using it, from oldest to newest.
+pub(crate) const LAYOUTS: &[(u32, Layout)] = &[
+    (
+        0,
+        Layout {
+            fields: &[
+                Field::Modified,
+                Field::Accessed,
+                Field::Mode,
+                Field::NameLen,
+                Field::DataCompression,
+                Field::UncompressedLen,
+                Field::Len,
+            ],
+            name_before_data: true,
+        },
+    ),
+    (
+        CRC32_VERSION,
+        Layout {
+            fields: &[
+                Field::Modified,
+                Field::Accessed,
+                Field::Mode,
+                Field::NameLen,
+                Field::DataCompression,
+                Field::UncompressedLen,
+                Field::Len,
+                Field::Crc32,
+            ],
+            name_before_data: true,
+        },
+    ),
+    (
+        OWNER_VERSION,
+        Layout {
+            fields: &[
+                Field::Modified,
+                Field::Accessed,
+                Field::Mode,
+                Field::NameLen,
+                Field::DataCompression,
+                Field::UncompressedLen,
+                Field::Len,
+                Field::Crc32,
+                Field::Uid,
+                Field::Gid,
+            ],
+            name_before_data: true,
+        },
+    ),
+    (
+        LINK_VERSION,
+        Layout {
+            fields: &[
+                Field::Modified,
+                Field::Accessed,
+                Field::Mode,
+                Field::NameLen,
+                Field::DataCompression,
+                Field::UncompressedLen,
+                Field::Len,
+                Field::Crc32,
+                Field::Uid,
+                Field::Gid,
+                Field::Link,
+            ],
+            name_before_data: true,
+        },
+    ),
+    (
+        HARD_LINK_VERSION,
+        Layout {
+            fields: &[
+                Field::Modified,
+                Field::Accessed,
+                Field::Mode,
+                Field::NameLen,
+                Field::DataCompression,
+                Field::UncompressedLen,
+                Field::Len,
+                Field::Crc32,
+                Field::Uid,
+                Field::Gid,
+                Field::Link,
+                Field::HardLink,
+            ],
+            name_before_data: true,
+        },
+    ),
+];
+
+impl Layout {
+    /// The layout of archives with format `version`.
+    pub(crate) fn for_version(version: u32) -> &'static Layout {
+        LAYOUTS
+            .iter()
+            .rev()
+            .find(|(first_version, _)| *first_version <= version)
+            .map(|(_, layout)| layout)
+            .unwrap()
+    }
+}
+
+/// Fails if the bytes of a file name aren't valid UTF-8 and `options.require_utf8_names` is set.
+fn check_name(name: &[u8], options: ReadOptions) -> io::Result<()> {
+    if options.require_utf8_names && std::str::from_utf8(name).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("file name isn't valid UTF-8 (bytes: {})", hex_bytes(name)),
+        ));
+    }
+    Ok(())
+}
+
+/// The error for a file in an archive claiming to be `len` bytes long, more than `max`, the
+/// `-max-member-size` limit.
+pub(crate) fn beyond_max_member_size(len: u64, max: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("a file in the archive claims to be {len} bytes long, more than the -max-member-size limit of {max}"),
+    )
+}
+
+/// The time `secs` seconds and `nanos` nanoseconds after the UNIX epoch, unless it's too far off
+/// to represent.
+pub(crate) fn timestamp(secs: u64, nanos: u32) -> Option<SystemTime> {
+    let since = Duration::from_secs(secs).checked_add(Duration::from_nanos(nanos.into()))?;
+    SystemTime::UNIX_EPOCH.checked_add(since)
+}
+
+/// Parses a timestamp in seconds with an optional fraction, like `1700000000.5`, into seconds
+/// and nanoseconds.
+pub(crate) fn parse_timestamp(value: &[u8]) -> io::Result<(u64, u32)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid timestamp");
+    let value = std::str::from_utf8(value).map_err(|_| invalid())?;
+    let (secs, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let nanos = format!("{fraction:0<9}").parse().map_err(|_| invalid())?;
+    Ok((secs.parse().map_err(|_| invalid())?, nanos))
+}
+
+/// Key/value metadata about the file that follows it, for values that don't fit in the fixed
+/// size [`FileHeader`], similar to tar's PAX headers.
+///
+/// It's stored as [`EXTENDED_METADATA`], the length of the records, and then the records
+/// themselves. Each record is `<length> <key>=<value>\n`, where the length is in decimal and
+/// covers the whole record, so values can hold any bytes.
+///
+/// Known keys:
+/// - `path`: the file name
+/// - `mtime`, `atime`: timestamps, in seconds with an optional fraction
+/// - `sparse.size`, `sparse.map`: where the data of a sparse file is, see [`SparseMap`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ExtendedMetadata {
+    records: Vec<(String, Vec<u8>)>,
+}
+
+impl ExtendedMetadata {
+    pub(crate) fn push(&mut self, key: &str, value: &[u8]) {
+        self.records.push((key.to_string(), value.to_vec()));
+    }
+
+    /// Reads the records following an [`EXTENDED_METADATA`] tag, as long as they aren't longer
+    /// than `max_len`.
+    pub(crate) fn read(reader: &mut dyn Read, max_len: Option<u64>) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed extended metadata");
+        let len = reader.read_u32::<LittleEndian>()?;
+        if let Some(max) = max_len.filter(|&max| u64::from(len) > max) {
+            return Err(beyond_max_member_size(len.into(), max));
+        }
+        let mut payload = vec![];
+        reader.take(len as u64).read_to_end(&mut payload)?;
+        if payload.len() != len as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut records = vec![];
+        let mut rest = &payload[..];
+        while !rest.is_empty() {
+            let space = rest.iter().position(|&b| b == b' ').ok_or_else(invalid)?;
+            let record_len: usize = std::str::from_utf8(&rest[..space])
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .filter(|&x| x > space + 1 && x <= rest.len())
+                .ok_or_else(invalid)?;
+            let record = &rest[space + 1..record_len];
+            let record = record.strip_suffix(b"\n").ok_or_else(invalid)?;
+            let equals = record.iter().position(|&b| b == b'=').ok_or_else(invalid)?;
+            let key = String::from_utf8(record[..equals].to_vec()).map_err(|_| invalid())?;
+            records.push((key, record[equals + 1..].to_vec()));
+            rest = &rest[record_len..];
+        }
+        Ok(Self { records })
+    }
+
+    /// Writes the records, tag included.
+    pub(crate) fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let mut payload = vec![];
+        for (key, value) in &self.records {
+            // The length includes its own digits, so grow it until it accounts for them
+            let rest = key.len() + value.len() + 3;
+            let mut len = rest + 1;
+            while len != rest + len.to_string().len() {
+                len = rest + len.to_string().len();
+            }
+            payload.extend_from_slice(format!("{len} {key}=").as_bytes());
+            payload.extend_from_slice(value);
+            payload.push(b'\n');
+        }
+        // Truncating the length would leave the rest of the archive unreadable
+        let len = u32::try_from(payload.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the extended metadata is {} bytes, more than the {} that fit in an archive",
+                    payload.len(),
+                    u32::MAX
+                ),
+            )
+        })?;
+        writer.write_all(&EXTENDED_METADATA)?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&payload)
+    }
+}
+
+/// Where the data of a sparse file is, stored in the `sparse.size` and `sparse.map`
+/// [`ExtendedMetadata`] records like GNU tar's. Only the data segments are stored, one after the
+/// other, and everything between them is a hole that reads as zeros.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SparseMap {
+    /// The length of the whole file, holes included.
+    pub(crate) size: u64,
+    /// The offset and length of each data segment, in order.
+    pub(crate) segments: Vec<(u64, u64)>,
+}
+
+impl SparseMap {
+    /// How much of the file is data rather than holes.
+    pub(crate) fn data_len(&self) -> u64 {
+        self.segments.iter().map(|(_, len)| len).sum()
+    }
+
+    /// The segments as `offset,len,offset,len...`.
+    pub(crate) fn map(&self) -> String {
+        let segments: Vec<_> = self
+            .segments
+            .iter()
+            .map(|(offset, len)| format!("{offset},{len}"))
+            .collect();
+        segments.join(",")
+    }
+
+    /// Parses the values of the `sparse.size` and `sparse.map` records.
+    pub(crate) fn parse(size: &[u8], map: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed sparse map");
+        let number = |x: &[u8]| -> io::Result<u64> {
+            std::str::from_utf8(x)
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let size = number(size)?;
+        let numbers = match map {
+            [] => vec![],
+            map => map
+                .split(|&b| b == b',')
+                .map(number)
+                .collect::<Result<_, _>>()?,
+        };
+        if numbers.len() % 2 != 0 {
+            return Err(invalid());
+        }
+        let segments: Vec<_> = numbers.chunks(2).map(|x| (x[0], x[1])).collect();
+        // The segments have to be in order without overlapping, so they can be written in one
+        // pass
+        let mut end = 0;
+        for &(offset, len) in &segments {
+            let segment_end = offset.checked_add(len).ok_or_else(invalid)?;
+            if len == 0 || offset < end || segment_end > size {
+                return Err(invalid());
+            }
+            end = segment_end;
+        }
+        Ok(Self { size, segments })
+    }
+}
+
+/// How [`FileHeaderRepr::read_header_only`] and [`FileHeaderRepr::read_data`] treat the files
+/// they read.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ReadOptions<'a> {
+    /// Skip over the file data instead of reading it.
+    pub(crate) skip_data: bool,
+    /// Fail on names that aren't valid UTF-8 instead of converting them lossily.
+    pub(crate) require_utf8_names: bool,
+    /// Fail on files with more data than this instead of reading it all into memory.
+    pub(crate) max_data_len: Option<u64>,
+    /// Fail on files claiming more data than this (or a longer name or extended metadata), before
+    /// reading any of it.
+    pub(crate) max_member_size: Option<u64>,
+    /// Only read the data of the files with these names, skipping the rest.
+    pub(crate) only: Option<&'a [String]>,
+}
+
+impl ReadOptions<'_> {
+    /// Whether the data of the file `name` should be read, as far as [`ReadOptions::only`] is
+    /// concerned.
+    pub(crate) fn wants(&self, name: &str) -> bool {
+        self.only.is_none_or(|only| only.iter().any(|x| x == name))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct FileHeaderRepr {
+    pub(crate) inner: FileHeader,
+    /// The name, with any bytes that aren't valid UTF-8 replaced, see [`FileHeaderRepr::raw_name`].
+    pub(crate) name: String,
+    /// The exact bytes of the name when they aren't valid UTF-8, which is all a name is on Unix.
+    raw_name: Option<Vec<u8>>,
+    pub(crate) data: Vec<u8>,
+    /// The subsecond parts of the timestamps, which only fit in [`ExtendedMetadata`].
+    pub(crate) modified_nanos: u32,
+    pub(crate) accessed_nanos: u32,
+    /// Where the data goes when the file is sparse, with the rest of it holes.
+    pub(crate) sparse: Option<SparseMap>,
+}
+
+impl FileHeaderRepr {
+    /// Creates a file, filling in `header.name_len` from `name` and `header.crc32` from `data`.
+    pub(crate) fn new(mut header: FileHeader, name: Vec<u8>, data: Vec<u8>) -> Self {
+        header.crc32 = crc32fast::hash(&data);
+        let mut file = Self {
+            inner: header,
+            name: String::new(),
+            raw_name: None,
+            data,
+            modified_nanos: 0,
+            accessed_nanos: 0,
+            sparse: None,
+        };
+        file.set_name(name);
+        file
+    }
+
+    /// Renames the file to `name`, which can be any bytes, and fills in `inner.name_len` to
+    /// match.
+    fn set_name(&mut self, name: Vec<u8>) {
+        // Names too long for the header are stored in an extended metadata record instead
+        self.inner.name_len = u16::try_from(name.len()).unwrap_or(0);
+        (self.name, self.raw_name) = match String::from_utf8(name) {
+            Ok(name) => (name, None),
+            Err(e) => (
+                String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                Some(e.into_bytes()),
+            ),
+        };
+    }
+
+    /// The exact bytes of the name.
+    pub(crate) fn name_bytes(&self) -> &[u8] {
+        self.raw_name.as_deref().unwrap_or(self.name.as_bytes())
+    }
+
+    /// The name as a path, with the exact bytes of the name where paths can hold any bytes.
+    #[cfg(unix)]
+    pub(crate) fn path(&self) -> &std::path::Path {
+        use std::os::unix::ffi::OsStrExt;
+
+        std::path::Path::new(std::ffi::OsStr::from_bytes(self.name_bytes()))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn path(&self) -> &std::path::Path {
+        std::path::Path::new(&self.name)
+    }
+
+    /// Whether the file's data matches its CRC32, which is always true of layouts without one.
+    pub(crate) fn checksum_matches(&self, layout: &Layout) -> bool {
+        !layout.fields.contains(&Field::Crc32) || crc32fast::hash(&self.data) == self.inner.crc32
+    }
+
+    /// The length of the file's contents, holes included.
+    pub(crate) fn size(&self) -> u64 {
+        self.sparse
+            .as_ref()
+            .map_or(self.inner.uncompressed_len, |sparse| sparse.size)
+    }
+
+    /// When the file was last modified. Times too far off to represent, which reading a file
+    /// from an archive refuses, count as the UNIX epoch.
+    pub(crate) fn modified_time(&self) -> SystemTime {
+        timestamp(self.inner.modified, self.modified_nanos).unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    /// Like [`FileHeaderRepr::modified_time`], for when the file was last accessed.
+    pub(crate) fn accessed_time(&self) -> SystemTime {
+        timestamp(self.inner.accessed, self.accessed_nanos).unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+
+    /// The extended metadata needed for the values that don't fit in the file header, if any.
+    pub(crate) fn extended(&self) -> Option<ExtendedMetadata> {
+        let mut extended = ExtendedMetadata::default();
+        if self.inner.name_len as usize != self.name_bytes().len() {
+            extended.push("path", self.name_bytes());
+        }
+        if self.modified_nanos != 0 {
+            let mtime = format!("{}.{:09}", self.inner.modified, self.modified_nanos);
+            extended.push("mtime", mtime.as_bytes());
+        }
+        if self.accessed_nanos != 0 {
+            let atime = format!("{}.{:09}", self.inner.accessed, self.accessed_nanos);
+            extended.push("atime", atime.as_bytes());
+        }
+        if let Some(sparse) = &self.sparse {
+            extended.push("sparse.size", sparse.size.to_string().as_bytes());
+            extended.push("sparse.map", sparse.map().as_bytes());
+        }
+        (!extended.records.is_empty()).then_some(extended)
+    }
+
+    /// Overrides the values of the file header with the ones in `extended`. Unknown keys are
+    /// ignored so that newer archives can still be read.
+    fn apply_extended(
+        &mut self,
+        extended: ExtendedMetadata,
+        options: ReadOptions,
+    ) -> io::Result<()> {
+        let (mut sparse_size, mut sparse_map) = (None, None);
+        for (key, value) in extended.records {
+            match key.as_str() {
+                "path" => {
+                    check_name(&value, options)?;
+                    self.set_name(value);
+                }
+                "mtime" => {
+                    (self.inner.modified, self.modified_nanos) = parse_timestamp(&value)?;
+                }
+                "atime" => {
+                    (self.inner.accessed, self.accessed_nanos) = parse_timestamp(&value)?;
+                }
+                "sparse.size" => sparse_size = Some(value),
+                "sparse.map" => sparse_map = Some(value),
+                _ => {}
+            }
+        }
+        match (sparse_size, sparse_map) {
+            (None, None) => {}
+            (Some(size), Some(map)) => {
+                let sparse = SparseMap::parse(&size, &map)?;
+                if sparse.data_len() != self.inner.uncompressed_len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the sparse map doesn't match the length of the data",
+                    ));
+                }
+                self.sparse = Some(sparse);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "a sparse file is missing its size or map",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a file header and name as laid out by `layout`, applying the `extended` metadata
+    /// that came before the header, if any. When the layout has the name before the data, the
+    /// reader is left at the start of the data, and otherwise the data is read (unless told to
+    /// skip it) to get to the name.
+    pub(crate) fn read_header_only(
+        reader: &mut dyn Read,
+        layout: &Layout,
+        extended: Option<ExtendedMetadata>,
+        options: ReadOptions,
+    ) -> io::Result<Self> {
+        let mut file = Self {
+            inner: FileHeader::read(reader, layout)?,
+            name: String::new(),
+            raw_name: None,
+            data: vec![],
+            modified_nanos: 0,
+            accessed_nanos: 0,
+            sparse: None,
+        };
+        let claimed = file.inner.len.max(file.inner.name_len.into());
+        if let Some(max) = options.max_member_size.filter(|&max| claimed > max) {
+            return Err(beyond_max_member_size(claimed, max));
+        }
+        let read_name = |file: &mut Self, reader: &mut dyn Read| {
+            let mut name = vec![0u8; file.inner.name_len as usize];
+            reader.read_exact(&mut name)?;
+            check_name(&name, options)?;
+            file.set_name(name);
+            if let Some(extended) = extended {
+                file.apply_extended(extended, options)?;
+            }
+            let fits = |secs, nanos| timestamp(secs, nanos).is_some();
+            if !fits(file.inner.modified, file.modified_nanos)
+                || !fits(file.inner.accessed, file.accessed_nanos)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("the timestamps of \"{}\" are out of range", file.name),
+                ));
+            }
+            Ok(())
+        };
+        if layout.name_before_data {
+            read_name(&mut file, reader)?;
+        } else {
+            file.data = Self::read_data(reader, &file.inner, "a file", options)?;
+            read_name(&mut file, reader)?;
+        }
+        Ok(file)
+    }
+
+    /// Reads (or skips) the data described by `header`. `name` is only used for errors.
+    pub(crate) fn read_data(
+        reader: &mut dyn Read,
+        header: &FileHeader,
+        name: &str,
+        options: ReadOptions,
+    ) -> io::Result<Vec<u8>> {
+        if header.is_framed() {
+            return Self::read_framed_data(reader, name, options);
+        }
+        if options.skip_data {
+            if io::copy(&mut reader.take(header.len), &mut io::sink())? < header.len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("the archive ends partway through the data of \"{name}\""),
+                ));
+            }
+            return Ok(vec![]);
+        }
+        if let Some(max) = options.max_data_len.filter(|&max| header.len > max) {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!(
+                    "\"{name}\" needs {} bytes of memory, more than the limit of {max}",
+                    header.len
+                ),
+            ));
+        }
+        // The data is read as it comes rather than making room for all of it up front, so an
+        // archive lying about how much there is runs out instead of taking that much memory
+        let mut data = vec![];
+        reader.take(header.len).read_to_end(&mut data)?;
+        if data.len() as u64 != header.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("the archive ends partway through the data of \"{name}\""),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Like [`FileHeaderRepr::read_data`], for data in frames read from [`ArchiveReader::data`].
+    /// Only the frames tell how long it is, so it's read until they end.
+    fn read_framed_data(
+        reader: &mut dyn Read,
+        name: &str,
+        options: ReadOptions,
+    ) -> io::Result<Vec<u8>> {
+        if options.skip_data {
+            io::copy(reader, &mut io::sink())?;
+            return Ok(vec![]);
+        }
+        let max = options.max_data_len.unwrap_or(u64::MAX);
+        let mut data = vec![];
+        reader.take(max.saturating_add(1)).read_to_end(&mut data)?;
+        if data.len() as u64 > max {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!("\"{name}\" needs more than the limit of {max} bytes of memory"),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Writes the file as laid out by `layout`.
+    pub(crate) fn write(&self, writer: &mut dyn Write, layout: &Layout) -> std::io::Result<()> {
+        if layout.name_before_data {
+            self.write_header_only(writer, layout)?;
+            return writer.write_all(&self.data);
+        }
+        if let Some(extended) = self.extended() {
+            extended.write(writer)?;
+        }
+        self.inner.write(writer, layout)?;
+        writer.write_all(&self.data)?;
+        writer.write_all(self.stored_name())
+    }
+
+    /// Writes everything but the file's data, which is expected to be written right after, for
+    /// layouts with the name before the data.
+    pub(crate) fn write_header_only(
+        &self,
+        writer: &mut dyn Write,
+        layout: &Layout,
+    ) -> std::io::Result<()> {
+        assert!(layout.name_before_data);
+        if let Some(extended) = self.extended() {
+            extended.write(writer)?;
+        }
+        self.inner.write(writer, layout)?;
+        writer.write_all(self.stored_name())
+    }
+
+    /// The part of the name stored after the file header, which is all of it unless it's in
+    /// the extended metadata instead.
+    pub(crate) fn stored_name(&self) -> &[u8] {
+        let name = self.name_bytes();
+        &name[..(self.inner.name_len as usize).min(name.len())]
+    }
+}
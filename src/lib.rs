@@ -0,0 +1,4505 @@
+//! A simple archival format, and the `mark` command that packs and unpacks it, see [`run`].
+//!
+//! Archives can also be written with [`StreamingArchiveWriter`] (or [`ArchiveWriter`]) and read
+//! with [`ArchiveReader`] from other programs.
+//!
+//! For future editors:
+//! Remember to always output debugging messages to stderr and not to stdout.
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+
+mod cli;
+mod crypto;
+mod format;
+mod pack;
+mod reader;
+mod unpack;
+mod writer;
+
+use cli::reporting_warnings;
+pub use cli::run;
+use crypto::*;
+pub use format::DataCompression;
+use format::*;
+use pack::*;
+use reader::*;
+pub use reader::{ArchiveReader, Member};
+use unpack::*;
+use writer::*;
+pub use writer::{ArchiveWriter, StreamingArchiveWriter};
+
+lazy_static::lazy_static! {
+    static ref BROTLI_ENC_PARAMS: brotli::enc::BrotliEncoderParams = brotli::enc::BrotliEncoderParams::default();
+}
+
+#[derive(Default, Debug, Clone)]
+struct Opts {
+    /// Input file -- stdin if omitted.
+    input: Option<String>,
+    /// Output file -- stdout if omitted.
+    output: Option<String>,
+    /// Whether to use dotfiles
+    include_dotfiles: bool,
+    /// Pack what symlinks point to, rather than the symlinks themselves
+    dereference: bool,
+    /// Which compression method to use
+    compression_method: DataCompression,
+    /// The compression level to use, on the scale of the compression method
+    level: Option<u32>,
+    /// Compress every file, even ones that look like they're already compressed
+    force_compress: bool,
+    /// Keep the compression method of files that it makes bigger, instead of storing them as is
+    no_auto_store: bool,
+    /// Encode the packed archive as text
+    encode: Option<TextEncoding>,
+    /// Decode the input archive from text
+    decode: Option<TextEncoding>,
+    /// Refuse file names that aren't valid UTF-8 instead of replacing the invalid bytes
+    require_utf8_names: bool,
+    /// Skip paths to pack that don't exist instead of failing
+    ignore_missing: bool,
+    /// How to convert the line endings of text files, if at all
+    line_endings: Option<LineEndings>,
+    /// Write an archive that doesn't record its file count up front
+    stream: bool,
+    /// Skip directories containing a file with any of these names
+    exclude_if_present: Vec<String>,
+    /// Skip the directories version control systems keep their data in, see [`VCS_DIRS`]
+    exclude_vcs: bool,
+    /// Only pack files whose names in the archive match one of these globs, if there are any
+    include: Vec<String>,
+    /// Don't pack files or directories whose names in the archive match any of these globs
+    exclude: Vec<String>,
+    /// Only pack files of at least this many bytes
+    min_size: Option<u64>,
+    /// Only pack files of at most this many bytes
+    max_size: Option<u64>,
+    /// Only pack files modified after this long after 1970
+    newer_than: Option<Duration>,
+    /// Only pack files that aren't in this archive, or were modified after they were packed into
+    /// it
+    newer_than_archive: Option<String>,
+    /// Report more of what's going on, like files that are skipped
+    verbose: bool,
+    /// Don't report each file as it's packed or unpacked, or sum up what was done, leaving only
+    /// warnings and errors
+    quiet: bool,
+    /// Pack stdin as a single file with this name, instead of packing paths
+    name: Option<String>,
+    /// A file listing more paths to pack, `-` for stdin, and the byte that separates them
+    files_from: Option<(String, u8)>,
+    /// Pack files even if a `.gitignore` says to ignore them
+    no_gitignore: bool,
+    /// Pick whichever compression method works best for each file
+    auto_compress: bool,
+    /// Recreate symlinks even if they point outside of the output directory
+    allow_external_symlinks: bool,
+    /// Compression levels by file extension, with `*` matching any other file
+    level_map: Vec<(String, u32)>,
+    /// Compression methods by file extension, with `*` matching any other file
+    compress_map: Vec<(String, DataCompression)>,
+    /// How many bytes brotli buffers at a time -- [`DEFAULT_BUFFER_SIZE`] if omitted
+    buffer_size: Option<usize>,
+    /// The brotli window size as a power of two, with bigger windows compressing better but
+    /// taking more memory
+    brotli_window: Option<u32>,
+    /// Compress with a zstd dictionary shared by all of the files: one trained on them for
+    /// `train`, or else the one in this file
+    dict: Option<String>,
+    /// The dictionary from `dict`, or that of the archive being read
+    dictionary: Option<Vec<u8>>,
+    /// Report files with identical contents when reading an archive
+    duplicates: bool,
+    /// What to do about files that already exist when unpacking
+    on_conflict: OnConflict,
+    /// How many leading directories to drop from the names of unpacked files
+    strip_components: usize,
+    /// Give unpacked files the owner and group they were packed with
+    preserve_owner: bool,
+    /// Leave unpacked files with the default permissions instead of their packed ones
+    no_preserve_mode: bool,
+    /// Permission bits cleared from the packed mode of unpacked files, like a umask
+    mode_mask: u32,
+    /// The permissions of the directories made for unpacked files that the archive doesn't have
+    dir_mode: Option<u32>,
+    /// Leave out files that can't be read when packing, instead of failing
+    skip_errors: bool,
+    /// Store only the data of sparse files, leaving out their holes
+    sparse: bool,
+    /// How many levels of directories to pack below the paths given, with 0 packing only the
+    /// paths themselves
+    max_depth: Option<usize>,
+    /// The directory the names of packed files are relative to, instead of the one each path to
+    /// pack is in
+    base: Option<String>,
+    /// The order files are stored in
+    sort: SortOrder,
+    /// Fail when reading or unpacking an archive that doesn't end right after its last file
+    strict: bool,
+    /// Unpack the files of a truncated archive up to where it ends, instead of failing
+    salvage: bool,
+    /// Roughly how many bytes of memory may be used for file data
+    max_memory: Option<u64>,
+    /// Refuse archives with a file claiming more data than this
+    max_member_size: Option<u64>,
+    /// How many files to compress at once -- the number of CPUs if omitted
+    jobs: Option<usize>,
+    /// Compress files bigger than this in blocks of this size, several blocks at once
+    block_size: Option<usize>,
+    /// Store timestamps with subsecond precision
+    subsec_times: bool,
+    /// Store the contents of identical files only once
+    dedup: bool,
+    /// Write an index after the files, for finding them without reading the whole archive
+    index: bool,
+    /// End the archive with the SHA-256 of everything before it, for verify to check
+    digest: bool,
+    /// Store files so that the same ones always make the same archive, with their modification
+    /// times clamped to this many seconds since the UNIX epoch
+    reproducible: Option<u64>,
+    /// Write the SHA-256 of every packed file to this file, in the format of `sha256sum`
+    manifest: Option<String>,
+    /// Report how long the phases of packing took, or decompression throughput when verifying
+    stats: bool,
+    /// Report progress as lines of JSON on stderr
+    progress_json: bool,
+    /// Only report what packing or unpacking would do, without writing anything
+    dry_run: bool,
+    /// Report how many files and bytes are done on stderr, a few times a second
+    progress: bool,
+    /// Print the contents of an archive as JSON on stdout
+    json: bool,
+    /// List sizes like 1.5 MiB rather than in bytes
+    human_sizes: bool,
+    /// Sign the archive with the Ed25519 secret key in this file
+    #[cfg(feature = "signing")]
+    sign: Option<String>,
+    /// Check the archive's signature against the Ed25519 public key in this file
+    #[cfg(feature = "signing")]
+    key: Option<String>,
+    /// Encrypt the packed files, or decrypt the unpacked ones, with a key derived from this
+    #[cfg(feature = "encryption")]
+    passphrase: Option<String>,
+    /// The key derived from `passphrase`, once the archive's salt is known
+    #[cfg(feature = "encryption")]
+    cipher: Option<encryption::Cipher>,
+}
+
+/// How many bytes brotli buffers at a time without `-buffer-size`.
+const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The sizes `-block-size` accepts. Smaller blocks compress worse, and every block being worked
+/// on is held in memory twice over. Block lengths have to fit in the `u32` before each block.
+const BLOCK_SIZES: std::ops::RangeInclusive<u64> = 64 << 10..=256 << 20;
+
+impl Opts {
+    /// How many bytes brotli buffers at a time.
+    fn buffer_size(&self) -> usize {
+        self.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE)
+    }
+
+    /// The metadata of a file to pack, which is that of what it points to with `-dereference`.
+    fn metadata(&self, path: &std::path::Path) -> io::Result<std::fs::Metadata> {
+        match self.dereference {
+            true => std::fs::metadata(path),
+            false => std::fs::symlink_metadata(path),
+        }
+    }
+
+    /// How many threads to compress with, or decompress blocks with.
+    fn jobs(&self) -> usize {
+        self.jobs
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |x| x.get()))
+    }
+}
+
+/// Why a command failed.
+#[derive(Debug)]
+pub enum MarkError {
+    /// The arguments don't make sense.
+    Usage(String),
+    /// The archive is malformed.
+    Invalid(String),
+    /// The archive ends early, so only the files before that were unpacked with `-salvage`.
+    Truncated(String),
+    /// Files in the archive don't match their checksums or fail to decrypt, or the archive
+    /// doesn't match its signature.
+    Corrupt(String),
+    /// Reading or writing failed, while doing what `context` says if it's given.
+    Io {
+        context: Option<String>,
+        source: io::Error,
+    },
+}
+
+impl MarkError {
+    /// The code `mark` exits with because of this error:
+    ///
+    /// - 1 for bad arguments (a wrong `-passphrase` among them), or a `-max-memory` limit too low
+    ///   for what's asked,
+    /// - 2 for I/O errors,
+    /// - 3 for archives that are malformed or truncated,
+    /// - 4 for files that fail their checksums or the authentication of their encrypted data, and
+    ///   archives that fail their signatures, and
+    /// - 5 for truncated archives that `-salvage` unpacked what it could of.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Usage(_) => 1,
+            // Reading more of an archive into memory than -max-memory allows
+            Self::Io { source, .. } if source.kind() == io::ErrorKind::OutOfMemory => 1,
+            Self::Io { source, .. }
+                if matches!(
+                    source.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                3
+            }
+            Self::Io { .. } => 2,
+            Self::Invalid(_) => 3,
+            Self::Corrupt(_) => 4,
+            Self::Truncated(_) => 5,
+        }
+    }
+}
+
+impl std::fmt::Display for MarkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Usage(message)
+            | Self::Invalid(message)
+            | Self::Truncated(message)
+            | Self::Corrupt(message) => f.write_str(message),
+            Self::Io {
+                context: Some(context),
+                source,
+            } => write!(f, "{context}: {source}"),
+            Self::Io {
+                context: None,
+                source,
+            } => write!(f, "{source}"),
+        }
+    }
+}
+
+impl std::error::Error for MarkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MarkError {
+    fn from(source: io::Error) -> Self {
+        if failed_authentication(&source) {
+            return Self::Corrupt(source.to_string());
+        }
+        Self::Io {
+            context: None,
+            source,
+        }
+    }
+}
+
+/// Adds context to I/O errors, like which file was being read.
+trait Context<T> {
+    fn context(self, context: impl FnOnce() -> String) -> Result<T, MarkError>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, context: impl FnOnce() -> String) -> Result<T, MarkError> {
+        self.map_err(|source| match failed_authentication(&source) {
+            true => MarkError::Corrupt(format!("{}: {source}", context())),
+            false => MarkError::Io {
+                context: Some(context()),
+                source,
+            },
+        })
+    }
+}
+
+/// Makes a regular file named `name` out of `contents`, compressed (and encrypted) like `pack`
+/// would. It's given the default permissions, and the current time unless `-reproducible` says
+/// otherwise.
+fn new_file(opts: &Opts, name: &str, mut contents: Vec<u8>) -> io::Result<FileHeaderRepr> {
+    let path = std::path::Path::new(name);
+    if let Some(line_endings) = opts.line_endings {
+        if is_text(path, &contents[..contents.len().min(SAMPLE_SIZE)]) {
+            contents = convert_line_endings(&contents, line_endings.crlf_on_pack());
+        }
+    }
+    let uncompressed_len = contents.len() as u64;
+    let (compression, data) = compress_contents(opts, path, contents)?;
+    let data_compression = compression as u8;
+    #[cfg(feature = "encryption")]
+    let (data_compression, data) = match &opts.cipher {
+        Some(cipher) => {
+            let mut encrypted = vec![];
+            cipher
+                .encryptor(name.as_bytes(), Box::new(io::Cursor::new(data)))
+                .map_err(io::Error::other)?
+                .read_to_end(&mut encrypted)?;
+            (data_compression | ENCRYPTED_DATA, encrypted)
+        }
+        None => (data_compression, data),
+    };
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO);
+    let modified = opts
+        .reproducible
+        .map_or(now.as_secs(), |epoch| epoch.min(now.as_secs()));
+    let mut file = FileHeaderRepr::new(
+        FileHeader {
+            modified,
+            accessed: modified,
+            mode: S_IFREG | 0o644,
+            data_compression,
+            uncompressed_len,
+            len: data.len() as u64,
+            ..Default::default()
+        },
+        name.as_bytes().to_vec(),
+        data,
+    );
+    if opts.subsec_times && opts.reproducible.is_none() {
+        file.modified_nanos = now.subsec_nanos();
+        file.accessed_nanos = now.subsec_nanos();
+    }
+    Ok(file)
+}
+
+/// Canonicalizes the path of a file to pack. Unlike [`std::fs::canonicalize`], a symlink itself
+/// isn't resolved, only the directories leading up to it.
+fn canonicalize_entry(path: &std::path::Path) -> io::Result<std::path::PathBuf> {
+    let Some(file_name) = path.file_name() else {
+        return std::fs::canonicalize(path);
+    };
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    Ok(std::fs::canonicalize(parent)?.join(file_name))
+}
+
+/// Compresses everything in `data` with `compression`, appending it to `output`. Returns the
+/// compressed size.
+///
+/// `level` is clamped to the range `compression` supports, and the method's default is used if
+/// it's `None`.
+fn compress(
+    opts: &Opts,
+    compression: DataCompression,
+    level: Option<u32>,
+    data: &mut dyn Read,
+    output: &mut Vec<u8>,
+) -> io::Result<usize> {
+    compressor(opts, compression, level, data)?.read_to_end(output)
+}
+
+/// Wraps `data` in a reader that compresses it with `compression` as it's read, at `level` as
+/// described by [`compress`].
+fn compressor<'a>(
+    opts: &Opts,
+    compression: DataCompression,
+    level: Option<u32>,
+    data: impl Read + 'a,
+) -> io::Result<Box<dyn Read + 'a>> {
+    let level = level
+        .zip(compression.level_range())
+        .map(|(level, range)| level.clamp(*range.start(), *range.end()));
+    Ok(match compression {
+        DataCompression::None => Box::new(data),
+        DataCompression::Brotli => {
+            let mut params = BROTLI_ENC_PARAMS.clone();
+            if let Some(level) = level {
+                params.quality = level as i32;
+            }
+            if let Some(window) = opts.brotli_window {
+                params.lgwin = window as i32;
+            }
+            Box::new(brotli::enc::reader::CompressorReader::with_params(
+                data,
+                opts.buffer_size(),
+                &params,
+            ))
+        }
+        // Level 0 is zstd's default level
+        DataCompression::Zstd => match &opts.dictionary {
+            Some(dictionary) => Box::new(zstd::stream::read::Encoder::with_dictionary(
+                BufReader::new(data),
+                level.unwrap_or(0) as i32,
+                dictionary,
+            )?),
+            None => Box::new(zstd::stream::read::Encoder::new(
+                data,
+                level.unwrap_or(0) as i32,
+            )?),
+        },
+        DataCompression::Gzip => {
+            let level = level.map_or(flate2::Compression::default(), flate2::Compression::new);
+            Box::new(flate2::read::GzEncoder::new(data, level))
+        }
+        // Level 6 is xz's default preset
+        DataCompression::Xz => Box::new(xz2::read::XzEncoder::new(data, level.unwrap_or(6))),
+    })
+}
+
+/// Like [`compressor`], but splits `data` into blocks of `-block-size` that are compressed on
+/// their own, `-jobs` of them at once. See [`BLOCKS_DATA`].
+fn block_compressor<'a>(
+    opts: &Opts,
+    compression: DataCompression,
+    level: Option<u32>,
+    data: Box<dyn Read + 'a>,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(Box::new(BlockCompressor {
+        opts: opts.clone(),
+        compression,
+        level,
+        block_size: opts.block_size.unwrap_or(*BLOCK_SIZES.start() as usize),
+        jobs: opts.jobs(),
+        inner: data,
+        output: vec![],
+        position: 0,
+        done: false,
+    }))
+}
+
+/// What a map parsed by [`cli::parse_extension_map`] has for `name`: the value of its extension or
+/// else the `*` fallback, if there's either.
+fn lookup_extension<T: Copy>(map: &[(String, T)], name: &std::path::Path) -> Option<T> {
+    let lookup = |wanted: &str| {
+        map.iter()
+            .find(|(extension, _)| extension.eq_ignore_ascii_case(wanted))
+            .map(|&(_, value)| value)
+    };
+    name.extension()
+        .and_then(|x| x.to_str())
+        .and_then(lookup)
+        .or_else(|| lookup("*"))
+}
+
+/// The compression level `-level-map` picks for `name`, and otherwise `-level`.
+fn level_for(opts: &Opts, name: &std::path::Path) -> Option<u32> {
+    lookup_extension(&opts.level_map, name).or(opts.level)
+}
+
+/// The compression method `-compress-map` picks for `name`, and otherwise `-compress`.
+fn compression_for(opts: &Opts, name: &std::path::Path) -> DataCompression {
+    lookup_extension(&opts.compress_map, name).unwrap_or(opts.compression_method)
+}
+
+/// The size of the leading sample `-auto-compress` tries every compression method on.
+const AUTO_COMPRESS_SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Picks whichever compression method shrinks `sample` the most. Ties go to the method listed
+/// first in [`DataCompression::ALL`], which are the cheaper ones.
+fn best_compression(opts: &Opts, sample: &[u8]) -> io::Result<DataCompression> {
+    let mut best = (DataCompression::None, sample.len());
+    for &compression in &DataCompression::ALL[1..] {
+        let mut compressed = vec![];
+        let size = compress(opts, compression, None, &mut &sample[..], &mut compressed)?;
+        if size < best.1 {
+            best = (compression, size);
+        }
+    }
+    Ok(best.0)
+}
+
+/// How much of each file a dictionary is trained on.
+const DICTIONARY_SAMPLE_SIZE: u64 = 128 * 1024;
+
+/// The most data a dictionary is trained on, which bounds the memory and time training takes.
+const DICTIONARY_SAMPLES_SIZE: usize = 16 << 20;
+
+/// The size of trained dictionaries, zstd's default.
+const DICTIONARY_SIZE: usize = 110 * 1024;
+
+/// Fills in `opts.dictionary` for `-dict`, training it on `files` if asked to and they're known
+/// up front.
+fn load_dictionary(
+    opts: &mut Opts,
+    files: Option<&[(std::ffi::OsString, std::path::PathBuf, bool)]>,
+) -> Result<(), MarkError> {
+    let Some(dict) = &opts.dict else {
+        return Ok(());
+    };
+    let zstd = std::iter::once(opts.compression_method)
+        .chain(opts.compress_map.iter().map(|&(_, method)| method))
+        .any(|method| method == DataCompression::Zstd);
+    if !zstd && !opts.auto_compress {
+        return Err(MarkError::Usage(
+            "-dict only helps files compressed with zstd, give -compress zstd too".into(),
+        ));
+    }
+    #[cfg(feature = "encryption")]
+    if opts.passphrase.is_some() {
+        return Err(MarkError::Usage(
+            "-dict can't be used with -passphrase, the dictionary isn't encrypted and would give away what's in the files".into(),
+        ));
+    }
+    opts.dictionary = match (dict.as_str(), files) {
+        ("train", Some(files)) => train_dictionary(opts, files),
+        ("train", None) => {
+            return Err(MarkError::Usage(
+                "-dict train needs all of the files up front, give a dictionary file instead"
+                    .into(),
+            ))
+        }
+        (path, _) => Some(
+            std::fs::read(path).context(|| format!("Failed to read the dictionary \"{path}\""))?,
+        ),
+    };
+    Ok(())
+}
+
+/// Trains a zstd dictionary on the start of every regular file in `files`, or warns and returns
+/// `None` if that doesn't work out, like when there are too few of them.
+fn train_dictionary(
+    opts: &Opts,
+    files: &[(std::ffi::OsString, std::path::PathBuf, bool)],
+) -> Option<Vec<u8>> {
+    let mut samples = vec![];
+    let mut total = 0;
+    for (_, path, is_dir) in files {
+        if total >= DICTIONARY_SAMPLES_SIZE {
+            break;
+        }
+        if *is_dir || !opts.dereference && path.is_symlink() {
+            continue;
+        }
+        // Files that can't be read fail when they're packed, or are left out with -skip-errors
+        let mut sample = vec![];
+        let read = std::fs::File::open(path)
+            .and_then(|file| file.take(DICTIONARY_SAMPLE_SIZE).read_to_end(&mut sample));
+        if read.is_ok() {
+            total += sample.len();
+            samples.push(sample);
+        }
+    }
+    match zstd::dict::from_samples(&samples, DICTIONARY_SIZE) {
+        Ok(dictionary) => {
+            if !opts.quiet {
+                eprintln!(
+                    "Trained a dictionary of {} bytes on {} file(s)",
+                    dictionary.len(),
+                    samples.len()
+                );
+            }
+            Some(dictionary)
+        }
+        Err(e) => {
+            eprintln!(
+                "Warning: packing without a dictionary, one can't be trained on these files: {e}"
+            );
+            None
+        }
+    }
+}
+
+/// The [`ArchiveHeader::flags`] of an archive packed with `opts`.
+fn archive_flags(opts: &Opts) -> u32 {
+    let mut flags = 0;
+    if opts.index {
+        flags |= INDEXED;
+    }
+    if opts.dictionary.is_some() {
+        flags |= DICTIONARY;
+    }
+    flags
+}
+
+/// A conversion of line endings in text files, similar to git's `core.autocrlf`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineEndings {
+    /// Store LF line endings and restore the platform's convention on unpack.
+    Auto,
+    ToLf,
+    ToCrlf,
+}
+
+impl LineEndings {
+    /// Whether text files should have CRLF line endings once packed.
+    fn crlf_on_pack(self) -> bool {
+        self == Self::ToCrlf
+    }
+
+    /// Whether text files should have CRLF line endings once unpacked.
+    fn crlf_on_unpack(self) -> bool {
+        match self {
+            Self::Auto => cfg!(windows),
+            Self::ToLf => false,
+            Self::ToCrlf => true,
+        }
+    }
+}
+
+impl std::str::FromStr for LineEndings {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "to-lf" => Self::ToLf,
+            "to-crlf" => Self::ToCrlf,
+            _ => return Err("unsupported line ending conversion"),
+        })
+    }
+}
+
+/// Extensions of formats that are always text, whatever their contents look like.
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "rst", "rs", "c", "h", "cpp", "hpp", "cc", "py", "js", "ts", "go", "java", "sh",
+    "toml", "yaml", "yml", "json", "xml", "html", "css", "csv", "ini", "cfg",
+];
+
+/// Guesses whether a file is text, from its extension or (like git) from there being no NUL
+/// bytes in its first block.
+fn is_text(name: &std::path::Path, sample: &[u8]) -> bool {
+    let known_extension = name.extension().and_then(|x| x.to_str()).is_some_and(|x| {
+        TEXT_EXTENSIONS
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(x))
+    });
+    known_extension || (!sample.contains(&0) && !looks_compressed(name, sample))
+}
+
+/// Converts every line ending in `data` to CRLF if `crlf` is set, or to LF otherwise. Mixed line
+/// endings are handled, and lone CRs are left alone.
+fn convert_line_endings(data: &[u8], crlf: bool) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(data.len() + data.len() / 32);
+    for (i, &b) in data.iter().enumerate() {
+        let after_cr = i > 0 && data[i - 1] == b'\r';
+        if b == b'\n' {
+            if crlf && !after_cr {
+                converted.push(b'\r');
+            } else if !crlf && after_cr {
+                converted.pop();
+            }
+        }
+        converted.push(b);
+    }
+    converted
+}
+
+/// Formats `bytes` as space-separated hex, for reporting names that can't be displayed.
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The directories version control systems keep a working copy's history in, for
+/// `-exclude-vcs`.
+const VCS_DIRS: &[&str] = &[".git", ".svn", ".hg", ".bzr", "CVS"];
+
+/// Checks whether `dir` contains any of the `-exclude-if-present` marker files, reporting it as
+/// skipped if so.
+fn has_exclusion_marker(opts: &Opts, dir: &std::path::Path) -> bool {
+    let Some(marker) = opts
+        .exclude_if_present
+        .iter()
+        .find(|marker| dir.join(marker).exists())
+    else {
+        return false;
+    };
+    if !opts.quiet {
+        eprintln!("Skipping \"{}\", it contains \"{marker}\"", dir.display());
+    }
+    true
+}
+
+/// The `.gitignore` files of the directories being packed, loaded as they're needed.
+#[derive(Default)]
+struct Gitignores {
+    by_dir: std::collections::HashMap<std::path::PathBuf, Option<ignore::gitignore::Gitignore>>,
+}
+
+impl Gitignores {
+    /// Whether `path` is ignored by the `.gitignore` of any directory from `root` down to its
+    /// parent. Like git, the `.gitignore` closest to the path takes precedence, so a nested one
+    /// can re-include a file with a negated pattern.
+    fn ignored(&mut self, root: &std::path::Path, path: &std::path::Path, is_dir: bool) -> bool {
+        for dir in path.ancestors().skip(1) {
+            if !dir.starts_with(root) {
+                break;
+            }
+            let gitignore = self.by_dir.entry(dir.to_path_buf()).or_insert_with(|| {
+                let file = dir.join(".gitignore");
+                if !file.is_file() {
+                    return None;
+                }
+                let (gitignore, error) = ignore::gitignore::Gitignore::new(&file);
+                if let Some(error) = error {
+                    eprintln!("Warning: \"{}\": {error}", file.display());
+                }
+                Some(gitignore)
+            });
+            match gitignore.as_ref().map(|x| x.matched(path, is_dir)) {
+                Some(ignore::Match::Ignore(_)) => return true,
+                Some(ignore::Match::Whitelist(_)) => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+/// The number of leading bytes of a file inspected by [`looks_compressed`].
+const SAMPLE_SIZE: usize = 4096;
+
+/// Extensions of formats whose contents are (almost) always compressed already.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "mark", "gz", "tgz", "bz2", "xz", "txz", "zst", "br", "lz4", "lzma", "zip", "7z", "rar", "jar",
+    "apk", "deb", "rpm", "jpg", "jpeg", "png", "gif", "webp", "avif", "heic", "mp3", "m4a", "aac",
+    "ogg", "opus", "flac", "mp4", "m4v", "mkv", "webm", "mov", "avi", "woff", "woff2",
+];
+
+/// Guesses whether a file is already compressed, in which case compressing it again would only
+/// waste time. A file is considered compressed if its extension is a known compressed format, or
+/// if its first block (`sample`) looks like random noise.
+fn looks_compressed(name: &std::path::Path, sample: &[u8]) -> bool {
+    let known_extension = name.extension().and_then(|x| x.to_str()).is_some_and(|x| {
+        COMPRESSED_EXTENSIONS
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(x))
+    });
+    if known_extension {
+        return true;
+    }
+
+    // The entropy estimate is meaningless for tiny samples.
+    if sample.len() < 256 {
+        return false;
+    }
+    let mut counts = [0usize; 256];
+    for &b in sample {
+        counts[b as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&c| c != 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+
+    // In bits per byte; text sits around 4-5 while compressed data is very close to 8.
+    entropy > 7.5
+}
+
+/// Opens the archive to read, decompressing it if it's in a [`Container`] and decoding it from
+/// text if asked to.
+fn open_input(opts: &Opts) -> Result<Box<dyn Read>, MarkError> {
+    let input: Box<dyn Read> = match opts.input.as_deref() {
+        Some(input) => {
+            let opening = || format!("Failed to open \"{input}\"");
+            let file = BufReader::new(std::fs::File::open(input).context(opening)?);
+            match Container::of(input) {
+                Some(Container::Gzip) => {
+                    Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(file)))
+                }
+                Some(Container::Zstd) => Box::new(BufReader::new(
+                    zstd::stream::read::Decoder::with_buffer(file).context(opening)?,
+                )),
+                None => Box::new(file),
+            }
+        }
+        None => Box::new(BufReader::new(std::io::stdin().lock())),
+    };
+    Ok(match opts.decode {
+        Some(encoding) => Box::new(TextDecoder::new(input, encoding)),
+        None => input,
+    })
+}
+
+/// An archive opened by [`open_archive`].
+enum Input {
+    Stream(Box<dyn Read>),
+    File(BufReader<std::fs::File>),
+}
+
+impl Input {
+    fn reader(&mut self) -> io::Result<ArchiveReader<'_>> {
+        let archive = match self {
+            Self::Stream(reader) => ArchiveReader::new(&mut **reader),
+            Self::File(file) => ArchiveReader::new_seekable(file),
+        };
+        archive.map(reporting_warnings)
+    }
+}
+
+/// Like [`open_input`], but keeps an archive file that isn't compressed or text-encoded
+/// seekable, so it can be read through its [`ArchiveIndex`].
+fn open_archive(opts: &Opts) -> Result<Input, MarkError> {
+    match (&opts.input, opts.decode) {
+        (Some(input), None) if Container::of(input).is_none() => Ok(Input::File(BufReader::new(
+            std::fs::File::open(input).context(|| format!("Failed to open \"{input}\""))?,
+        ))),
+        _ => Ok(Input::Stream(open_input(opts)?)),
+    }
+}
+
+/// Checks that every file in the archive decompresses to the size it's supposed to have.
+fn verify(mut opts: Opts) -> Result<(), MarkError> {
+    #[cfg(feature = "signing")]
+    if opts.key.is_some() && opts.input.is_none() {
+        return Err(MarkError::Usage(
+            "-key needs an -input, the signature is read from next to it".into(),
+        ));
+    }
+    #[cfg(feature = "signing")]
+    let mut hasher = match opts.key {
+        Some(_) => Some(HashingReader::new(open_input(&opts)?)),
+        None => None,
+    };
+    #[cfg(feature = "signing")]
+    let input: &mut dyn Read = match &mut hasher {
+        Some(hasher) => hasher,
+        None => &mut *open_input(&opts)?,
+    };
+    #[cfg(not(feature = "signing"))]
+    let input = &mut *open_input(&opts)?;
+    // Whether the archive has a digest to check is only known once its header has been read
+    let mut digester = DigestReader::new(input);
+
+    // Files, decompressed bytes, and time spent decompressing, by compression method
+    let mut stats = [(0usize, 0u64, Duration::ZERO); DataCompression::ALL.len()];
+    let mut failures = 0usize;
+    let mut archive = ArchiveReader::new(&mut digester)
+        .map(reporting_warnings)
+        .context(|| "Failed to read the archive header".into())?;
+    let file_count = match archive.header.is_streamed() {
+        true => String::new(),
+        false => format!(" of {}", archive.header.file_count),
+    };
+    let unlocked = unlock(&mut opts, archive.header, false)?;
+    opts.dictionary = archive.dictionary.clone();
+    if !unlocked {
+        eprintln!("Without -passphrase, only the checksums of the encrypted files are checked");
+    }
+    // The name and data length of every file, to check the index against
+    let mut indexed = vec![];
+    while let Some(file) = archive
+        .next_file(ReadOptions {
+            skip_data: false,
+            require_utf8_names: opts.require_utf8_names,
+            max_data_len: opts.max_memory,
+            max_member_size: opts.max_member_size,
+            only: None,
+        })
+        .context(|| format!("Failed to read file {}{file_count}", archive.read.max(1)))?
+    {
+        if archive.header.is_indexed() {
+            indexed.push((file.name_bytes().to_vec(), file.inner.len));
+        }
+        if !file.checksum_matches(archive.layout) {
+            eprintln!("\"{}\": checksum doesn't match", file.name);
+            failures += 1;
+            continue;
+        }
+        if let Some(target) = file.inner.link() {
+            if target + 1 >= archive.read as usize {
+                eprintln!(
+                    "\"{}\": shares the data of file {}, which doesn't come before it",
+                    file.name,
+                    target + 1
+                );
+                failures += 1;
+            }
+            continue;
+        }
+        let Ok(compression) = file.inner.compression() else {
+            eprintln!("\"{}\": unknown compression method", file.name);
+            failures += 1;
+            continue;
+        };
+        if file.inner.is_encrypted() && !unlocked {
+            continue;
+        }
+        let mut sink = CountingWriter::new(io::sink());
+        let start = std::time::Instant::now();
+        let result = decrypted(&opts, &file, &file.data[..])
+            .and_then(|mut data| decompress_from(&opts, &file.inner, &mut data, &mut sink));
+        let elapsed = start.elapsed();
+        match result {
+            Err(e) => {
+                eprintln!("\"{}\": {e}", file.name);
+                failures += 1;
+            }
+            Ok(()) if sink.count != file.inner.uncompressed_len => {
+                eprintln!(
+                    "\"{}\": decompressed to {} bytes instead of {}",
+                    file.name, sink.count, file.inner.uncompressed_len
+                );
+                failures += 1;
+            }
+            Ok(()) => {}
+        }
+        let entry = &mut stats[compression as usize];
+        entry.0 += 1;
+        entry.1 += sink.count;
+        entry.2 += elapsed;
+    }
+    // The warning for this has been printed already
+    let mut malformed = false;
+    if !archive.ended_cleanly {
+        eprintln!(
+            "The archive doesn't end right after file {}{file_count}",
+            archive.read
+        );
+        malformed = true;
+    }
+    if archive.header.is_indexed() && archive.ended_cleanly {
+        match ArchiveIndex::read(&mut archive.reader) {
+            Ok((index, _)) => {
+                let matches = index.entries.len() == indexed.len()
+                    && index
+                        .entries
+                        .iter()
+                        .zip(&indexed)
+                        .all(|(entry, (name, len))| entry.name == *name && entry.len == *len);
+                if !matches {
+                    eprintln!("The archive's index doesn't match its files");
+                    malformed = true;
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to read the archive's index: {e}");
+                malformed = true;
+            }
+        }
+    }
+    let mut digest = None;
+    if archive.header.has_digest() && archive.ended_cleanly {
+        match read_digest(&mut archive.reader) {
+            Ok(x) => digest = Some(x),
+            Err(e) => {
+                eprintln!("Failed to read the archive's digest: {e}");
+                malformed = true;
+            }
+        }
+    }
+    let mut trailing = [0u8; 1];
+    if read_up_to(&mut archive.reader, &mut trailing)
+        .context(|| "Failed to read the archive".into())?
+        > 0
+    {
+        eprintln!("There's more data after the end of the archive");
+        malformed = true;
+    }
+
+    if opts.stats {
+        let throughput = |bytes: u64, time: Duration| {
+            let secs = time.as_secs_f64();
+            if secs > 0.0 {
+                format!("{:.1}", bytes as f64 / secs / 1e6)
+            } else {
+                "-".to_string()
+            }
+        };
+        eprintln!(
+            "{:<8} {:>8} {:>14} {:>10} {:>10}",
+            "Codec", "Files", "Bytes", "Seconds", "MB/s"
+        );
+        let mut total = (0usize, 0u64, Duration::ZERO);
+        for compression in DataCompression::ALL {
+            let (files, bytes, time) = stats[compression as usize];
+            total = (total.0 + files, total.1 + bytes, total.2 + time);
+            if files == 0 {
+                continue;
+            }
+            eprintln!(
+                "{:<8} {files:>8} {bytes:>14} {:>10.3} {:>10}",
+                format!("{compression:?}"),
+                time.as_secs_f64(),
+                throughput(bytes, time)
+            );
+        }
+        let (files, bytes, time) = total;
+        eprintln!(
+            "{:<8} {files:>8} {bytes:>14} {:>10.3} {:>10}",
+            "Total",
+            time.as_secs_f64(),
+            throughput(bytes, time)
+        );
+    }
+
+    match (failures, malformed) {
+        (0, false) => {}
+        (0, true) => return Err(MarkError::Invalid("The archive isn't well-formed".into())),
+        (_, false) => {
+            return Err(MarkError::Corrupt(format!(
+                "{failures} file(s) failed verification"
+            )))
+        }
+        (_, true) => {
+            return Err(MarkError::Invalid(format!(
+                "{failures} file(s) failed verification, and the archive isn't well-formed"
+            )))
+        }
+    }
+    if let Some(digest) = digest {
+        if digester.finalize() != digest {
+            return Err(MarkError::Corrupt(
+                "Digest: doesn't match, the archive has been changed since it was packed".into(),
+            ));
+        }
+        eprintln!("Digest: matches");
+    }
+    #[cfg(feature = "signing")]
+    if let (Some(key), Some(mut hasher), Some(input)) = (&opts.key, hasher, &opts.input) {
+        // Anything after the end of the archive is covered by the signature too
+        io::copy(&mut hasher, &mut io::sink())
+            .context(|| "Failed to read the rest of the archive".into())?;
+        let signature = signing::signature_path(input);
+        match signing::verify(key, &hasher.finalize(), &signature)? {
+            signing::Signature::Valid => eprintln!("Signature: valid"),
+            signing::Signature::Invalid => {
+                return Err(MarkError::Corrupt(format!(
+                    "Signature: INVALID ({signature})"
+                )));
+            }
+            signing::Signature::Missing => {
+                return Err(MarkError::Corrupt(format!(
+                    "Signature: missing, there's no {signature}"
+                )));
+            }
+        }
+    }
+
+    eprintln!("All files verified");
+    Ok(())
+}
+
+/// Reports progress through the files of an operation. With `-progress-json`, every event is
+/// printed to stderr as a line of JSON, such as:
+///
+/// ```text
+/// {"event":"start","total":100}
+/// {"event":"file","name":"x","done":42,"total":100,"bytes":1234}
+/// {"event":"done","done":100,"total":100,"bytes":5678}
+/// ```
+///
+/// `total` is `null` when the number of files isn't known up front, and `bytes` counts the
+/// uncompressed contents of every file so far.
+///
+/// With `-progress`, a line like `42/100 files, 1234 bytes` is printed to stderr a few times a
+/// second instead.
+struct Progress {
+    json: bool,
+    lines: bool,
+    /// When the last `-progress` line was printed, and how many files were done then.
+    printed: Option<(std::time::Instant, u64)>,
+    total: Option<u64>,
+    done: u64,
+    bytes: u64,
+}
+
+/// How often `-progress` prints a line at most.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+impl Progress {
+    fn new(opts: &Opts, total: Option<u64>) -> Self {
+        let progress = Self {
+            json: opts.progress_json,
+            lines: opts.progress,
+            printed: None,
+            total,
+            done: 0,
+            bytes: 0,
+        };
+        if progress.json {
+            eprintln!(
+                "{{\"event\":\"start\",\"total\":{}}}",
+                progress.json_total()
+            );
+        }
+        progress
+    }
+
+    fn json_total(&self) -> String {
+        self.total.map_or("null".to_string(), |x| x.to_string())
+    }
+
+    /// Records that the file `name`, with `bytes` of contents, has been handled.
+    fn file(&mut self, name: &str, bytes: u64) {
+        self.done += 1;
+        self.bytes = self.bytes.saturating_add(bytes);
+        if self.json {
+            eprintln!(
+                "{{\"event\":\"file\",\"name\":{},\"done\":{},\"total\":{},\"bytes\":{}}}",
+                json_string(name),
+                self.done,
+                self.json_total(),
+                self.bytes
+            );
+        }
+        if self.lines
+            && self
+                .printed
+                .is_none_or(|(x, _)| x.elapsed() >= PROGRESS_INTERVAL)
+        {
+            self.print_line();
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.json {
+            eprintln!(
+                "{{\"event\":\"done\",\"done\":{},\"total\":{},\"bytes\":{}}}",
+                self.done,
+                self.json_total(),
+                self.bytes
+            );
+        }
+        if self.lines && self.printed.is_none_or(|(_, done)| done != self.done) {
+            self.print_line();
+        }
+    }
+
+    fn print_line(&mut self) {
+        match self.total {
+            Some(total) => eprintln!("{}/{total} files, {} bytes", self.done, self.bytes),
+            None => eprintln!("{} files, {} bytes", self.done, self.bytes),
+        }
+        self.printed = Some((std::time::Instant::now(), self.done));
+    }
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Passes writes on to the inner writer, keeping count of how many bytes went through.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes reads on from the inner reader, keeping track of how long they took.
+struct TimedReader<R: Read> {
+    inner: R,
+    elapsed: Duration,
+}
+
+impl<R: Read> TimedReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            elapsed: Duration::ZERO,
+        }
+    }
+}
+
+impl<R: Read> Read for TimedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = std::time::Instant::now();
+        let n = self.inner.read(buf);
+        self.elapsed += start.elapsed();
+        n
+    }
+}
+
+/// Passes writes on to the inner writer, hashing everything that went through.
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: sha2::Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        use sha2::Digest;
+        Self {
+            inner,
+            hasher: sha2::Sha256::new(),
+        }
+    }
+
+    /// Gives back the inner writer, along with the hash of everything written.
+    fn into_parts(self) -> (W, [u8; 32]) {
+        use sha2::Digest;
+        (self.inner, self.hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use sha2::Digest;
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads exactly `len` bytes from a reader, failing if it has fewer or more than that.
+struct ExactLen<R: Read> {
+    inner: R,
+    left: u64,
+}
+
+impl<R: Read> ExactLen<R> {
+    fn new(inner: R, len: u64) -> Self {
+        Self { inner, left: len }
+    }
+}
+
+impl<R: Read> Read for ExactLen<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            return match self.inner.read(&mut [0u8; 1])? {
+                0 => Ok(0),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the data is longer than it's supposed to be",
+                )),
+            };
+        }
+        let len = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the data is shorter than it's supposed to be",
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads the data segments of a sparse file one after the other, leaving out the holes between
+/// them.
+struct SegmentsReader {
+    file: std::fs::File,
+    segments: std::vec::IntoIter<(u64, u64)>,
+    left: u64,
+}
+
+impl SegmentsReader {
+    fn new(file: std::fs::File, segments: Vec<(u64, u64)>) -> Self {
+        Self {
+            file,
+            segments: segments.into_iter(),
+            left: 0,
+        }
+    }
+}
+
+impl Read for SegmentsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use io::Seek;
+
+        while self.left == 0 {
+            let Some((offset, len)) = self.segments.next() else {
+                return Ok(0);
+            };
+            self.file.seek(io::SeekFrom::Start(offset))?;
+            self.left = len;
+        }
+        let len = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let n = self.file.read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the file got shorter while it was being packed",
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads the data of a sparse file from the inner reader with its holes filled back in as zeros.
+struct Unsparse<'a, R: Read> {
+    inner: R,
+    map: &'a SparseMap,
+    /// The next segment that hasn't been read all the way
+    segment: usize,
+    position: u64,
+}
+
+impl<'a, R: Read> Unsparse<'a, R> {
+    fn new(inner: R, map: &'a SparseMap) -> Self {
+        Self {
+            inner,
+            map,
+            segment: 0,
+            position: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Unsparse<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let fits = |left: u64| buf.len().min(left.try_into().unwrap_or(usize::MAX));
+        match self.map.segments.get(self.segment) {
+            Some(&(offset, len)) if self.position >= offset => {
+                let end = offset + len;
+                let n = fits(end - self.position);
+                let n = self.inner.read(&mut buf[..n])?;
+                if n == 0 && !buf.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the data is shorter than its sparse map",
+                    ));
+                }
+                self.position += n as u64;
+                if self.position == end {
+                    self.segment += 1;
+                }
+                Ok(n)
+            }
+            next => {
+                let hole_end = next.map_or(self.map.size, |&(offset, _)| offset);
+                let n = fits(hole_end - self.position);
+                buf[..n].fill(0);
+                self.position += n as u64;
+                Ok(n)
+            }
+        }
+    }
+}
+
+/// Writes the data of a sparse file to `file`, seeking over its holes instead of writing them so
+/// that they stay holes. The file still has to be extended to [`SparseMap::size`] at the end, in
+/// case it ends with a hole.
+struct SparseWriter<'a> {
+    file: &'a mut std::fs::File,
+    segments: std::slice::Iter<'a, (u64, u64)>,
+    left: u64,
+}
+
+impl<'a> SparseWriter<'a> {
+    fn new(file: &'a mut std::fs::File, map: &'a SparseMap) -> Self {
+        Self {
+            file,
+            segments: map.segments.iter(),
+            left: 0,
+        }
+    }
+}
+
+impl Write for SparseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use io::Seek;
+
+        while self.left == 0 && !buf.is_empty() {
+            let Some(&(offset, len)) = self.segments.next() else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the data is longer than its sparse map",
+                ));
+            };
+            self.file.seek(io::SeekFrom::Start(offset))?;
+            self.left = len;
+        }
+        let len = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        let n = self.file.write(&buf[..len])?;
+        self.left -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Runs `f` on each of `blocks` on a thread of its own, returning what it made of them in order.
+fn in_parallel(
+    blocks: &[Vec<u8>],
+    f: impl Fn(&[u8]) -> io::Result<Vec<u8>> + Sync,
+) -> io::Result<Vec<Vec<u8>>> {
+    if blocks.len() <= 1 {
+        return blocks.iter().map(|block| f(block)).collect();
+    }
+    let f = &f;
+    std::thread::scope(|scope| {
+        let threads = blocks
+            .iter()
+            .map(|block| scope.spawn(move || f(block)))
+            .collect::<Vec<_>>();
+        threads.into_iter().map(|x| x.join().unwrap()).collect()
+    })
+}
+
+/// Compresses the data read from the inner reader in blocks, see [`block_compressor`].
+struct BlockCompressor<'a> {
+    opts: Opts,
+    compression: DataCompression,
+    level: Option<u32>,
+    block_size: usize,
+    jobs: usize,
+    inner: Box<dyn Read + 'a>,
+    /// The blocks compressed last, with their lengths
+    output: Vec<u8>,
+    /// How much of `output` has been read
+    position: usize,
+    done: bool,
+}
+
+impl Read for BlockCompressor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() {
+            if self.done {
+                return Ok(0);
+            }
+            let mut blocks = vec![];
+            while blocks.len() < self.jobs && !self.done {
+                let mut block = vec![0u8; self.block_size];
+                let n = read_up_to(&mut self.inner, &mut block)?;
+                block.truncate(n);
+                self.done = n < self.block_size;
+                if n > 0 {
+                    blocks.push(block);
+                }
+            }
+            let compressed = in_parallel(&blocks, |block| {
+                let mut compressed = vec![];
+                compress(
+                    &self.opts,
+                    self.compression,
+                    self.level,
+                    &mut &block[..],
+                    &mut compressed,
+                )?;
+                Ok(compressed)
+            })?;
+            self.output.clear();
+            self.position = 0;
+            for block in compressed {
+                self.output
+                    .extend_from_slice(&(block.len() as u32).to_le_bytes());
+                self.output.extend_from_slice(&block);
+            }
+        }
+        let n = (&self.output[self.position..]).read(buf)?;
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Decompresses data written by a [`BlockCompressor`] as it's read, `-jobs` blocks at once.
+struct BlockDecompressor<'a, R: Read> {
+    opts: &'a Opts,
+    compression: DataCompression,
+    jobs: usize,
+    inner: R,
+    /// The blocks decompressed last
+    output: Vec<u8>,
+    /// How much of `output` has been read
+    position: usize,
+    done: bool,
+}
+
+impl<'a, R: Read> BlockDecompressor<'a, R> {
+    fn new(opts: &'a Opts, compression: DataCompression, inner: R) -> Self {
+        Self {
+            opts,
+            compression,
+            jobs: opts.jobs(),
+            inner,
+            output: vec![],
+            position: 0,
+            done: false,
+        }
+    }
+
+    /// Reads the next block's compressed data, if there's another one.
+    fn next_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let truncated = || {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the data ends partway through a block",
+            )
+        };
+        let mut len = [0u8; 4];
+        match read_up_to(&mut self.inner, &mut len)? {
+            0 => return Ok(None),
+            4 => {}
+            _ => return Err(truncated()),
+        }
+        let len = u32::from_le_bytes(len) as u64;
+        let mut block = vec![];
+        (&mut self.inner).take(len).read_to_end(&mut block)?;
+        if (block.len() as u64) < len {
+            return Err(truncated());
+        }
+        Ok(Some(block))
+    }
+}
+
+impl<R: Read> Read for BlockDecompressor<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() {
+            if self.done {
+                return Ok(0);
+            }
+            let mut blocks = vec![];
+            while blocks.len() < self.jobs {
+                match self.next_block()? {
+                    Some(block) => blocks.push(block),
+                    None => {
+                        self.done = true;
+                        break;
+                    }
+                }
+            }
+            let decompressed = in_parallel(&blocks, |block| {
+                let mut decompressed = vec![];
+                decompress_with(
+                    self.opts,
+                    self.compression,
+                    &mut &block[..],
+                    &mut decompressed,
+                )?;
+                Ok(decompressed)
+            })?;
+            self.output = decompressed.concat();
+            self.position = 0;
+        }
+        let n = (&self.output[self.position..]).read(buf)?;
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Passes reads on from the inner reader, hashing everything that went through.
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: sha2::Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        use sha2::Digest;
+        Self {
+            inner,
+            hasher: sha2::Sha256::new(),
+        }
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        use sha2::Digest;
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use sha2::Digest;
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Passes reads on from the inner reader, hashing everything that went through but the last
+/// [`DIGEST_LEN`] bytes, so that once it's been read to the end the hash is of everything before
+/// the digest of an archive with [`DIGEST`].
+struct DigestReader<R: Read> {
+    inner: R,
+    hasher: sha2::Sha256,
+    /// The last bytes read, which haven't been hashed yet
+    held: Vec<u8>,
+}
+
+impl<R: Read> DigestReader<R> {
+    fn new(inner: R) -> Self {
+        use sha2::Digest;
+        Self {
+            inner,
+            hasher: sha2::Sha256::new(),
+            held: Vec::with_capacity(2 * DIGEST_LEN),
+        }
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        use sha2::Digest;
+        self.hasher.finalize().into()
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use sha2::Digest;
+        let n = self.inner.read(buf)?;
+        let buf = &buf[..n];
+        // Only what's followed by a whole digest's worth of bytes can't be part of the digest
+        let hashed = (self.held.len() + n).saturating_sub(DIGEST_LEN);
+        let from_held = hashed.min(self.held.len());
+        self.hasher.update(&self.held[..from_held]);
+        self.held.drain(..from_held);
+        self.hasher.update(&buf[..hashed - from_held]);
+        self.held.extend_from_slice(&buf[hashed - from_held..]);
+        Ok(n)
+    }
+}
+
+/// The SHA-256 of everything in `reader`.
+fn digest_of(reader: impl Read) -> io::Result<[u8; 32]> {
+    let mut hasher = HashingReader::new(reader);
+    io::copy(&mut hasher, &mut io::sink())?;
+    Ok(hasher.finalize())
+}
+
+/// The parts of the file system only unix has, with stand-ins elsewhere, so archives made on one
+/// platform unpack on any other.
+mod platform {
+    use std::fs::Metadata;
+    use std::io;
+    use std::path::Path;
+
+    /// The file's mode, with its type and permissions. Without one, it's made up from the file's
+    /// type: 0o755 for directories and 0o644 for files (0o444 if they're read-only).
+    #[cfg(unix)]
+    pub fn mode(metadata: &Metadata) -> u32 {
+        std::os::unix::fs::MetadataExt::mode(metadata)
+    }
+
+    #[cfg(not(unix))]
+    pub fn mode(metadata: &Metadata) -> u32 {
+        use super::{S_IFDIR, S_IFLNK, S_IFREG};
+
+        if metadata.is_dir() {
+            S_IFDIR | 0o755
+        } else if metadata.is_symlink() {
+            S_IFLNK | 0o777
+        } else if metadata.permissions().readonly() {
+            S_IFREG | 0o444
+        } else {
+            S_IFREG | 0o644
+        }
+    }
+
+    /// The user and group that own the file, or root's without them.
+    #[cfg(unix)]
+    pub fn owner(metadata: &Metadata) -> (u32, u32) {
+        use std::os::unix::fs::MetadataExt;
+
+        (metadata.uid(), metadata.gid())
+    }
+
+    #[cfg(not(unix))]
+    pub fn owner(_: &Metadata) -> (u32, u32) {
+        (0, 0)
+    }
+
+    /// The device and inode of the file, which tell whether two paths are the same file, and how
+    /// many hard links it has. Without them, every path is its own file.
+    #[cfg(unix)]
+    pub fn identity(metadata: &Metadata) -> Option<((u64, u64), u64)> {
+        use std::os::unix::fs::MetadataExt;
+
+        Some(((metadata.dev(), metadata.ino()), metadata.nlink()))
+    }
+
+    #[cfg(not(unix))]
+    pub fn identity(_: &Metadata) -> Option<((u64, u64), u64)> {
+        None
+    }
+
+    /// Makes a name from the bytes stored in an archive. Without byte names, ones that aren't
+    /// UTF-8 are made valid first.
+    #[cfg(unix)]
+    pub fn os_string(bytes: Vec<u8>) -> std::ffi::OsString {
+        std::os::unix::ffi::OsStringExt::from_vec(bytes)
+    }
+
+    #[cfg(not(unix))]
+    pub fn os_string(bytes: Vec<u8>) -> std::ffi::OsString {
+        String::from_utf8_lossy(&bytes).into_owned().into()
+    }
+
+    /// Gives the file at `path` the permissions in `mode`, which are ignored without them.
+    #[cfg(unix)]
+    pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    pub fn set_mode(_: &Path, _: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Gives `path` (or the symlink itself) an owner and group, which are ignored without them.
+    #[cfg(unix)]
+    pub fn lchown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        std::os::unix::fs::lchown(path, Some(uid), Some(gid))
+    }
+
+    #[cfg(not(unix))]
+    pub fn lchown(_: &Path, _: u32, _: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Creates a symlink at `path` pointing to `target`.
+    #[cfg(unix)]
+    pub fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, path)
+    }
+
+    #[cfg(windows)]
+    pub fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+        std::os::windows::fs::symlink_file(target, path)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    pub fn symlink(_: &Path, _: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "symlinks aren't supported on this platform",
+        ))
+    }
+}
+
+fn read_archive(mut opts: Opts) -> Result<(), MarkError> {
+    let Listing {
+        header,
+        files,
+        hashes,
+    } = read_headers(&mut opts)?;
+    if opts.json {
+        print_json(header, &files);
+    } else if header.is_streamed() {
+        eprintln!(
+            "Format version: {}; File count: {} (streamed)",
+            header.version,
+            files.len()
+        );
+    } else {
+        eprintln!(
+            "Format version: {}; File count: {}",
+            header.version, header.file_count
+        );
+    }
+    if let Some(dictionary) = opts.dictionary.as_ref().filter(|_| !opts.json) {
+        eprintln!("Dictionary: {} bytes", dictionary.len());
+    }
+    for file in files.iter().filter(|_| !opts.json) {
+        let compression = file
+            .inner
+            .compression()
+            .map_or("unknown compression".to_string(), |x| format!("{x:?}"));
+        let encrypted = match file.inner.is_encrypted() {
+            true => ", encrypted",
+            false => "",
+        };
+        let blocks = match file.inner.has_blocks() {
+            true => " in blocks",
+            false => "",
+        };
+        let sparse = match &file.sparse {
+            Some(sparse) => format!(", sparse with {} bytes in all", sparse.size),
+            None => String::new(),
+        };
+        eprintln!(
+            "{} :: {:?} ({compression}{blocks}{encrypted}{sparse})",
+            file.name, file.inner
+        );
+    }
+    if opts.duplicates {
+        report_duplicates(&files, &hashes);
+    }
+    Ok(())
+}
+
+/// Prints the files in the archive to stdout in columns, like `ls -l`:
+///
+/// ```text
+/// Mode          Size  Compressed  Name
+/// drwxr-xr-x       0           0  src
+/// -rw-r--r--    6066        1718  src/lib.rs
+/// ```
+///
+/// Files sharing the data of an earlier one (see `-dedup`) have no compressed size of their own.
+fn list(mut opts: Opts) -> Result<(), MarkError> {
+    let Listing { files, hashes, .. } = read_headers(&mut opts)?;
+    let size = |bytes: u64| match opts.human_sizes {
+        true => human_size(bytes),
+        false => bytes.to_string(),
+    };
+    let rows = files
+        .iter()
+        .map(|file| {
+            let stored = match file.inner.link() {
+                Some(_) => 0,
+                None => file.inner.len,
+            };
+            (
+                symbolic_mode(file.inner.mode),
+                size(file.size()),
+                size(stored),
+            )
+        })
+        .collect::<Vec<_>>();
+    let width = |column: fn(&(String, String, String)) -> &String, title: &str| {
+        rows.iter()
+            .map(|x| column(x).len())
+            .fold(title.len(), usize::max)
+    };
+    let size_width = width(|x| &x.1, "Size");
+    let stored_width = width(|x| &x.2, "Compressed");
+    let mut stdout = io::stdout().lock();
+    let writing = || "Failed to write the listing".to_string();
+    writeln!(
+        stdout,
+        "{:<10}  {:>size_width$}  {:>stored_width$}  Name",
+        "Mode", "Size", "Compressed"
+    )
+    .context(writing)?;
+    for (file, (mode, size, stored)) in files.iter().zip(&rows) {
+        writeln!(
+            stdout,
+            "{mode}  {size:>size_width$}  {stored:>stored_width$}  {}",
+            file.name
+        )
+        .context(writing)?;
+    }
+    stdout.flush().context(writing)?;
+    if opts.duplicates {
+        report_duplicates(&files, &hashes);
+    }
+    Ok(())
+}
+
+/// A file mode the way `ls -l` shows it, like `drwxr-xr-x`.
+fn symbolic_mode(mode: u32) -> String {
+    let kind = match mode & S_IFMT {
+        S_IFDIR => 'd',
+        S_IFLNK => 'l',
+        _ => '-',
+    };
+    let mut symbolic = String::from(kind);
+    // The setuid, setgid, and sticky bits show up in place of the execute bits
+    let special = [(S_ISUID, 's'), (S_ISGID, 's'), (S_ISVTX, 't')];
+    for (i, (bit, letter)) in special.into_iter().enumerate() {
+        let permissions = mode >> (6 - 3 * i);
+        symbolic.push(if permissions & 4 != 0 { 'r' } else { '-' });
+        symbolic.push(if permissions & 2 != 0 { 'w' } else { '-' });
+        symbolic.push(match (permissions & 1 != 0, mode & bit != 0) {
+            (true, true) => letter,
+            (false, true) => letter.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    symbolic
+}
+
+/// A number of bytes in the biggest binary unit that leaves at least 1 of them, like `1.5 MiB`.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// The files of an archive read by [`read_headers`].
+struct Listing {
+    header: ArchiveHeader,
+    files: Vec<FileHeaderRepr>,
+    /// The content hash of every file, with `-duplicates`
+    hashes: Vec<Option<[u8; 32]>>,
+}
+
+/// Reads the headers of every file in the archive at `-input`, for listing them. With
+/// `-duplicates`, their data is read and hashed too.
+fn read_headers(opts: &mut Opts) -> Result<Listing, MarkError> {
+    let mut input = open_archive(opts)?;
+
+    let mut files = vec![];
+    // The content hash of every file, if duplicates are being looked for
+    let mut hashes = vec![];
+
+    let mut archive = input
+        .reader()
+        .context(|| "Failed to read the archive header".into())?;
+    let file_count = match archive.header.is_streamed() {
+        true => String::new(),
+        false => format!(" of {}", archive.header.file_count),
+    };
+    // With an index, the headers are read without reading through the data between them, unless
+    // all of the archive has to be checked
+    let index = match opts.strict {
+        true => None,
+        false => archive
+            .index()
+            .context(|| "Failed to read the archive's index".into())?,
+    };
+    let mut jumps = index.map(|index| index.entries.into_iter().enumerate());
+    let options = ReadOptions {
+        skip_data: !opts.duplicates,
+        require_utf8_names: opts.require_utf8_names,
+        max_data_len: opts.max_memory,
+        max_member_size: opts.max_member_size,
+        only: None,
+    };
+    // Encrypted files can only be hashed with the passphrase
+    let unlocked = match opts.duplicates {
+        true => unlock(opts, archive.header, false)?,
+        false => false,
+    };
+    opts.dictionary = archive.dictionary.clone();
+    loop {
+        let file = match &mut jumps {
+            Some(jumps) => archive
+                .next_indexed_header(jumps, options)
+                .and_then(|file| {
+                    file.map(|file| archive.with_data(file, options))
+                        .transpose()
+                }),
+            None => archive.next_file(options),
+        };
+        let file =
+            file.context(|| format!("Failed to read file {}{file_count}", archive.read.max(1)))?;
+        let Some(mut file) = file else {
+            break;
+        };
+        if opts.duplicates {
+            if !file.checksum_matches(archive.layout) {
+                eprintln!("Warning: the checksum of \"{}\" doesn't match", file.name);
+            }
+            let hash = match file.inner.link() {
+                Some(target) => hashes.get(target).copied().flatten(),
+                None if file.inner.is_encrypted() && !unlocked => None,
+                None => hash_contents(opts, &file, &file.data[..])
+                    .context(|| format!("Failed to decompress \"{}\"", file.name))?,
+            };
+            hashes.push(hash);
+            file.data = vec![];
+        }
+        files.push(file);
+    }
+    if opts.strict {
+        check_rest(&mut archive)?;
+    }
+
+    Ok(Listing {
+        header: archive.header,
+        files,
+        hashes,
+    })
+}
+
+/// Prints the archive's files to stdout as JSON, like:
+///
+/// ```text
+/// {"version":4,"file_count":1,"files":[{"name":"x","mode":33188,"data_uncompressed_len":12,
+/// "data_len":16,"data_compression":"brotli"}]}
+/// ```
+///
+/// `data_compression` is `null` for compression methods this version doesn't know. Files sharing
+/// the data of an earlier one (see `-dedup`) have a `"link"` with its 1-based index, and
+/// `"hard_link":true` if they're a hard link to it. Files whose data is encrypted (see
+/// `-passphrase`) have `"encrypted":true`, files compressed in blocks (see `-block-size`) have
+/// `"blocks":true`, and sparse files (see `-sparse`) have a `"sparse_size"` with their length,
+/// holes included.
+fn print_json(header: ArchiveHeader, files: &[FileHeaderRepr]) {
+    let files = files
+        .iter()
+        .map(|file| {
+            let compression = file.inner.compression().map_or("null".to_string(), |x| {
+                json_string(&format!("{x:?}").to_lowercase())
+            });
+            let link = match (file.inner.link, file.inner.hard_link) {
+                (0, _) => String::new(),
+                (link, false) => format!(",\"link\":{link}"),
+                (link, true) => format!(",\"link\":{link},\"hard_link\":true"),
+            };
+            let encrypted = match file.inner.is_encrypted() {
+                true => ",\"encrypted\":true",
+                false => "",
+            };
+            let blocks = match file.inner.has_blocks() {
+                true => ",\"blocks\":true",
+                false => "",
+            };
+            let sparse = match &file.sparse {
+                Some(sparse) => format!(",\"sparse_size\":{}", sparse.size),
+                None => String::new(),
+            };
+            format!(
+                "{{\"name\":{},\"mode\":{},\"data_uncompressed_len\":{},\"data_len\":{},\"data_compression\":{compression}{link}{encrypted}{blocks}{sparse}}}",
+                json_string(&file.name),
+                file.inner.mode,
+                file.inner.uncompressed_len,
+                file.inner.len
+            )
+        })
+        .collect::<Vec<_>>();
+    println!(
+        "{{\"version\":{},\"file_count\":{},\"files\":[{}]}}",
+        header.version,
+        files.len(),
+        files.join(",")
+    );
+}
+
+/// Hashes the decompressed contents of `file`. Symlinks and directories don't get a hash since
+/// their data isn't file contents.
+/// `data` is the file's data as it's stored.
+fn hash_contents(
+    opts: &Opts,
+    file: &FileHeaderRepr,
+    data: impl Read,
+) -> io::Result<Option<[u8; 32]>> {
+    use sha2::Digest;
+
+    if file.inner.is_symlink() || file.inner.is_dir() {
+        return Ok(None);
+    }
+    let mut hasher = sha2::Sha256::new();
+    let data = decrypted(opts, file, data)?;
+    let data = decompressor(opts, &file.inner, data)?;
+    match &file.sparse {
+        Some(sparse) => io::copy(&mut Unsparse::new(data, sparse), &mut hasher)?,
+        None => io::copy(&mut { data }, &mut hasher)?,
+    };
+    Ok(Some(hasher.finalize().into()))
+}
+
+/// Prints groups of files with identical contents, and how much smaller the archive would be if
+/// each group's contents were only stored once.
+fn report_duplicates(files: &[FileHeaderRepr], hashes: &[Option<[u8; 32]>]) {
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut group_of_hash = std::collections::HashMap::new();
+    for (i, hash) in hashes.iter().enumerate() {
+        let Some(hash) = hash else {
+            continue;
+        };
+        let group = *group_of_hash.entry(hash).or_insert_with(|| {
+            groups.push(vec![]);
+            groups.len() - 1
+        });
+        groups[group].push(i);
+    }
+
+    let mut savings = 0u64;
+    let mut duplicate_groups = 0usize;
+    for group in groups.iter().filter(|group| group.len() > 1) {
+        duplicate_groups += 1;
+        let names = group
+            .iter()
+            .map(|&i| format!("\"{}\"", files[i].name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        eprintln!("Identical contents: {names}");
+        savings += group[1..].iter().map(|&i| files[i].inner.len).sum::<u64>();
+    }
+    eprintln!(
+        "Found {duplicate_groups} group(s) of duplicates; storing each only once would save {savings} bytes"
+    );
+}
+
+/// Writes the contents of the file named in `names` to stdout.
+fn cat(mut opts: Opts, names: &[String]) -> Result<(), MarkError> {
+    let [name] = names else {
+        return Err(MarkError::Usage(
+            "Expected the name of one file to print!".into(),
+        ));
+    };
+    let mut input = open_archive(&opts)?;
+    let mut archive = input
+        .reader()
+        .context(|| "Failed to read the archive header".into())?;
+    unlock(&mut opts, archive.header, true)?;
+    opts.dictionary = archive.dictionary.clone();
+    let reading = || "Failed to read a file from the archive".to_string();
+    let options = ReadOptions {
+        require_utf8_names: opts.require_utf8_names,
+        max_data_len: opts.max_memory,
+        max_member_size: opts.max_member_size,
+        ..Default::default()
+    };
+    // Where the first file starts, to go back to for the file whose data a link shares
+    let start = archive.position().context(reading)?;
+    // With an index, the file is found without reading through the others
+    let index = archive.index().context(reading)?;
+    let file = match &index {
+        Some(index) => {
+            match index
+                .entries
+                .iter()
+                .position(|x| String::from_utf8_lossy(&x.name) == *name)
+            {
+                Some(i) => {
+                    archive
+                        .seek_to(index.entries[i].offset, i as u32)
+                        .context(reading)?;
+                    archive.next_header(options).context(reading)?
+                }
+                None => None,
+            }
+        }
+        None => loop {
+            match archive.next_header(options).context(reading)? {
+                Some(file) if file.name == *name => break Some(file),
+                Some(_) => {}
+                None => break None,
+            }
+        },
+    };
+    let Some(mut file) = file else {
+        return Err(MarkError::Usage(format!(
+            "The archive doesn't contain \"{name}\""
+        )));
+    };
+    if file.inner.is_dir() {
+        return Err(MarkError::Usage(format!("\"{name}\" is a directory")));
+    }
+    if file.inner.is_symlink() {
+        return Err(MarkError::Usage(format!("\"{name}\" is a symlink")));
+    }
+    // Files sharing the data of an earlier one have none of their own
+    if let Some(target) = file.inner.link() {
+        let Some(start) = start else {
+            return Err(MarkError::Usage(format!(
+                "\"{name}\" shares its data with an earlier file, which can't be gone back to in an archive read from stdin or with -decode"
+            )));
+        };
+        file = archive.go_back_to(target, index.as_ref(), start, name, options)?;
+    }
+
+    let reading = || format!("Failed to decompress \"{}\"", file.name);
+    let mut stdout = io::stdout().lock();
+    let result = decrypted(&opts, &file, archive.data())
+        .and_then(|data| decompressor(&opts, &file.inner, data))
+        .and_then(|data| match &file.sparse {
+            Some(sparse) => io::copy(&mut Unsparse::new(data, sparse), &mut stdout),
+            None => io::copy(&mut { data }, &mut stdout),
+        })
+        .and_then(|_| stdout.flush());
+    match result {
+        // Whatever the output is piped to has all it wants
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+        result => result.context(reading)?,
+    }
+    if !archive.finish_data(&file).context(reading)? {
+        return Err(MarkError::Corrupt(format!(
+            "The checksum of \"{}\" doesn't match, so what was printed is corrupt",
+            file.name
+        )));
+    }
+    Ok(())
+}
+
+/// Writes the manifest of the archive at `-input` to `-output`, see [`write_manifest`].
+fn manifest(mut opts: Opts) -> Result<(), MarkError> {
+    let mut input = open_input(&opts)?;
+    let writing = || "Failed to write the manifest".to_string();
+    let output: &mut dyn Write = match &opts.output {
+        Some(output) => &mut BufWriter::new(
+            std::fs::File::create(output).context(|| format!("Failed to create \"{output}\""))?,
+        ),
+        None => &mut BufWriter::new(io::stdout().lock()),
+    };
+    write_manifest(&mut opts, &mut *input, output)?;
+    output.flush().context(writing)
+}
+
+/// Writes a line for each regular file in the archive read from `input` like `sha256sum` does,
+/// with the SHA-256 of the file's contents followed by two spaces and its name, so that unpacked
+/// files can be checked with `sha256sum -c`.
+fn write_manifest(
+    opts: &mut Opts,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<(), MarkError> {
+    let mut archive = ArchiveReader::new(input)
+        .map(reporting_warnings)
+        .context(|| "Failed to read the archive header".into())?;
+    let file_count = match archive.header.is_streamed() {
+        true => String::new(),
+        false => format!(" of {}", archive.header.file_count),
+    };
+    unlock(opts, archive.header, true)?;
+    opts.dictionary = archive.dictionary.clone();
+    let options = ReadOptions {
+        require_utf8_names: opts.require_utf8_names,
+        max_member_size: opts.max_member_size,
+        ..Default::default()
+    };
+    let writing = || "Failed to write the manifest".to_string();
+    // The hash of every file so far, for the ones sharing the data of an earlier one
+    let mut hashes = vec![];
+    while let Some(file) = archive
+        .next_header(options)
+        .context(|| format!("Failed to read file {}{file_count}", archive.read.max(1)))?
+    {
+        let hash = match file.inner.link() {
+            Some(target) => *hashes.get(target).ok_or_else(|| {
+                MarkError::Invalid(format!(
+                    "\"{}\" shares its data with a file that doesn't come before it",
+                    file.name
+                ))
+            })?,
+            None => {
+                let reading = || format!("Failed to decompress \"{}\"", file.name);
+                let hash = hash_contents(opts, &file, archive.data()).context(reading)?;
+                if !archive.finish_data(&file).context(reading)? {
+                    return Err(MarkError::Corrupt(format!(
+                        "The checksum of \"{}\" doesn't match",
+                        file.name
+                    )));
+                }
+                hash
+            }
+        };
+        hashes.push(hash);
+        let Some(hash) = hash else {
+            continue;
+        };
+        // sha256sum escapes names that would break up the line, and marks them with a backslash
+        // in front of the hash
+        let name = file.name_bytes();
+        let escape = name.iter().any(|b| matches!(b, b'\\' | b'\n' | b'\r'));
+        let mut line = match escape {
+            true => b"\\".to_vec(),
+            false => vec![],
+        };
+        for b in hash {
+            line.extend_from_slice(format!("{b:02x}").as_bytes());
+        }
+        line.extend_from_slice(b"  ");
+        for &b in name {
+            match b {
+                b'\\' => line.extend_from_slice(b"\\\\"),
+                b'\n' => line.extend_from_slice(b"\\n"),
+                b'\r' => line.extend_from_slice(b"\\r"),
+                b => line.push(b),
+            }
+        }
+        line.push(b'\n');
+        output.write_all(&line).context(writing)?;
+    }
+    Ok(())
+}
+
+/// Copies the archive at `-input` to `-output`, leaving out the files named in `names`.
+fn remove(opts: Opts, names: &[String]) -> Result<(), MarkError> {
+    if names.is_empty() {
+        return Err(MarkError::Usage(
+            "Expected the names of one or more files to remove!".into(),
+        ));
+    }
+    if let (Some(input), Some(output)) = (&opts.input, &opts.output) {
+        let same_file = match (std::fs::metadata(input), std::fs::metadata(output)) {
+            (Ok(input), Ok(output)) => {
+                let inode = |x| platform::identity(x).map(|(inode, _)| inode);
+                inode(&input).is_some() && inode(&input) == inode(&output)
+            }
+            _ => false,
+        };
+        if same_file {
+            return Err(MarkError::Usage(
+                "remove writes a new archive, the -output can't be the -input".into(),
+            ));
+        }
+    }
+
+    let input = &mut *open_input(&opts)?;
+    let mut archive = ArchiveReader::new(input)
+        .map(reporting_warnings)
+        .context(|| "Failed to read the archive header".into())?;
+    let (header, layout) = (archive.header, archive.layout);
+    let output: &mut dyn ArchiveOutput = match opts.output.as_deref() {
+        Some(output) => &mut OutputFile::create(output)?,
+        None => &mut BufWriter::new(std::io::stdout().lock()),
+    };
+    // The file count is filled in once it's known, if the output allows going back to it
+    let fill_in_count = !header.is_streamed() && output.seekable().is_some();
+    if !header.is_streamed() && !fill_in_count {
+        if header.version < END_OF_ARCHIVE_VERSION {
+            return Err(MarkError::Usage(format!(
+                "A version {} archive needs its file count up front, so files can only be removed into an -output file",
+                header.version
+            )));
+        }
+        eprintln!(
+            "Writing an archive without a file count, the output can't be seeked in to fill it in"
+        );
+    }
+    let writing = || "Failed to write the archive".to_string();
+    let file_count = if fill_in_count {
+        header.file_count
+    } else {
+        UNKNOWN_FILE_COUNT
+    };
+    // Digesting the new archive would take reading all of it back
+    if header.has_digest() {
+        eprintln!("Warning: the new archive has no digest, pack it again with -digest for one");
+    }
+    let header = ArchiveHeader {
+        flags: header.flags & !DIGEST,
+        ..header
+    };
+    ArchiveHeader {
+        file_count,
+        ..header
+    }
+    .write(output)
+    .context(writing)?;
+    let mut position = header.size();
+    if let Some(dictionary) = &archive.dictionary {
+        position += write_dictionary(output, dictionary).context(writing)?;
+    }
+
+    let options = ReadOptions {
+        require_utf8_names: opts.require_utf8_names,
+        max_member_size: opts.max_member_size,
+        ..Default::default()
+    };
+    let mut removed = vec![false; names.len()];
+    let mut kept = 0u32;
+    // An index is written again without the removed files
+    let mut index = header.is_indexed().then(ArchiveIndex::default);
+    // The new index of every file read so far, or 0 for removed ones, for the files sharing their
+    // data
+    let mut new_indices = vec![];
+    while let Some(mut file) = archive
+        .next_header(options)
+        .context(|| "Failed to read a file from the archive".into())?
+    {
+        // The data of removed files is skipped over by the next call
+        if let Some(i) = names.iter().position(|x| *x == file.name) {
+            removed[i] = true;
+            new_indices.push(0);
+            continue;
+        }
+        if let Some(target) = file.inner.link() {
+            file.inner.link = new_indices.get(target).copied().unwrap_or(0);
+            if file.inner.link == 0 {
+                if let Some(output) = &opts.output {
+                    let _ = std::fs::remove_file(output);
+                }
+                return Err(MarkError::Usage(format!(
+                    "\"{}\" shares the data of a file being removed, remove it too",
+                    file.name
+                )));
+            }
+        }
+        let reading = || format!("Failed to read \"{}\" from the archive", file.name);
+        let mut counter = CountingWriter::new(&mut *output);
+        file.write_header_only(&mut counter, layout)
+            .context(writing)?;
+        if file.inner.is_framed() {
+            // The frames are written again as they were, CRC32 and all, and the index gets the
+            // length of the data in them
+            let (len, _) = write_frames(&mut archive.data(), &mut counter).context(reading)?;
+            let crc = archive.frames_crc.unwrap_or_default();
+            counter.write_all(&crc.to_le_bytes()).context(writing)?;
+            file.inner.len = len;
+        } else {
+            io::copy(&mut archive.data(), &mut counter).context(reading)?;
+        }
+        if let Some(index) = &mut index {
+            index.push(&file, position);
+        }
+        position += counter.count;
+        if !archive.finish_data(&file).context(reading)? {
+            eprintln!(
+                "Warning: \"{}\" was copied, but its checksum doesn't match",
+                file.name
+            );
+        }
+        kept += 1;
+        new_indices.push(kept);
+    }
+    if header.version >= END_OF_ARCHIVE_VERSION {
+        output.write_all(&END_OF_ARCHIVE).context(writing)?;
+    }
+    if let Some(index) = index {
+        index
+            .write(output, position + END_OF_ARCHIVE.len() as u64)
+            .context(writing)?;
+    }
+    if let Some(output) = output.seekable().filter(|_| fill_in_count) {
+        output.seek(io::SeekFrom::Start(0)).context(writing)?;
+        ArchiveHeader {
+            file_count: kept,
+            ..header
+        }
+        .write(output)
+        .context(writing)?;
+    }
+    output.close().context(writing)?;
+
+    let missing = names
+        .iter()
+        .zip(&removed)
+        .filter(|(_, removed)| !**removed)
+        .map(|(name, _)| format!("\"{name}\""))
+        .collect::<Vec<_>>();
+    if missing.len() == names.len() {
+        // A copy with nothing removed isn't what was asked for
+        if let Some(output) = &opts.output {
+            let _ = std::fs::remove_file(output);
+        }
+        return Err(MarkError::Usage(format!(
+            "The archive doesn't contain {}, so nothing was removed",
+            missing.join(", ")
+        )));
+    }
+    if !missing.is_empty() {
+        eprintln!(
+            "Warning: the archive doesn't contain {}",
+            missing.join(", ")
+        );
+    }
+    if !opts.quiet {
+        eprintln!(
+            "Removed {} file(s), kept {kept}",
+            names.len() - missing.len()
+        );
+    }
+    Ok(())
+}
+
+/// Writes the files in the archive as a tar archive, with their names, permissions, owners, and
+/// modification times. Files sharing the data of an earlier one (see `-dedup`) become hard links
+/// to it, since tar has no other way of storing data once.
+fn export_tar(mut opts: Opts) -> Result<(), MarkError> {
+    let mut input = open_archive(&opts)?;
+    let mut archive = input
+        .reader()
+        .context(|| "Failed to read the archive header".into())?;
+    unlock(&mut opts, archive.header, true)?;
+    opts.dictionary = archive.dictionary.clone();
+    // Older archives don't know who owned their files
+    let has_owner = archive.layout.fields.contains(&Field::Uid);
+    let output: Box<dyn Write> = match opts.output.as_deref() {
+        Some(output) => Box::new(BufWriter::new(
+            std::fs::File::create(output).context(|| format!("Failed to create \"{output}\""))?,
+        )),
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    let mut tar = tar::Builder::new(output);
+    let writing = || "Failed to write the tar archive".to_string();
+    let options = ReadOptions {
+        require_utf8_names: opts.require_utf8_names,
+        max_data_len: opts.max_memory,
+        max_member_size: opts.max_member_size,
+        ..Default::default()
+    };
+    // The name each file was exported with, by its index in the archive, for the files that
+    // share its data
+    let mut exported = std::collections::HashMap::new();
+    let mut skipped = 0usize;
+    let result = (|| {
+        while let Some(mut file) = archive
+            .next_header(options)
+            .context(|| "Failed to read a file from the archive".into())?
+        {
+            let reading = || format!("Failed to read \"{}\" from the archive", file.name);
+            let Some(name) = sanitize_member_name(file.path()) else {
+                eprintln!(
+                    "Not exporting \"{}\", its name is absolute or goes up out of the archive",
+                    file.name
+                );
+                skipped += 1;
+                continue;
+            };
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(file.inner.mode & 0o7777);
+            header.set_mtime(file.inner.modified);
+            if has_owner {
+                header.set_uid(file.inner.uid.into());
+                header.set_gid(file.inner.gid.into());
+            }
+            file.inner.compression().context(reading)?;
+
+            if file.inner.is_dir() {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                tar.append_data(&mut header, &name, io::empty())
+                    .context(writing)?;
+            } else if file.inner.is_symlink() {
+                file.data = FileHeaderRepr::read_data(
+                    &mut archive.data(),
+                    &file.inner,
+                    &file.name,
+                    options,
+                )
+                .context(reading)?;
+                let mut target = vec![];
+                decrypted(&opts, &file, &file.data[..])
+                    .and_then(|mut data| {
+                        decompress_from(&opts, &file.inner, &mut data, &mut target)
+                    })
+                    .context(reading)?;
+                let target = std::path::PathBuf::from(platform::os_string(target));
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                tar.append_link(&mut header, &name, target)
+                    .context(writing)?;
+            } else if let Some(target) = file.inner.link() {
+                let Some(target) = exported.get(&target) else {
+                    eprintln!(
+                        "Not exporting \"{}\", it shares its data with a file that wasn't exported",
+                        file.name
+                    );
+                    skipped += 1;
+                    continue;
+                };
+                header.set_entry_type(tar::EntryType::Link);
+                header.set_size(0);
+                tar.append_link(&mut header, &name, target)
+                    .context(writing)?;
+            } else {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(file.size());
+                let data = decrypted(&opts, &file, archive.data())
+                    .and_then(|data| decompressor(&opts, &file.inner, data))
+                    .context(reading)?;
+                let data = ExactLen::new(data, file.inner.uncompressed_len);
+                // tar has sparse files of its own, but they aren't worth the trouble here
+                match &file.sparse {
+                    Some(sparse) => {
+                        tar.append_data(&mut header, &name, Unsparse::new(data, sparse))
+                    }
+                    None => tar.append_data(&mut header, &name, data),
+                }
+                .context(reading)?;
+            }
+            if !archive.finish_data(&file).context(reading)? {
+                return Err(MarkError::Corrupt(format!(
+                    "The checksum of \"{}\" doesn't match",
+                    file.name
+                )));
+            }
+            exported.insert(archive.read as usize - 1, name);
+        }
+        tar.finish().context(writing)?;
+        tar.get_mut().flush().context(writing)
+    })();
+    if result.is_err() {
+        // Don't leave a tar archive behind with only some of the files in it
+        if let Some(output) = &opts.output {
+            let _ = std::fs::remove_file(output);
+        }
+        return result;
+    }
+    if !opts.quiet {
+        eprintln!(
+            "Exported {} file(s) to tar, skipped {skipped}",
+            exported.len()
+        );
+    }
+    Ok(())
+}
+
+/// Converts a tar archive to an archive, compressing its regular files like `pack` would. Its
+/// directories, symlinks, and hard links are kept too, and anything else is skipped with a
+/// warning.
+fn import_tar(mut opts: Opts) -> Result<(), MarkError> {
+    check_levels(&opts)?;
+    #[cfg(feature = "encryption")]
+    if opts.passphrase.is_some() {
+        return Err(MarkError::Usage(
+            "import-tar can't encrypt the files it imports, unpack the tar archive and pack it instead".into(),
+        ));
+    }
+    load_dictionary(&mut opts, None)?;
+    let input: Box<dyn Read> = match opts.input.as_deref() {
+        Some(input) => Box::new(BufReader::new(
+            std::fs::File::open(input).context(|| format!("Failed to open \"{input}\""))?,
+        )),
+        None => Box::new(BufReader::new(std::io::stdin().lock())),
+    };
+    let mut tar = tar::Archive::new(input);
+    let output: &mut dyn ArchiveOutput = match opts.output.as_deref() {
+        Some(output) => &mut OutputFile::create(output)?,
+        None => &mut BufWriter::new(std::io::stdout().lock()),
+    };
+    // How many files there are is only known at the end, when it's filled in if the output
+    // allows going back to it
+    let header = ArchiveHeader {
+        version: CURRENT_VERSION,
+        file_count: UNKNOWN_FILE_COUNT,
+        flags: archive_flags(&opts),
+        encryption: None,
+    };
+    let writing = || "Failed to write the archive".to_string();
+    let mut archive =
+        RawArchiveWriter::with_header(&mut *output, header, opts.dictionary.as_deref())
+            .context(writing)?;
+    let reading = || "Failed to read the tar archive".to_string();
+    // The index in the archive and header of each file imported so far, by name, for the hard
+    // links to them
+    let mut imported: std::collections::HashMap<Vec<u8>, (u32, FileHeader)> =
+        std::collections::HashMap::new();
+    let mut skipped = 0usize;
+    let result = (|| {
+        for entry in tar.entries().context(reading)? {
+            let mut entry = entry.context(reading)?;
+            let path = entry.path_bytes().into_owned();
+            // Names like `./a` and `a/` are stored as `a`
+            let mut name = &path[..];
+            while let Some(rest) = name.strip_prefix(b"./") {
+                name = rest;
+            }
+            while let Some(rest) = name.strip_suffix(b"/") {
+                name = rest;
+            }
+            let name = name.to_vec();
+            let display = String::from_utf8_lossy(&name).into_owned();
+            if name.is_empty() || name == b"." {
+                continue;
+            }
+            let reading = || format!("Failed to read \"{display}\" from the tar archive");
+            let tar_header = entry.header();
+            let permissions = tar_header.mode().context(reading)? & 0o7777;
+            let mut header = FileHeader {
+                modified: tar_header.mtime().context(reading)?,
+                uid: tar_header.uid().context(reading)?.try_into().unwrap_or(0),
+                gid: tar_header.gid().context(reading)?.try_into().unwrap_or(0),
+                ..Default::default()
+            };
+            header.accessed = header.modified;
+            // The file whose data a hard link shares
+            let mut shared = None;
+            let data = match tar_header.entry_type() {
+                tar::EntryType::Directory => {
+                    header.mode = S_IFDIR | permissions;
+                    vec![]
+                }
+                tar::EntryType::Symlink => {
+                    let Some(target) = entry.link_name_bytes() else {
+                        eprintln!("Warning: skipping \"{display}\", a symlink without a target");
+                        skipped += 1;
+                        continue;
+                    };
+                    header.mode = S_IFLNK | permissions;
+                    header.uncompressed_len = target.len() as u64;
+                    target.into_owned()
+                }
+                tar::EntryType::Link => {
+                    let target = entry
+                        .link_name_bytes()
+                        .map(|x| x.into_owned())
+                        .unwrap_or_default();
+                    let target = target.strip_prefix(b"./").unwrap_or(&target);
+                    let Some(&(index, target)) = imported.get(target) else {
+                        eprintln!(
+                            "Warning: skipping \"{display}\", a hard link to \"{}\", which isn't imported before it",
+                            String::from_utf8_lossy(target)
+                        );
+                        skipped += 1;
+                        continue;
+                    };
+                    shared = Some((index, target));
+                    header.mode = S_IFREG | permissions;
+                    header.link = index;
+                    header.hard_link = true;
+                    header.data_compression = target.data_compression;
+                    header.uncompressed_len = target.uncompressed_len;
+                    vec![]
+                }
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    header.mode = S_IFREG | permissions;
+                    header.uncompressed_len = entry.size();
+                    let needed = entry.size().saturating_mul(2);
+                    if let Some(max) = opts.max_memory.filter(|&max| needed > max) {
+                        return Err(MarkError::Usage(format!(
+                            "Importing \"{display}\" needs {needed} bytes of memory, more than the -max-memory limit of {max}"
+                        )));
+                    }
+                    let mut contents = vec![];
+                    entry.read_to_end(&mut contents).context(reading)?;
+                    let path = std::path::PathBuf::from(platform::os_string(name.clone()));
+                    let (compression, data) =
+                        compress_contents(&opts, &path, contents).context(reading)?;
+                    header.data_compression = compression as u8;
+                    data
+                }
+                other => {
+                    eprintln!("Warning: skipping \"{display}\", tar entries of type {other:?} aren't supported");
+                    skipped += 1;
+                    continue;
+                }
+            };
+            header.len = data.len() as u64;
+            let file = FileHeaderRepr::new(header, name.clone(), data);
+            archive.add(&file).context(writing)?;
+            if file.inner.is_symlink() || file.inner.is_dir() {
+                continue;
+            }
+            // A hard link to a hard link shares the data of the file the first one links to
+            imported.insert(name, shared.unwrap_or((archive.files, file.inner)));
+        }
+        let files = archive.files;
+        let output = archive.finish().context(writing)?;
+        if let Some(output) = output.seekable() {
+            output.seek(io::SeekFrom::Start(0)).context(writing)?;
+            ArchiveHeader {
+                file_count: files,
+                ..header
+            }
+            .write(output)
+            .context(writing)?;
+        }
+        output.close().context(writing)?;
+        Ok(files)
+    })();
+    let files = match result {
+        Ok(files) => files,
+        Err(e) => {
+            // Don't leave an archive behind with only some of the files in it
+            if let Some(output) = &opts.output {
+                let _ = std::fs::remove_file(output);
+            }
+            return Err(e);
+        }
+    };
+    if !opts.quiet {
+        eprintln!("Imported {files} file(s) from tar, skipped {skipped}");
+    }
+    Ok(())
+}
+
+/// Decompresses file `data` stored with `compression`, writing the result to `output`.
+fn decompress(
+    opts: &Opts,
+    file: &FileHeader,
+    data: &[u8],
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    decompress_from(opts, file, &mut &data[..], output)
+}
+
+/// Like [`decompress`], but reads the data from `data` as it goes.
+fn decompress_from(
+    opts: &Opts,
+    file: &FileHeader,
+    data: &mut dyn Read,
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    let compression = file.compression()?;
+    if file.has_blocks() {
+        let mut blocks = BlockDecompressor::new(opts, compression, data);
+        return io::copy(&mut blocks, output).map(|_| ());
+    }
+    decompress_with(opts, compression, data, output)
+}
+
+/// Decompresses `data` compressed with `compression` into `output`, as a single block.
+fn decompress_with(
+    opts: &Opts,
+    compression: DataCompression,
+    data: &mut dyn Read,
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    match compression {
+        DataCompression::None => io::copy(data, output).map(|_| ()),
+        DataCompression::Brotli => {
+            let mut x = brotli::DecompressorWriter::new(output, opts.buffer_size());
+            io::copy(data, &mut x)?;
+            x.into_inner()
+                .map(|_| ())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "truncated brotli data"))
+        }
+        DataCompression::Zstd => match &opts.dictionary {
+            Some(dictionary) => io::copy(
+                &mut zstd::stream::read::Decoder::with_dictionary(
+                    BufReader::new(data),
+                    dictionary,
+                )?,
+                output,
+            )
+            .map(|_| ()),
+            None => io::copy(&mut zstd::stream::read::Decoder::new(data)?, output).map(|_| ()),
+        },
+        DataCompression::Gzip => {
+            io::copy(&mut flate2::read::GzDecoder::new(data), output).map(|_| ())
+        }
+        DataCompression::Xz => io::copy(&mut xz2::read::XzDecoder::new(data), output).map(|_| ()),
+    }
+}
+
+/// Like [`decompress_from`], but decompresses the data as it's read from the returned reader.
+fn decompressor<'a>(
+    opts: &'a Opts,
+    file: &FileHeader,
+    data: Box<dyn Read + 'a>,
+) -> io::Result<Box<dyn Read + 'a>> {
+    let compression = file.compression()?;
+    if file.has_blocks() {
+        return Ok(Box::new(BlockDecompressor::new(opts, compression, data)));
+    }
+    Ok(match compression {
+        DataCompression::None => data,
+        DataCompression::Brotli => Box::new(brotli::Decompressor::new(data, opts.buffer_size())),
+        DataCompression::Zstd => match &opts.dictionary {
+            Some(dictionary) => Box::new(zstd::stream::read::Decoder::with_dictionary(
+                BufReader::new(data),
+                dictionary,
+            )?),
+            None => Box::new(zstd::stream::read::Decoder::new(data)?),
+        },
+        DataCompression::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+        DataCompression::Xz => Box::new(xz2::read::XzDecoder::new(data)),
+    })
+}
+
+/// Walks `p`, calling `callback` with every file and directory inside of it and whether it's a
+/// directory. Only the directories it returns `true` for are walked into, and none more than
+/// `max_depth` levels below `p`. If `p` isn't a directory, it's the only thing `callback` is
+/// called with. With `dereference`, symlinks to directories count as directories.
+///
+/// The device and inode of every directory walked is kept track of, so that a directory
+/// reachable from inside itself (like through a bind mount) is only walked, and only given to
+/// `callback`, once.
+fn walk(
+    p: impl AsRef<std::path::Path>,
+    max_depth: Option<usize>,
+    dereference: bool,
+    callback: &mut dyn FnMut(bool, &std::path::Path) -> std::io::Result<bool>,
+) -> Result<(), std::io::Error> {
+    let p = p.as_ref();
+    let metadata = |path: &std::path::Path| match dereference {
+        true => std::fs::metadata(path),
+        false => std::fs::symlink_metadata(path),
+    };
+    // Symlinks are stored as links rather than followed unless `dereference`, so they don't count
+    // as directories otherwise
+    if !metadata(p)?.is_dir() {
+        // We don't want to ignore the first item if it's a file
+        callback(false, p)?;
+        return Ok(());
+    }
+    let inode = |path: &std::path::Path| {
+        metadata(path).map(|x| platform::identity(&x).map(|(inode, _)| inode))
+    };
+    let mut visited = std::collections::HashSet::new();
+    visited.extend(inode(p)?);
+    // The directories left to walk and how deep they are, kept here rather than on the call
+    // stack so that however deep the tree goes, it can't overflow
+    let mut pending = vec![(p.to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        // What's inside of the directories at the limit would be below it
+        if max_depth.is_some_and(|max| depth >= max) {
+            continue;
+        }
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() || dereference && file_type.is_symlink() && path.is_dir() {
+                // Left out entirely, rather than packed as an empty directory
+                let inode = inode(&path)?;
+                if inode.is_some_and(|inode| visited.contains(&inode)) {
+                    eprintln!(
+                        "Warning: skipping \"{}\", it leads back to a directory that was already walked",
+                        path.display()
+                    );
+                    continue;
+                }
+                if callback(true, &path)? {
+                    visited.extend(inode);
+                    pending.push((path, depth + 1));
+                }
+            } else {
+                callback(false, &path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A text encoding for moving archives through channels that mangle binary data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TextEncoding {
+    Base64,
+    Hex,
+}
+
+impl std::str::FromStr for TextEncoding {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "base64" => Self::Base64,
+            "hex" => Self::Hex,
+            _ => return Err("unsupported text encoding"),
+        })
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const HEX_ALPHABET: &[u8; 16] = b"0123456789abcdef";
+/// Encoded output is wrapped to lines of this many characters.
+const TEXT_LINE_WIDTH: usize = 76;
+
+/// Encodes everything written to it as text before passing it on to the inner writer.
+///
+/// [`TextEncoder::finish`] must be called once everything has been written, so that any trailing
+/// bytes get encoded.
+struct TextEncoder<W: Write> {
+    inner: W,
+    encoding: TextEncoding,
+    /// Bytes that don't form a whole base64 group yet.
+    pending: Vec<u8>,
+    column: usize,
+}
+
+impl<W: Write> TextEncoder<W> {
+    fn new(inner: W, encoding: TextEncoding) -> Self {
+        Self {
+            inner,
+            encoding,
+            pending: Vec::with_capacity(3),
+            column: 0,
+        }
+    }
+
+    fn push_text(&mut self, text: &[u8], out: &mut Vec<u8>) {
+        for &c in text {
+            out.push(c);
+            self.column += 1;
+            if self.column == TEXT_LINE_WIDTH {
+                out.push(b'\n');
+                self.column = 0;
+            }
+        }
+    }
+
+    fn encode_group(group: &[u8]) -> [u8; 4] {
+        let b = [
+            group[0],
+            group.get(1).copied().unwrap_or(0),
+            group.get(2).copied().unwrap_or(0),
+        ];
+        let mut text = [
+            BASE64_ALPHABET[(b[0] >> 2) as usize],
+            BASE64_ALPHABET[(((b[0] & 0b11) << 4) | (b[1] >> 4)) as usize],
+            BASE64_ALPHABET[(((b[1] & 0b1111) << 2) | (b[2] >> 6)) as usize],
+            BASE64_ALPHABET[(b[2] & 0b111111) as usize],
+        ];
+        if group.len() < 3 {
+            text[3] = b'=';
+        }
+        if group.len() < 2 {
+            text[2] = b'=';
+        }
+        text
+    }
+
+    /// Encodes any remaining bytes, terminates the last line, and returns the inner writer.
+    fn finish(mut self) -> io::Result<W> {
+        let mut out = vec![];
+        if !self.pending.is_empty() {
+            let text = Self::encode_group(&self.pending);
+            self.push_text(&text, &mut out);
+        }
+        if self.column != 0 {
+            out.push(b'\n');
+        }
+        self.inner.write_all(&out)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for TextEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut out = Vec::with_capacity(buf.len() * 2 + buf.len() / TEXT_LINE_WIDTH + 8);
+        match self.encoding {
+            TextEncoding::Hex => {
+                for &b in buf {
+                    let text = [
+                        HEX_ALPHABET[(b >> 4) as usize],
+                        HEX_ALPHABET[(b & 0xf) as usize],
+                    ];
+                    self.push_text(&text, &mut out);
+                }
+            }
+            TextEncoding::Base64 => {
+                let mut bytes = buf;
+                if !self.pending.is_empty() {
+                    let needed = (3 - self.pending.len()).min(bytes.len());
+                    self.pending.extend_from_slice(&bytes[..needed]);
+                    bytes = &bytes[needed..];
+                    if self.pending.len() == 3 {
+                        let text = Self::encode_group(&self.pending);
+                        self.pending.clear();
+                        self.push_text(&text, &mut out);
+                    }
+                }
+                let mut groups = bytes.chunks_exact(3);
+                for group in &mut groups {
+                    let text = Self::encode_group(group);
+                    self.push_text(&text, &mut out);
+                }
+                self.pending.extend_from_slice(groups.remainder());
+            }
+        }
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decodes text produced by a [`TextEncoder`] back into bytes. Whitespace is ignored.
+struct TextDecoder<R: Read> {
+    inner: R,
+    encoding: TextEncoding,
+    /// Encoded characters that don't form a whole group yet.
+    group: Vec<u8>,
+    /// Decoded bytes not yet handed out, and how many of them already have been.
+    decoded: Vec<u8>,
+    decoded_pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> TextDecoder<R> {
+    fn new(inner: R, encoding: TextEncoding) -> Self {
+        Self {
+            inner,
+            encoding,
+            group: Vec::with_capacity(4),
+            decoded: vec![],
+            decoded_pos: 0,
+            finished: false,
+        }
+    }
+
+    fn invalid(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+    }
+
+    fn decode_char(&self, c: u8) -> io::Result<u8> {
+        let alphabet: &[u8] = match self.encoding {
+            TextEncoding::Base64 => BASE64_ALPHABET,
+            TextEncoding::Hex => HEX_ALPHABET,
+        };
+        let c = match self.encoding {
+            TextEncoding::Base64 => c,
+            TextEncoding::Hex => c.to_ascii_lowercase(),
+        };
+        alphabet
+            .iter()
+            .position(|&x| x == c)
+            .map(|x| x as u8)
+            .ok_or_else(|| Self::invalid("invalid character in text-encoded archive"))
+    }
+
+    /// Decodes a complete group of characters into `self.decoded`.
+    fn decode_group(&mut self) -> io::Result<()> {
+        let group = std::mem::take(&mut self.group);
+        match self.encoding {
+            TextEncoding::Hex => {
+                let value = (self.decode_char(group[0])? << 4) | self.decode_char(group[1])?;
+                self.decoded.push(value);
+            }
+            TextEncoding::Base64 => {
+                let padding = group.iter().rev().take_while(|&&c| c == b'=').count();
+                if padding > 2 || group[..4 - padding].contains(&b'=') {
+                    return Err(Self::invalid("misplaced padding in base64 archive"));
+                }
+                let mut value = 0u32;
+                for &c in &group[..4 - padding] {
+                    value = (value << 6) | self.decode_char(c)? as u32;
+                }
+                value <<= 6 * padding as u32;
+                let bytes = value.to_be_bytes();
+                self.decoded.extend_from_slice(&bytes[1..4 - padding]);
+                if padding != 0 {
+                    self.finished = true;
+                }
+            }
+        }
+        self.group = group;
+        self.group.clear();
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for TextDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let group_len = match self.encoding {
+            TextEncoding::Base64 => 4,
+            TextEncoding::Hex => 2,
+        };
+        let mut text = [0u8; 4096];
+        while self.decoded_pos == self.decoded.len() {
+            self.decoded.clear();
+            self.decoded_pos = 0;
+            let n = self.inner.read(&mut text)?;
+            if n == 0 {
+                if !self.group.is_empty() {
+                    return Err(Self::invalid(
+                        "text-encoded archive ends in the middle of a group",
+                    ));
+                }
+                return Ok(0);
+            }
+            for &c in &text[..n] {
+                if c.is_ascii_whitespace() {
+                    continue;
+                }
+                if self.finished {
+                    return Err(Self::invalid("unexpected data after base64 padding"));
+                }
+                self.group.push(c);
+                if self.group.len() == group_len {
+                    self.decode_group()?;
+                }
+            }
+        }
+
+        let available = &self.decoded[self.decoded_pos..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.decoded_pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cli::parse_flags;
+    use super::*;
+
+    /// Keeps track of the largest allocation made, to tell whether anything was held in memory
+    /// as a whole.
+    struct Tracking;
+
+    static LARGEST_ALLOCATION: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl std::alloc::GlobalAlloc for Tracking {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            LARGEST_ALLOCATION.fetch_max(layout.size(), Ordering::Relaxed);
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(
+            &self,
+            ptr: *mut u8,
+            layout: std::alloc::Layout,
+            new_size: usize,
+        ) -> *mut u8 {
+            LARGEST_ALLOCATION.fetch_max(new_size, Ordering::Relaxed);
+            std::alloc::System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Tracking = Tracking;
+
+    /// An empty directory for one test to work in.
+    fn scratch(test: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("mark-{}-{test}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An archive holding `files`, written the way a pipe gets it.
+    fn archive_of(files: &[FileHeaderRepr]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let header = ArchiveHeader {
+            version: CURRENT_VERSION,
+            file_count: UNKNOWN_FILE_COUNT,
+            flags: 0,
+            encryption: None,
+        };
+        let mut writer = RawArchiveWriter::with_header(Unseekable(&mut buf), header, None).unwrap();
+        for file in files {
+            writer.add(file).unwrap();
+        }
+        writer.finish().unwrap();
+        buf
+    }
+
+    fn regular(name: &str, contents: &[u8]) -> FileHeaderRepr {
+        new_file(&Opts::default(), name, contents.to_vec()).unwrap()
+    }
+
+    fn directory(name: &str) -> FileHeaderRepr {
+        let mut file = regular(name, b"");
+        file.inner.mode = S_IFDIR | 0o755;
+        file
+    }
+
+    fn symlink(name: &str, target: &str) -> FileHeaderRepr {
+        let mut file = regular(name, target.as_bytes());
+        file.inner.mode = S_IFLNK | 0o777;
+        file
+    }
+
+    /// A file sharing the data of `target`, the `index`th file (counting from 0).
+    fn link(name: &str, index: u32, target: &FileHeaderRepr) -> FileHeaderRepr {
+        let mut file = regular(name, b"");
+        file.inner.link = index + 1;
+        file.inner.data_compression = target.inner.data_compression;
+        file.inner.uncompressed_len = target.inner.uncompressed_len;
+        file
+    }
+
+    /// Unpacks an archive of `files` into `output`, or only the ones in `names` if there are any.
+    fn unpack_into(
+        files: &[FileHeaderRepr],
+        output: &std::path::Path,
+        names: &[&str],
+    ) -> Result<(), MarkError> {
+        let archive = output.with_extension("mark");
+        std::fs::write(&archive, archive_of(files)).unwrap();
+        let opts = Opts {
+            input: Some(archive.to_string_lossy().into_owned()),
+            output: Some(output.to_string_lossy().into_owned()),
+            quiet: true,
+            ..Default::default()
+        };
+        let names = names.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        unpack(opts, &names)
+    }
+
+    /// Packs `paths` into `archive`, with the rest of `opts` as given.
+    fn pack_into(
+        archive: &std::path::Path,
+        opts: Opts,
+        paths: &[&std::path::Path],
+    ) -> Result<(), MarkError> {
+        let opts = Opts {
+            output: Some(archive.to_string_lossy().into_owned()),
+            quiet: true,
+            ..opts
+        };
+        let paths = paths
+            .iter()
+            .map(|x| x.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        pack(opts, &paths)
+    }
+
+    /// The names of the files in `archive`, in the order they're stored.
+    fn names_in(archive: &std::path::Path) -> Vec<String> {
+        let mut input = std::fs::File::open(archive).unwrap();
+        ArchiveReader::new(&mut input)
+            .unwrap()
+            .map(|x| x.unwrap().name)
+            .collect()
+    }
+
+    #[test]
+    fn text_encodings_round_trip() {
+        let dir = scratch("text-encodings");
+        let file = dir.join("file");
+        std::fs::write(&file, b"some contents to pack, ".repeat(100)).unwrap();
+        let plain = dir.join("plain.mark");
+        pack_into(&plain, Opts::default(), &[&file]).unwrap();
+        for encoding in [TextEncoding::Base64, TextEncoding::Hex] {
+            let encoded = dir.join("encoded.mark");
+            let opts = Opts {
+                encode: Some(encoding),
+                ..Default::default()
+            };
+            pack_into(&encoded, opts, &[&file]).unwrap();
+            let mut decoded = vec![];
+            TextDecoder::new(std::fs::File::open(&encoded).unwrap(), encoding)
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, std::fs::read(&plain).unwrap(), "{encoding:?}");
+        }
+    }
+
+    #[test]
+    fn missing_paths_are_reported() {
+        let dir = scratch("missing");
+        let file = dir.join("file");
+        std::fs::write(&file, b"here").unwrap();
+        let missing = dir.join("missing");
+        let archive = dir.join("missing.mark");
+        let error = pack_into(&archive, Opts::default(), &[&file, &missing]).unwrap_err();
+        assert!(matches!(&error, MarkError::Usage(message) if message.contains("does not exist")));
+
+        let opts = Opts {
+            ignore_missing: true,
+            ..Default::default()
+        };
+        pack_into(&archive, opts, &[&file, &missing]).unwrap();
+        assert_eq!(names_in(&archive), ["file"]);
+    }
+
+    #[test]
+    fn directories_with_a_marker_are_left_out() {
+        let dir = scratch("exclude-if-present");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("kept")).unwrap();
+        std::fs::create_dir_all(tree.join("cache/deeper")).unwrap();
+        std::fs::write(tree.join("kept/file"), b"kept").unwrap();
+        std::fs::write(tree.join("cache/.nobackup"), b"").unwrap();
+        std::fs::write(tree.join("cache/file"), b"left out").unwrap();
+        std::fs::write(tree.join("cache/deeper/file"), b"left out").unwrap();
+        let archive = dir.join("tree.mark");
+        let opts = Opts {
+            exclude_if_present: vec![".nobackup".into()],
+            include_dotfiles: true,
+            ..Default::default()
+        };
+        pack_into(&archive, opts, &[&tree]).unwrap();
+        assert_eq!(names_in(&archive), ["tree/kept/file"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_keep_relative_targets_and_refuse_external_ones() {
+        let dir = scratch("symlink-targets");
+        let files = [
+            regular("lib/x", b"x"),
+            symlink("bin/x", "../lib/x"),
+            symlink("absolute", "/etc/passwd"),
+            symlink("up", "../outside"),
+        ];
+        let output = dir.join("out");
+        unpack_into(&files, &output, &[]).unwrap();
+        assert_eq!(
+            std::fs::read_link(output.join("bin/x")).unwrap(),
+            std::path::Path::new("../lib/x")
+        );
+        assert_eq!(std::fs::read(output.join("bin/x")).unwrap(), b"x");
+        assert!(std::fs::symlink_metadata(output.join("absolute")).is_err());
+        assert!(std::fs::symlink_metadata(output.join("up")).is_err());
+
+        let archive = dir.join("allowed.mark");
+        std::fs::write(&archive, archive_of(&files)).unwrap();
+        let opts = Opts {
+            input: Some(archive.to_string_lossy().into_owned()),
+            output: Some(dir.join("allowed").to_string_lossy().into_owned()),
+            allow_external_symlinks: true,
+            quiet: true,
+            ..Default::default()
+        };
+        unpack(opts, &[]).unwrap();
+        assert_eq!(
+            std::fs::read_link(dir.join("allowed/absolute")).unwrap(),
+            std::path::Path::new("/etc/passwd")
+        );
+        assert_eq!(
+            std::fs::read_link(dir.join("allowed/up")).unwrap(),
+            std::path::Path::new("../outside")
+        );
+    }
+
+    #[test]
+    fn level_map_sets_levels_by_extension() {
+        let dir = scratch("level-map");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        // Text that repeats loosely, so more effort finds more of it
+        let mut state = 1u32;
+        let contents = (0..20000)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                ["alpha ", "beta ", "gamma ", "delta ", "epsilon\n"][(state >> 16) as usize % 5]
+            })
+            .collect::<String>();
+        std::fs::write(tree.join("a.txt"), &contents).unwrap();
+        std::fs::write(tree.join("a.log"), &contents).unwrap();
+        let archive = dir.join("tree.mark");
+        let opts = Opts {
+            level_map: vec![("txt".into(), 11), ("log".into(), 0)],
+            ..Default::default()
+        };
+        pack_into(&archive, opts, &[&tree]).unwrap();
+        let mut input = std::fs::File::open(&archive).unwrap();
+        let lens = ArchiveReader::new(&mut input)
+            .unwrap()
+            .map(|x| {
+                let member = x.unwrap();
+                (member.name, member.file.inner.len)
+            })
+            .collect::<std::collections::HashMap<_, _>>();
+        assert!(lens["tree/a.txt"] < lens["tree/a.log"], "{lens:?}");
+
+        let opts = Opts {
+            level_map: vec![("txt".into(), 12)],
+            ..Default::default()
+        };
+        assert!(matches!(
+            pack_into(&archive, opts, &[&tree]),
+            Err(MarkError::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn every_layout_reads_back_what_it_wrote() {
+        let value = |header: &FileHeader, field: Field| match field {
+            Field::Modified => header.modified,
+            Field::Accessed => header.accessed,
+            Field::Mode => header.mode.into(),
+            Field::NameLen => header.name_len.into(),
+            Field::DataCompression => header.data_compression.into(),
+            Field::UncompressedLen => header.uncompressed_len,
+            Field::Len => header.len,
+            Field::Crc32 => header.crc32.into(),
+            Field::Uid => header.uid.into(),
+            Field::Gid => header.gid.into(),
+            Field::Link => header.link.into(),
+            Field::HardLink => header.hard_link.into(),
+        };
+        let all = [
+            Field::Modified,
+            Field::Accessed,
+            Field::Mode,
+            Field::NameLen,
+            Field::DataCompression,
+            Field::UncompressedLen,
+            Field::Len,
+            Field::Crc32,
+            Field::Uid,
+            Field::Gid,
+            Field::Link,
+            Field::HardLink,
+        ];
+        let header = FileHeader {
+            modified: 1_000_000,
+            accessed: 2_000_000,
+            mode: 0o100644,
+            data_compression: DataCompression::None as u8,
+            uncompressed_len: 4,
+            len: 4,
+            crc32: crc32fast::hash(b"data"),
+            uid: 1000,
+            gid: 100,
+            link: 7,
+            hard_link: true,
+            ..Default::default()
+        };
+        for (version, layout) in LAYOUTS {
+            assert_eq!(Layout::for_version(*version).fields, layout.fields);
+            let written = FileHeaderRepr::new(header, b"name".to_vec(), b"data".to_vec());
+            let mut buf = vec![];
+            written.write(&mut buf, layout).unwrap();
+            let mut input = &buf[..];
+            let read =
+                FileHeaderRepr::read_header_only(&mut input, layout, None, ReadOptions::default())
+                    .unwrap();
+            assert_eq!(read.name, "name", "version {version}");
+            assert_eq!(input, b"data", "version {version}");
+            for field in all {
+                let expected = match layout.fields.contains(&field) {
+                    true => value(&written.inner, field),
+                    false => value(&FileHeader::default(), field),
+                };
+                assert_eq!(
+                    value(&read.inner, field),
+                    expected,
+                    "{field:?} in version {version}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gzip_round_trips() {
+        let dir = scratch("gzip");
+        let file = dir.join("file.txt");
+        let contents = b"gzip me, gzip me again\n".repeat(500);
+        std::fs::write(&file, &contents).unwrap();
+        let archive = dir.join("file.mark");
+        let opts = Opts {
+            compression_method: DataCompression::Gzip,
+            ..Default::default()
+        };
+        pack_into(&archive, opts, &[&file]).unwrap();
+        let mut input = std::fs::File::open(&archive).unwrap();
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        let member = reader.next_member().unwrap().unwrap();
+        assert_eq!(
+            member.file.inner.data_compression,
+            DataCompression::Gzip as u8
+        );
+        // The data is a gzip stream of its own
+        let mut data = vec![];
+        reader.data().read_to_end(&mut data).unwrap();
+        let mut decoded = vec![];
+        flate2::read::GzDecoder::new(&data[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, contents);
+
+        let opts = Opts {
+            input: Some(archive.to_string_lossy().into_owned()),
+            output: Some(dir.join("out").to_string_lossy().into_owned()),
+            quiet: true,
+            ..Default::default()
+        };
+        unpack(opts, &[]).unwrap();
+        assert_eq!(std::fs::read(dir.join("out/file.txt")).unwrap(), contents);
+    }
+
+    #[test]
+    fn member_names_are_sanitized() {
+        let sanitized = |name: &str| sanitize_member_name(std::path::Path::new(name));
+        for name in [
+            "../evil",
+            "a/../../evil",
+            "a/b/../../../evil",
+            // Even going up and back down
+            "a/../a",
+            "/etc/passwd",
+            "//etc/passwd",
+            "..",
+            ".",
+            "",
+            "a/..",
+        ] {
+            assert_eq!(sanitized(name), None, "{name}");
+        }
+        assert_eq!(sanitized("a/./b"), Some("a/b".into()));
+        assert_eq!(sanitized("./a/b/"), Some("a/b".into()));
+        #[cfg(windows)]
+        {
+            assert_eq!(sanitized("C:\\Windows\\evil"), None);
+            assert_eq!(sanitized("\\\\server\\share\\evil"), None);
+        }
+    }
+
+    #[test]
+    fn readers_note_a_missing_end_of_archive_marker() {
+        let buf = ArchiveWriter::new(vec![])
+            .unwrap()
+            .add_file("a", b"first")
+            .unwrap()
+            .finish()
+            .unwrap();
+        let mut input = &buf[..buf.len() - END_OF_ARCHIVE.len()];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        assert!(reader.next_member().unwrap().is_some());
+        assert!(reader.warnings().is_empty());
+        assert!(reader.next_member().unwrap().is_none());
+        assert_eq!(
+            reader.warnings(),
+            ["the end-of-archive marker is missing, the archive may be truncated"]
+        );
+    }
+
+    #[test]
+    fn streaming_writer_round_trips() {
+        let mut writer = StreamingArchiveWriter::new(vec![])
+            .unwrap()
+            .add_file("a", b"first")
+            .unwrap();
+        writer.set_compression(DataCompression::Zstd);
+        let writer = writer.add_file("b/c", b"second").unwrap();
+        let buf = writer.finish().unwrap();
+        let mut input = &buf[..];
+        let header = ArchiveHeader::read(&mut input).unwrap();
+        assert_eq!(header.file_count, UNKNOWN_FILE_COUNT);
+
+        let mut input = &buf[..];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        let mut files = vec![];
+        while let Some(member) = reader.next_member().unwrap() {
+            files.push((member.name.clone(), reader.contents(&member).unwrap()));
+        }
+        assert_eq!(
+            files,
+            [
+                ("a".into(), b"first".to_vec()),
+                ("b/c".into(), b"second".to_vec())
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loops_are_left_out() {
+        let dir = scratch("symlink-loops");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("a")).unwrap();
+        std::fs::write(tree.join("a/file"), b"file").unwrap();
+        std::os::unix::fs::symlink("..", tree.join("a/up")).unwrap();
+        std::os::unix::fs::symlink(".", tree.join("self")).unwrap();
+        let archive = dir.join("tree.mark");
+        let opts = Opts {
+            dereference: true,
+            ..Default::default()
+        };
+        pack_into(&archive, opts, &[&tree]).unwrap();
+        assert_eq!(names_in(&archive), ["tree/a/file"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn require_utf8_names_refuses_other_names() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = scratch("require-utf8-names");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join(std::ffi::OsStr::from_bytes(b"caf\xe9")), b"").unwrap();
+        let opts = Opts {
+            require_utf8_names: true,
+            ..Default::default()
+        };
+        let error = pack_into(&dir.join("tree.mark"), opts, &[&tree]).unwrap_err();
+        assert!(
+            matches!(&error, MarkError::Usage(message) if message.contains("74 72 65 65 2f 63 61 66 e9")),
+            "{error:?}"
+        );
+        assert_eq!(error.exit_code(), 1);
+    }
+
+    #[test]
+    fn names_for_stdin_are_sanitized() {
+        for name in [
+            "../evil",
+            "/etc/passwd",
+            "a/../b",
+            "./a",
+            "a//b",
+            "a/",
+            "",
+            ".",
+        ] {
+            let opts = Opts {
+                name: Some(name.into()),
+                ..Default::default()
+            };
+            assert!(
+                matches!(pack(opts, &[]), Err(MarkError::Usage(message)) if message.starts_with("-name")),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn empty_directories_round_trip() {
+        let dir = scratch("empty-directories");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("empty")).unwrap();
+        std::fs::create_dir_all(tree.join("full")).unwrap();
+        std::fs::write(tree.join("full/file"), b"file").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = std::fs::Permissions::from_mode(0o750);
+            std::fs::set_permissions(tree.join("empty"), permissions).unwrap();
+        }
+        let archive = dir.join("tree.mark");
+        pack_into(&archive, Opts::default(), &[&tree]).unwrap();
+        let opts = Opts {
+            input: Some(archive.to_string_lossy().into_owned()),
+            output: Some(dir.join("out").to_string_lossy().into_owned()),
+            quiet: true,
+            ..Default::default()
+        };
+        unpack(opts, &[]).unwrap();
+        assert!(dir.join("out/tree/empty").is_dir());
+        assert_eq!(
+            std::fs::read(dir.join("out/tree/full/file")).unwrap(),
+            b"file"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(dir.join("out/tree/empty")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o750);
+        }
+    }
+
+    #[test]
+    fn new_empty_directories_are_in_incrementals() {
+        let dir = scratch("incremental-directories");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("old")).unwrap();
+        std::fs::create_dir_all(tree.join("full")).unwrap();
+        std::fs::write(tree.join("full/file"), b"file").unwrap();
+        let base = dir.join("base.mark");
+        pack_into(&base, Opts::default(), &[&tree]).unwrap();
+        std::fs::create_dir(tree.join("new")).unwrap();
+        let incremental = dir.join("incremental.mark");
+        let opts = Opts {
+            newer_than_archive: Some(base.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        pack_into(&incremental, opts, &[&tree]).unwrap();
+        assert_eq!(names_in(&incremental), ["tree/new"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn latin1_names_round_trip() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = scratch("latin1");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9");
+        std::fs::write(tree.join(name), b"latin-1").unwrap();
+        let archive = dir.join("tree.mark");
+        pack_into(&archive, Opts::default(), &[&tree]).unwrap();
+        let opts = Opts {
+            input: Some(archive.to_string_lossy().into_owned()),
+            output: Some(dir.join("out").to_string_lossy().into_owned()),
+            quiet: true,
+            ..Default::default()
+        };
+        unpack(opts, &[]).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("out/tree").join(name)).unwrap(),
+            b"latin-1"
+        );
+    }
+
+    #[test]
+    fn flags_take_one_or_two_dashes() {
+        type Check = fn(&Opts) -> bool;
+        let cases: [(&[&str], Check); 6] = [
+            (&["-input", "in.mark"], |x| {
+                x.input.as_deref() == Some("in.mark")
+            }),
+            (&["-output", "out.mark"], |x| {
+                x.output.as_deref() == Some("out.mark")
+            }),
+            (&["-compress", "zstd"], |x| {
+                x.compression_method == DataCompression::Zstd
+            }),
+            (&["-include-dotfiles"], |x| x.include_dotfiles),
+            (&["-mode-mask", "022"], |x| x.mode_mask == 0o022),
+            (&["-max-member-size", "1K"], |x| {
+                x.max_member_size == Some(1024)
+            }),
+        ];
+        for (args, check) in cases {
+            for dashes in ["-", "--"] {
+                let args = args
+                    .iter()
+                    .map(|x| match x.strip_prefix('-') {
+                        Some(flag) => format!("{dashes}{flag}"),
+                        None => x.to_string(),
+                    })
+                    .collect::<Vec<_>>();
+                let (opts, positionals) = parse_flags(args.clone()).unwrap().unwrap();
+                assert!(check(&opts), "{args:?}");
+                assert!(positionals.is_empty(), "{args:?}");
+            }
+        }
+        // Everything after `--` is a positional argument
+        let args = ["--", "--input", "-o"].map(String::from).to_vec();
+        let (opts, positionals) = parse_flags(args).unwrap().unwrap();
+        assert_eq!(opts.input, None);
+        assert_eq!(positionals, ["--input", "-o"]);
+        // -V prints the version and returns instead of exiting
+        assert!(parse_flags(vec!["--version".into()]).unwrap().is_none());
+    }
+
+    #[test]
+    fn reproducible_archives_are_identical() {
+        let dir = scratch("reproducible");
+        let names = ["b", "a", "sub/c"];
+        // The same files, made in a different order, with different permissions and times after
+        // the epoch they're clamped to
+        for (copy, order, modified) in
+            [("one", [0, 1, 2], 2_000_000), ("two", [2, 1, 0], 3_000_000)]
+        {
+            let tree = dir.join(copy).join("tree");
+            std::fs::create_dir_all(tree.join("sub")).unwrap();
+            for i in order {
+                let path = tree.join(names[i]);
+                std::fs::write(&path, names[i].repeat(100)).unwrap();
+                let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(modified);
+                let file = std::fs::File::options().write(true).open(&path).unwrap();
+                file.set_modified(modified).unwrap();
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = if copy == "one" { 0o644 } else { 0o664 };
+                    let permissions = std::fs::Permissions::from_mode(mode);
+                    std::fs::set_permissions(&path, permissions).unwrap();
+                }
+            }
+        }
+        let mut archives = vec![];
+        for copy in ["one", "one", "two"] {
+            let archive = dir.join(format!("{copy}.mark"));
+            let opts = Opts {
+                reproducible: Some(1_500_000),
+                ..Default::default()
+            };
+            pack_into(&archive, opts, &[&dir.join(copy).join("tree")]).unwrap();
+            archives.push(std::fs::read(&archive).unwrap());
+        }
+        assert_eq!(archives[0], archives[1]);
+        assert_eq!(archives[0], archives[2]);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn passphrases_must_match() {
+        let dir = scratch("passphrase");
+        let file = dir.join("secret");
+        std::fs::write(&file, b"sensitive").unwrap();
+        let archive = dir.join("secret.mark");
+        let opts = Opts {
+            passphrase: Some("right".into()),
+            ..Default::default()
+        };
+        pack_into(&archive, opts, &[&file]).unwrap();
+        let unpacking = |passphrase: Option<&str>, output: &str| Opts {
+            input: Some(archive.to_string_lossy().into_owned()),
+            output: Some(dir.join(output).to_string_lossy().into_owned()),
+            passphrase: passphrase.map(String::from),
+            quiet: true,
+            ..Default::default()
+        };
+        let wrong = unpack(unpacking(Some("wrong"), "wrong"), &[]).unwrap_err();
+        assert_eq!(wrong.exit_code(), 1);
+        assert!(!dir.join("wrong/secret").exists());
+        assert!(unpack(unpacking(None, "none"), &[]).is_err());
+        assert!(!dir.join("none/secret").exists());
+        unpack(unpacking(Some("right"), "right"), &[]).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("right/secret")).unwrap(),
+            b"sensitive"
+        );
+
+        // Changed data fails its authentication tag even with the right passphrase
+        let mut buf = std::fs::read(&archive).unwrap();
+        let mut input = io::Cursor::new(buf.clone());
+        let mut reader = ArchiveReader::new_seekable(&mut input).unwrap();
+        reader.next_header(ReadOptions::default()).unwrap();
+        let data = reader.position().unwrap().unwrap();
+        buf[data as usize] ^= 1;
+        let mut input = &buf[..];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        reader.unlock("right").unwrap();
+        let member = reader.next_member().unwrap().unwrap();
+        let changed = reader.contents(&member).unwrap_err();
+        assert!(matches!(changed, MarkError::Corrupt(_)), "{changed}");
+        assert!(changed.to_string().contains("authenticate"), "{changed}");
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn readers_decrypt_with_the_passphrase() {
+        let dir = scratch("reader-passphrase");
+        let file = dir.join("secret");
+        std::fs::write(&file, b"sensitive").unwrap();
+        let archive = dir.join("secret.mark");
+        let opts = Opts {
+            passphrase: Some("right".into()),
+            ..Default::default()
+        };
+        pack_into(&archive, opts, &[&file]).unwrap();
+        let buf = std::fs::read(&archive).unwrap();
+
+        let mut input = &buf[..];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        assert!(reader.unlock("wrong").is_err());
+        let member = reader.next_member().unwrap().unwrap();
+        assert!(reader.contents(&member).is_err());
+
+        let mut input = &buf[..];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        reader.unlock("right").unwrap();
+        let member = reader.next_member().unwrap().unwrap();
+        assert_eq!(member.name, "secret");
+        assert_eq!(reader.contents(&member).unwrap(), b"sensitive");
+    }
+
+    #[test]
+    fn names_come_from_canonical_paths() {
+        let dir = scratch("canonical-names");
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::write(dir.join("a/b/file"), b"file").unwrap();
+        let archive = dir.join("names.mark");
+        let cases: [(&[&str], &[&str]); 4] = [
+            (&["a/../a/b/file"], &["file"]),
+            (&["./a/b/../b"], &["b/file"]),
+            (&["a/b/..", "./a"], &["a/b/file"]),
+            (&["a/b/file", "a/./b/../b/file"], &["file"]),
+        ];
+        for (paths, expected) in cases {
+            let paths = paths.iter().map(|x| dir.join(x)).collect::<Vec<_>>();
+            let paths = paths.iter().map(|x| x.as_path()).collect::<Vec<_>>();
+            pack_into(&archive, Opts::default(), &paths).unwrap();
+            assert_eq!(names_in(&archive), expected, "{paths:?}");
+        }
+    }
+
+    #[test]
+    fn files_with_the_same_name_are_refused() {
+        let dir = scratch("same-name");
+        for tree in ["one", "two"] {
+            std::fs::create_dir_all(dir.join(tree).join("project")).unwrap();
+            std::fs::write(dir.join(tree).join("project/README"), tree).unwrap();
+        }
+        let archive = dir.join("both.mark");
+        let paths = [dir.join("one/project"), dir.join("two/project")];
+        let error = pack_into(&archive, Opts::default(), &[&paths[0], &paths[1]]).unwrap_err();
+        assert!(
+            matches!(&error, MarkError::Usage(message) if message.contains("\"project/README\"")),
+            "{error:?}"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn deep_trees_dont_overflow_the_stack() {
+        let dir = scratch("deep");
+        // As deep as a path can go and still be opened, with too little stack to recurse that
+        // deep
+        let depth = (4000 - dir.as_os_str().len()) / 2;
+        let deepest = (0..depth).fold(dir.join("tree"), |path, _| path.join("d"));
+        std::fs::create_dir_all(&deepest).unwrap();
+        let walked = std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(move || {
+                let mut walked = 0;
+                walk(dir.join("tree"), None, false, &mut |_, _| {
+                    walked += 1;
+                    Ok(true)
+                })
+                .map(|()| walked)
+            })
+            .unwrap()
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(walked, depth);
+    }
+
+    #[test]
+    fn the_archive_being_written_isnt_packed() {
+        let dir = scratch("self-inclusion");
+        std::fs::write(dir.join("file"), b"file").unwrap();
+        let archive = dir.join("data.mark");
+        std::fs::write(&archive, b"an earlier archive").unwrap();
+        pack_into(&archive, Opts::default(), &[&dir]).unwrap();
+        let name = dir.file_name().unwrap().to_string_lossy();
+        assert_eq!(names_in(&archive), [format!("{name}/file")]);
+        // Also when it's named directly
+        pack_into(&archive, Opts::default(), &[&archive, &dir.join("file")]).unwrap();
+        assert_eq!(names_in(&archive), ["file"]);
+    }
+
+    #[test]
+    fn compressed_archives_round_trip() {
+        let dir = scratch("compressed-archives");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("a"), b"first file").unwrap();
+        std::fs::write(tree.join("b"), b"second file").unwrap();
+        let plain = dir.join("a.mark");
+        pack_into(&plain, Opts::default(), &[&tree]).unwrap();
+        let plain = std::fs::read(&plain).unwrap();
+        for extension in ["mark.gz", "mark.zst"] {
+            let archive = dir.join(format!("a.{extension}"));
+            pack_into(&archive, Opts::default(), &[&tree]).unwrap();
+            let compressed = std::fs::File::open(&archive).unwrap();
+            let mut decompressed = vec![];
+            match extension {
+                "mark.gz" => {
+                    flate2::read::GzDecoder::new(compressed).read_to_end(&mut decompressed)
+                }
+                _ => zstd::Decoder::new(compressed)
+                    .unwrap()
+                    .read_to_end(&mut decompressed),
+            }
+            .unwrap();
+            assert_eq!(decompressed, plain, "{extension}");
+
+            let output = dir.join(format!("out.{extension}"));
+            let opts = Opts {
+                input: Some(archive.to_string_lossy().into_owned()),
+                output: Some(output.to_string_lossy().into_owned()),
+                quiet: true,
+                ..Default::default()
+            };
+            unpack(opts, &[]).unwrap();
+            assert_eq!(std::fs::read(output.join("tree/a")).unwrap(), b"first file");
+            assert_eq!(
+                std::fs::read(output.join("tree/b")).unwrap(),
+                b"second file"
+            );
+        }
+    }
+
+    #[test]
+    fn names_dont_depend_on_how_paths_are_spelled() {
+        let dir = scratch("base");
+        std::fs::create_dir_all(dir.join("project/src")).unwrap();
+        std::fs::write(dir.join("project/src/main.rs"), b"fn main() {}").unwrap();
+        let canonical = std::fs::canonicalize(dir.join("project")).unwrap();
+        let spellings = [
+            dir.join("project"),
+            dir.join("project/"),
+            dir.join("./project"),
+            dir.join("project/src/.."),
+            canonical,
+        ];
+        let bases = [
+            (None, "project/src/main.rs"),
+            (Some(dir.clone()), "project/src/main.rs"),
+            (Some(dir.join("project")), "src/main.rs"),
+        ];
+        for (base, expected) in bases {
+            for path in &spellings {
+                let archive = dir.join("names.mark");
+                let opts = Opts {
+                    base: base.as_ref().map(|x| x.to_string_lossy().into_owned()),
+                    ..Default::default()
+                };
+                pack_into(&archive, opts, &[path]).unwrap();
+                assert_eq!(
+                    names_in(&archive),
+                    [expected],
+                    "{} in {base:?}",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn executables_stay_executable() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch("executables");
+        let script = dir.join("script");
+        std::fs::write(&script, b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let archive = dir.join("script.mark");
+        let packing = Opts {
+            output: Some(archive.to_string_lossy().into_owned()),
+            compression_method: DataCompression::None,
+            quiet: true,
+            ..Default::default()
+        };
+        pack(packing, &[script.to_string_lossy().into_owned()]).unwrap();
+        let unpacking = Opts {
+            input: Some(archive.to_string_lossy().into_owned()),
+            output: Some(dir.join("out").to_string_lossy().into_owned()),
+            quiet: true,
+            ..Default::default()
+        };
+        unpack(unpacking, &[]).unwrap();
+        let metadata = std::fs::metadata(dir.join("out/script")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o755);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mode_mask_clears_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch("mode-mask");
+        let mut file = regular("shared", b"");
+        file.inner.mode = S_IFREG | 0o777;
+        let archive = dir.join("shared.mark");
+        std::fs::write(&archive, archive_of(&[file])).unwrap();
+        let opts = Opts {
+            input: Some(archive.to_string_lossy().into_owned()),
+            output: Some(dir.join("out").to_string_lossy().into_owned()),
+            mode_mask: 0o022,
+            quiet: true,
+            ..Default::default()
+        };
+        unpack(opts, &[]).unwrap();
+        let metadata = std::fs::metadata(dir.join("out/shared")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o755);
+    }
+
+    #[test]
+    fn files_that_cant_be_opened_are_named() {
+        let dir = scratch("cant-open");
+        let missing = dir.join("missing.mark").to_string_lossy().into_owned();
+        let opts = Opts {
+            input: Some(missing.clone()),
+            ..Default::default()
+        };
+        let error = list(opts).unwrap_err();
+        assert_eq!(error.exit_code(), 2);
+        assert!(
+            error
+                .to_string()
+                .starts_with(&format!("Failed to open \"{missing}\": ")),
+            "{error}"
+        );
+
+        let unwritable = dir.join("missing/out.mark");
+        let error = pack_into(&unwritable, Opts::default(), &[&dir]).unwrap_err();
+        assert_eq!(error.exit_code(), 2);
+        let expected = format!("Failed to create \"{}\": ", unwritable.display());
+        assert!(error.to_string().starts_with(&expected), "{error}");
+    }
+
+    #[test]
+    fn hostile_timestamps_are_refused() {
+        let mut file = new_file(&Opts::default(), "x", b"hi".to_vec()).unwrap();
+        file.inner.modified = u64::MAX;
+        let buf = archive_of(&[file]);
+        let mut input = &buf[..];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        let error = reader.next_header(ReadOptions::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn keep_newer_survives_hostile_timestamps() {
+        let existing = std::fs::metadata("Cargo.toml").unwrap();
+        let mut file = new_file(&Opts::default(), "x", b"hi".to_vec()).unwrap();
+        file.inner.modified = u64::MAX;
+        assert!(!OnConflict::KeepNewer.replaces(&existing, &file.inner));
+        file.inner.modified = u32::MAX.into();
+        assert!(OnConflict::KeepNewer.replaces(&existing, &file.inner));
+    }
+
+    #[test]
+    fn names_leaving_the_output_directory_arent_written() {
+        let dir = scratch("leaving");
+        let output = dir.join("out");
+        let files = [
+            regular("../evil.txt", b"evil"),
+            regular("a/../../evil.txt", b"evil"),
+        ];
+        unpack_into(&files, &output, &[]).unwrap();
+        assert!(!dir.join("evil.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_in_the_archive_arent_written_through() {
+        let dir = scratch("chained-archive");
+        let output = dir.join("out");
+        // Each link stays inside on its own, but "l" ends up being the parent of "out"
+        let files = [
+            directory("a"),
+            symlink("a/sub", ".."),
+            symlink("l", "a/sub/.."),
+            regular("l/evil.txt", b"evil"),
+            symlink("m", "."),
+            regular("m/fine.txt", b"fine"),
+        ];
+        assert!(matches!(
+            unpack_into(&files, &output, &[]),
+            Err(MarkError::Invalid(_))
+        ));
+        assert!(!dir.join("evil.txt").exists());
+        assert!(!output.join("fine.txt").exists());
+        // "a/sub" is still made, just once nothing else can be written through it, but going up
+        // out of it is refused
+        assert!(output.join("a/sub").is_symlink());
+        assert!(std::fs::symlink_metadata(output.join("l")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_chained_through_other_links_dont_escape() {
+        let dir = scratch("chained-links");
+        // "d" is "slo/inner" itself, so "e" is three levels up from there, not one
+        let links = [
+            symlink("slo/inner/d", "."),
+            symlink("slo/inner/e", "d/d/../../.."),
+        ];
+        // Whichever comes first in the archive
+        for (name, files) in [
+            ("before", links.clone()),
+            ("after", [links[1].clone(), links[0].clone()]),
+        ] {
+            let output = dir.join(name);
+            unpack_into(&files, &output, &[]).unwrap();
+            assert!(output.join("slo/inner/d").is_symlink());
+            assert!(std::fs::symlink_metadata(output.join("slo/inner/e")).is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_on_disk_arent_written_through() {
+        let dir = scratch("chained-disk");
+        let output = dir.join("out");
+        std::fs::create_dir_all(output.join("a")).unwrap();
+        std::os::unix::fs::symlink("..", output.join("a/sub")).unwrap();
+        std::os::unix::fs::symlink("a/sub/..", output.join("l")).unwrap();
+        let files = [
+            regular("l/evil.txt", b"evil"),
+            regular("a/sub/evil.txt", b"evil"),
+        ];
+        assert!(matches!(
+            unpack_into(&files, &output, &[]),
+            Err(MarkError::Invalid(_))
+        ));
+        assert!(!dir.join("evil.txt").exists());
+        assert!(!output.join("evil.txt").exists());
+    }
+
+    #[test]
+    fn framed_data_reads_back() {
+        let contents = (0..FRAME_LEN * 3 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut framed = regular("framed", b"");
+        framed.inner.uncompressed_len = contents.len() as u64;
+        let mut buf = Vec::new();
+        let header = ArchiveHeader {
+            version: CURRENT_VERSION,
+            file_count: UNKNOWN_FILE_COUNT,
+            flags: INDEXED,
+            encryption: None,
+        };
+        let mut writer = RawArchiveWriter::with_header(Unseekable(&mut buf), header, None).unwrap();
+        writer
+            .add_streamed(&mut framed, &mut &contents[..])
+            .unwrap();
+        writer.add(&regular("after", b"after")).unwrap();
+        writer.finish().unwrap();
+        // The header is left as if the data hadn't been written in frames
+        assert!(!framed.inner.is_framed());
+        assert_eq!(framed.inner.len, contents.len() as u64);
+
+        let mut input = &buf[..];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        let file = reader.next_file(ReadOptions::default()).unwrap().unwrap();
+        assert_eq!(file.data, contents);
+        assert_eq!(file.inner.len, framed.inner.len);
+        assert!(file.checksum_matches(reader.layout));
+        let file = reader.next_file(ReadOptions::default()).unwrap().unwrap();
+        assert_eq!(file.name, "after");
+
+        // Read as it's decompressed, and skipped over without being read at all
+        let mut input = &buf[..];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        let file = reader.next_header(ReadOptions::default()).unwrap().unwrap();
+        let mut data = vec![];
+        reader.data().read_to_end(&mut data).unwrap();
+        assert_eq!(data, contents);
+        assert!(reader.finish_data(&file).unwrap());
+        let mut input = &buf[..];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        reader.next_header(ReadOptions::default()).unwrap();
+        let file = reader.next_header(ReadOptions::default()).unwrap().unwrap();
+        assert_eq!(file.name, "after");
+
+        // A frame that's been tampered with doesn't match the CRC32 after them
+        let at = buf.windows(3).position(|x| x == [1, 2, 3]).unwrap();
+        buf[at] ^= 1;
+        let mut input = &buf[..];
+        let mut reader = ArchiveReader::new(&mut input).unwrap();
+        let file = reader.next_file(ReadOptions::default()).unwrap().unwrap();
+        assert!(!file.checksum_matches(reader.layout));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn packing_into_a_pipe_holds_no_whole_file() {
+        let dir = scratch("pipe");
+        let big = dir.join("big");
+        std::fs::File::create(&big)
+            .and_then(|x| x.set_len(1 << 30))
+            .unwrap();
+        // Like a pipe, /dev/null can't be seeked in
+        let opts = Opts {
+            output: Some("/dev/null".into()),
+            compression_method: DataCompression::None,
+            quiet: true,
+            ..Default::default()
+        };
+        pack(opts, &[big.to_string_lossy().into_owned()]).unwrap();
+        assert!(LARGEST_ALLOCATION.load(Ordering::Relaxed) < 64 << 20);
+    }
+
+    #[test]
+    fn shared_data_is_read_again_for_files_unpacked_without_it() {
+        let dir = scratch("shared");
+        let a = regular("a", b"shared");
+        let files = [a.clone(), link("b", 0, &a), regular("c", b"after")];
+        unpack_into(&files, &dir.join("only"), &["b"]).unwrap();
+        assert_eq!(std::fs::read(dir.join("only/b")).unwrap(), b"shared");
+        assert!(!dir.join("only/a").exists());
+
+        // Left alone by the conflict policy, the file on disk isn't what's in the archive
+        let output = dir.join("skipped");
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("a"), b"on disk").unwrap();
+        unpack_into(&files, &output, &[]).unwrap();
+        assert_eq!(std::fs::read(output.join("a")).unwrap(), b"on disk");
+        assert_eq!(std::fs::read(output.join("b")).unwrap(), b"shared");
+        assert_eq!(std::fs::read(output.join("c")).unwrap(), b"after");
+    }
+
+    #[test]
+    fn exit_codes() {
+        let dir = scratch("exit-codes");
+        let code = |result: Result<(), MarkError>| result.err().map_or(0, |e| e.exit_code());
+        let with_input = |input: &str| Opts {
+            input: Some(dir.join(input).to_string_lossy().into_owned()),
+            output: Some(dir.join("out").to_string_lossy().into_owned()),
+            quiet: true,
+            ..Default::default()
+        };
+        let archive = archive_of(&[regular("a", b"contents"), regular("b", b"more contents")]);
+        std::fs::write(dir.join("whole.mark"), &archive).unwrap();
+        assert_eq!(code(verify(with_input("whole.mark"))), 0);
+
+        assert_eq!(code(cat(with_input("whole.mark"), &[])), 1);
+        let packing = Opts {
+            output: Some(dir.join("packed.mark").to_string_lossy().into_owned()),
+            max_memory: Some(1),
+            ..Default::default()
+        };
+        let packed = dir.join("whole.mark").to_string_lossy().into_owned();
+        assert_eq!(code(pack(packing, &[packed])), 1);
+        let unpacking = Opts {
+            max_memory: Some(1),
+            line_endings: Some(LineEndings::ToLf),
+            ..with_input("whole.mark")
+        };
+        assert_eq!(code(unpack(unpacking, &[])), 1);
+
+        assert_eq!(code(verify(with_input("missing.mark"))), 2);
+
+        // Cut off partway through the data of "b"
+        std::fs::write(dir.join("truncated.mark"), &archive[..archive.len() - 10]).unwrap();
+        assert_eq!(code(verify(with_input("truncated.mark"))), 3);
+        let salvaging = Opts {
+            salvage: true,
+            ..with_input("truncated.mark")
+        };
+        assert_eq!(code(unpack(salvaging, &[])), 5);
+        assert_eq!(std::fs::read(dir.join("out/a")).unwrap(), b"contents");
+
+        let mut corrupt = archive.clone();
+        let at = corrupt.windows(8).position(|x| x == b"contents").unwrap();
+        corrupt[at] ^= 1;
+        std::fs::write(dir.join("corrupt.mark"), &corrupt).unwrap();
+        assert_eq!(code(verify(with_input("corrupt.mark"))), 4);
+        let mut opts = Opts::default();
+        let manifest = write_manifest(&mut opts, &mut &corrupt[..], &mut io::sink());
+        assert_eq!(code(manifest), 4);
+    }
+
+    #[test]
+    fn max_member_size_refuses_files_claiming_more() {
+        let dir = scratch("max-member-size");
+        let mut lying = regular("lying", b"hi");
+        lying.inner.len = 1 << 40;
+        std::fs::write(dir.join("lying.mark"), archive_of(&[lying])).unwrap();
+        let mut framed = regular("framed", b"");
+        framed.inner.uncompressed_len = 5000;
+        let mut buf = vec![];
+        let header = ArchiveHeader {
+            version: CURRENT_VERSION,
+            file_count: UNKNOWN_FILE_COUNT,
+            flags: 0,
+            encryption: None,
+        };
+        let mut writer = RawArchiveWriter::with_header(Unseekable(&mut buf), header, None).unwrap();
+        writer
+            .add_streamed(&mut framed, &mut &[0u8; 5000][..])
+            .unwrap();
+        writer.finish().unwrap();
+        std::fs::write(dir.join("framed.mark"), buf).unwrap();
+
+        for archive in ["lying.mark", "framed.mark"] {
+            let opts = |max_member_size| Opts {
+                input: Some(dir.join(archive).to_string_lossy().into_owned()),
+                output: Some(dir.join("out").to_string_lossy().into_owned()),
+                max_member_size,
+                quiet: true,
+                ..Default::default()
+            };
+            for result in [
+                verify(opts(Some(1000))),
+                list(opts(Some(1000))),
+                unpack(opts(Some(1000)), &[]),
+            ] {
+                let e = result.unwrap_err();
+                assert!(e.to_string().contains("-max-member-size"), "{e}");
+                assert_eq!(e.exit_code(), 3);
+            }
+        }
+        let opts = Opts {
+            input: Some(dir.join("framed.mark").to_string_lossy().into_owned()),
+            max_member_size: Some(5000),
+            ..Default::default()
+        };
+        verify(opts).unwrap();
+    }
+
+    #[test]
+    fn huge_lengths_are_over_the_memory_limit() {
+        let dir = scratch("huge_lengths_are_over_the_memory_limit");
+        let mut file = regular("x", b"hi");
+        file.inner.uncompressed_len = u64::MAX;
+        let archive = dir.join("huge.mark");
+        std::fs::write(&archive, archive_of(&[file])).unwrap();
+        let opts = Opts {
+            input: Some(archive.to_string_lossy().into_owned()),
+            output: Some(dir.join("out").to_string_lossy().into_owned()),
+            max_memory: Some(1 << 20),
+            line_endings: Some(LineEndings::ToLf),
+            quiet: true,
+            ..Default::default()
+        };
+        assert!(matches!(unpack(opts, &[]), Err(MarkError::Usage(_))));
+    }
+}
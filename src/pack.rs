@@ -0,0 +1,1480 @@
+//! Packing files into a new archive, or onto the end of an existing one.
+
+use super::*;
+
+pub(crate) fn pack(mut opts: Opts, args: &[String]) -> Result<(), MarkError> {
+    let args = paths_to_pack(&opts, args)?;
+    if opts.name.is_some() && !args.is_empty() {
+        return Err(MarkError::Usage(
+            "-name is for packing stdin, it can't be given along with paths to pack".into(),
+        ));
+    }
+    if args.is_empty() && opts.name.is_none() {
+        return Err(MarkError::Usage(
+            "Expected one or more files or directories to archive!".into(),
+        ));
+    }
+    // The name is stored as given, so it has to be one unpack would write as is
+    if let Some(name) = &opts.name {
+        let sanitized = sanitize_member_name(std::path::Path::new(name)).map(|x| {
+            x.components()
+                .map(|x| x.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        });
+        if sanitized.as_deref() != Some(name.as_str()) {
+            return Err(MarkError::Usage(format!(
+                "-name \"{name}\" isn't a relative path made of only file names, separated by /"
+            )));
+        }
+    }
+    check_levels(&opts)?;
+
+    if opts.manifest.is_some() && opts.output.is_none() {
+        return Err(MarkError::Usage(
+            "-manifest needs an -output, the manifest is made by reading it back".into(),
+        ));
+    }
+    #[cfg(feature = "signing")]
+    if opts.sign.is_some() && opts.output.is_none() {
+        return Err(MarkError::Usage(
+            "-sign needs an -output, the signature is written next to it".into(),
+        ));
+    }
+
+    // With -name, stdin is packed instead of any files
+    let start = std::time::Instant::now();
+    let files = match opts.name {
+        Some(_) => vec![],
+        None => collect_files(&opts, &args, opts.output.as_deref())?,
+    };
+    let walking = start.elapsed();
+    if let (Some(name), true) = (&opts.name, opts.dry_run) {
+        eprintln!("Would pack stdin as \"{name}\"");
+        return Ok(());
+    }
+    if opts.dry_run {
+        let mut total = 0;
+        for (name, path, _) in &files {
+            let metadata = opts
+                .metadata(path)
+                .context(|| format!("Failed to read the metadata of \"{}\"", path.display()))?;
+            let len = match metadata.is_dir() {
+                true => 0,
+                false => metadata.len(),
+            };
+            total += len;
+            println!("{} ({len} bytes)", name.to_string_lossy());
+        }
+        eprintln!("Would pack {} file(s), {total} bytes", files.len());
+        return Ok(());
+    }
+    let from_stdin = opts.name.is_some();
+    load_dictionary(&mut opts, (!from_stdin).then_some(&files[..]))?;
+
+    let base: &mut dyn ArchiveOutput = match opts.output.as_deref() {
+        Some(output) => &mut OutputFile::create(output)?,
+        None => &mut BufWriter::new(std::io::stdout().lock()),
+    };
+    let mut encoder = None;
+    let output: &mut dyn ArchiveOutput = match opts.encode {
+        Some(encoding) => encoder.insert(TextEncoder::new(&mut *base, encoding)),
+        None => &mut *base,
+    };
+    // The signature covers the archive itself, before any text encoding
+    #[cfg(feature = "signing")]
+    let mut hasher = None;
+    #[cfg(feature = "signing")]
+    let output: &mut dyn ArchiveOutput = match opts.sign {
+        Some(_) => hasher.insert(HashingWriter::new(output)),
+        None => output,
+    };
+    // An archive written to a file is read back to digest it once it's done, so that files can
+    // still be streamed into it, and any other one is digested as it's written
+    let mut digester = None;
+    let output: &mut dyn ArchiveOutput = match opts.digest && output.seekable().is_none() {
+        true => digester.insert(HashingWriter::new(output)),
+        false => output,
+    };
+    // File data is compressed straight into the archive when the header in front of it can be
+    // filled in afterwards, instead of being held in memory
+    let streaming = output.seekable().is_some();
+
+    let writing = || "Failed to write the archive".to_string();
+    // Files left out with -skip-errors are taken off the file count afterwards, which needs to
+    // seek back to it
+    let count_unknown = opts.skip_errors && opts.name.is_none() && !opts.stream && !streaming;
+    if count_unknown && !opts.quiet {
+        eprintln!(
+            "Writing an archive without a file count, the output can't be seeked in to fill it in if files are left out"
+        );
+    }
+    let header = ArchiveHeader {
+        version: CURRENT_VERSION,
+        file_count: if opts.stream || count_unknown {
+            UNKNOWN_FILE_COUNT
+        } else if opts.name.is_some() {
+            1
+        } else {
+            files.len() as u32
+        },
+        flags: match opts.digest {
+            true => archive_flags(&opts) | DIGEST,
+            false => archive_flags(&opts),
+        },
+        encryption: None,
+    };
+    #[cfg(feature = "encryption")]
+    let header = match &opts.passphrase {
+        Some(passphrase) => {
+            let salt = encryption::new_salt()?;
+            let cipher = encryption::Cipher::derive(passphrase, &salt)?;
+            let check = cipher.check();
+            opts.cipher = Some(cipher);
+            ArchiveHeader {
+                flags: header.flags | ENCRYPTED,
+                encryption: Some(Encryption { salt, check }),
+                ..header
+            }
+        }
+        None => header,
+    };
+    let mut archive = RawArchiveWriter::with_header(output, header, opts.dictionary.as_deref())
+        .context(writing)?;
+    match &opts.name {
+        Some(name) => write_stdin(&opts, &mut archive, name)?,
+        None => write_files(&opts, &mut archive, &files, streaming, walking)?,
+    }
+    let written = archive.files;
+    let output = archive.finish().context(writing)?;
+    if !header.is_streamed() && written != header.file_count {
+        let output = output.seekable().ok_or_else(|| {
+            MarkError::Usage("The file count can't be filled in without seeking".into())
+        })?;
+        output.seek(io::SeekFrom::Start(0)).context(writing)?;
+        ArchiveHeader {
+            file_count: written,
+            ..header
+        }
+        .write(output)
+        .context(writing)?;
+        output.flush().context(writing)?;
+    }
+    if let (Some(output), Some(path), true) = (output.seekable(), &opts.output, opts.digest) {
+        output.flush().context(writing)?;
+        let digest = std::fs::File::open(path)
+            .and_then(|x| digest_of(BufReader::new(x)))
+            .context(|| format!("Failed to read \"{path}\" back to digest it"))?;
+        output.seek(io::SeekFrom::End(0)).context(writing)?;
+        write_digest(output, &digest).context(writing)?;
+    }
+    if let Some(digester) = digester {
+        let (output, digest) = digester.into_parts();
+        write_digest(output, &digest).context(writing)?;
+    }
+
+    #[cfg(feature = "signing")]
+    if let (Some(key), Some(hasher), Some(output)) = (&opts.sign, hasher, &opts.output) {
+        let (_, digest) = hasher.into_parts();
+        signing::sign(key, &digest, &signing::signature_path(output))?;
+    }
+
+    if let Some(encoder) = encoder {
+        encoder.finish().context(writing)?;
+    }
+    base.close().context(writing)?;
+
+    // Hashing what's in the archive, rather than the files that were packed, makes the manifest
+    // match what unpacking gives back even if line endings were converted
+    if let Some(manifest) = opts.manifest.take() {
+        opts.input = opts.output.take();
+        opts.decode = opts.encode;
+        let mut input = open_input(&opts)?;
+        let writing = || format!("Failed to write the manifest \"{manifest}\"");
+        let mut output = BufWriter::new(std::fs::File::create(&manifest).context(writing)?);
+        write_manifest(&mut opts, &mut *input, &mut output)?;
+        output.flush().context(writing)?;
+    }
+
+    Ok(())
+}
+
+/// Adds files to the end of the archive at `-input`, in place, without rewriting what's
+/// already in it.
+pub(crate) fn append(mut opts: Opts, args: &[String]) -> Result<(), MarkError> {
+    use io::Seek;
+
+    let args = paths_to_pack(&opts, args)?;
+    if args.is_empty() {
+        return Err(MarkError::Usage(
+            "Expected one or more files or directories to append!".into(),
+        ));
+    }
+    let Some(path) = opts.input.clone() else {
+        return Err(MarkError::Usage(
+            "append needs the archive to add to as -input, it can't append through stdin".into(),
+        ));
+    };
+    let path = path.as_str();
+    if opts.decode.is_some() || opts.encode.is_some() {
+        return Err(MarkError::Usage(
+            "append can't add to a text-encoded archive".into(),
+        ));
+    }
+    if Container::of(path).is_some() {
+        return Err(MarkError::Usage(format!(
+            "append can't add to \"{path}\", it's compressed as a whole"
+        )));
+    }
+    check_levels(&opts)?;
+    if opts.dict.is_some() {
+        return Err(MarkError::Usage(
+            "append can't use -dict, the files are compressed with the archive's own dictionary if it has one".into(),
+        ));
+    }
+
+    let reading = || format!("Failed to read \"{path}\"");
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context(|| format!("Failed to open \"{path}\""))?;
+    if !file.metadata().context(reading)?.is_file() {
+        return Err(MarkError::Usage(format!(
+            "\"{path}\" isn't a regular file, append needs to seek in it"
+        )));
+    }
+
+    // Find where the last file ends, and which names are taken already
+    let mut reader = BufReader::new(&file);
+    let mut names = std::collections::HashSet::new();
+    let (header, existing) = {
+        let mut archive = ArchiveReader::new(&mut reader)
+            .map(reporting_warnings)
+            .context(reading)?;
+        let options = ReadOptions {
+            skip_data: true,
+            max_member_size: opts.max_member_size,
+            ..Default::default()
+        };
+        while let Some(file) = archive.next_header(options).context(reading)? {
+            names.insert(file.name_bytes().to_vec());
+        }
+        opts.dictionary = archive.dictionary;
+        (archive.header, archive.read)
+    };
+    if header.version != CURRENT_VERSION {
+        return Err(MarkError::Usage(format!(
+            "\"{path}\" is a version {} archive, only version {CURRENT_VERSION} archives can be appended to. Unpack it and pack it again first.",
+            header.version
+        )));
+    }
+    // The files appended to an encrypted archive are encrypted with its key
+    #[cfg(feature = "encryption")]
+    if opts.passphrase.is_some() && header.encryption.is_none() {
+        return Err(MarkError::Usage(format!(
+            "\"{path}\" isn't encrypted, so the files appended to it can't be"
+        )));
+    }
+    unlock(&mut opts, header, true)?;
+    let end = reader.stream_position().context(reading)?;
+    let mut marker = [0u8; END_OF_ARCHIVE.len()];
+    let marker_position = end.saturating_sub(marker.len() as u64);
+    reader
+        .seek(io::SeekFrom::Start(marker_position))
+        .and_then(|_| reader.read_exact(&mut marker))
+        .context(reading)?;
+    // The index after the last file is written again with the new files in it
+    let index = match header.is_indexed() {
+        true => Some(ArchiveIndex::find(&mut reader, header).context(reading)?),
+        false => None,
+    };
+    let index_len = index.as_ref().map_or(0, |x| x.size());
+    if end + index_len + header.trailer_len() != file.metadata().context(reading)?.len()
+        || marker != END_OF_ARCHIVE
+        || index
+            .as_ref()
+            .is_some_and(|x| x.entries.len() != existing as usize)
+    {
+        return Err(MarkError::Invalid(format!(
+            "\"{path}\" doesn't end right after its last file, it may be truncated, so nothing was appended"
+        )));
+    }
+
+    let start = std::time::Instant::now();
+    let files = collect_files(&opts, &args, Some(path))?
+        .into_iter()
+        .filter(|(name, ..)| {
+            let taken = names.contains(name.as_encoded_bytes());
+            if taken {
+                eprintln!(
+                    "Warning: \"{}\" is already in the archive, skipping it",
+                    name.to_string_lossy()
+                );
+            }
+            !taken
+        })
+        .collect::<Vec<_>>();
+    let file_count = if header.is_streamed() {
+        UNKNOWN_FILE_COUNT
+    } else {
+        match u32::try_from(files.len())
+            .ok()
+            .and_then(|x| header.file_count.checked_add(x))
+            .filter(|&x| x != UNKNOWN_FILE_COUNT)
+        {
+            Some(file_count) => file_count,
+            None => {
+                return Err(MarkError::Usage(format!(
+                    "\"{path}\" can't hold {} more files",
+                    files.len()
+                )))
+            }
+        }
+    };
+
+    // The new files go over the end-of-archive marker, which is written again after them
+    let writing = || format!("Failed to write \"{path}\"");
+    let mut output = BufWriter::new(file);
+    output
+        .seek(io::SeekFrom::Start(marker_position))
+        .context(writing)?;
+    let mut archive = RawArchiveWriter::resume(&mut output, existing, marker_position);
+    archive.index = index;
+    write_files(&opts, &mut archive, &files, true, start.elapsed())?;
+    // Files left out with -skip-errors aren't counted
+    let file_count = match header.is_streamed() {
+        true => file_count,
+        false => archive.files,
+    };
+    let output = archive.finish().context(writing)?;
+    if file_count != header.file_count {
+        output.seek(io::SeekFrom::Start(0)).context(writing)?;
+        ArchiveHeader {
+            file_count,
+            ..header
+        }
+        .write(output)
+        .context(writing)?;
+    }
+    output.flush().context(writing)?;
+    // The digest is of the whole archive, so it's made again from its start
+    if header.has_digest() {
+        let file = output.get_mut();
+        file.seek(io::SeekFrom::Start(0)).context(writing)?;
+        let digest = digest_of(BufReader::new(&*file)).context(reading)?;
+        file.seek(io::SeekFrom::End(0)).context(writing)?;
+        write_digest(file, &digest).context(writing)?;
+    }
+
+    #[cfg(feature = "signing")]
+    if std::path::Path::new(&signing::signature_path(path)).exists() {
+        eprintln!(
+            "Warning: \"{}\" no longer matches the archive and needs to be made again",
+            signing::signature_path(path)
+        );
+    }
+    Ok(())
+}
+
+/// Checks that the compression levels asked for fit the compression method.
+pub(crate) fn check_levels(opts: &Opts) -> Result<(), MarkError> {
+    if let Some(range) = opts.compression_method.level_range() {
+        if let Some(level) = opts.level.filter(|level| !range.contains(level)) {
+            return Err(MarkError::Usage(format!(
+                "The -level must be between {} and {} for {:?}, not {level}",
+                range.start(),
+                range.end(),
+                opts.compression_method
+            )));
+        }
+        if let Some((extension, level)) = opts
+            .level_map
+            .iter()
+            .find(|(_, level)| !range.contains(level))
+        {
+            return Err(MarkError::Usage(format!(
+                "The level for \"{extension}\" must be between {} and {} for {:?}, not {level}",
+                range.start(),
+                range.end(),
+                opts.compression_method
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The paths to pack, which are `args` followed by the ones listed in the `-files-from` file.
+fn paths_to_pack(opts: &Opts, args: &[String]) -> Result<Vec<std::path::PathBuf>, MarkError> {
+    let mut paths = args
+        .iter()
+        .map(std::path::PathBuf::from)
+        .collect::<Vec<_>>();
+    if let Some((list, separator)) = &opts.files_from {
+        let mut contents = vec![];
+        match list.as_str() {
+            "-" => io::stdin().lock().read_to_end(&mut contents),
+            list => std::fs::File::open(list).and_then(|mut x| x.read_to_end(&mut contents)),
+        }
+        .context(|| format!("Failed to read the list of files to pack from \"{list}\""))?;
+        paths.extend(
+            contents
+                .split(|x| x == separator)
+                .filter(|x| !x.is_empty())
+                .map(|x| std::path::PathBuf::from(platform::os_string(x.to_vec()))),
+        );
+    }
+    Ok(paths)
+}
+
+/// Finds the files to pack from `args` and what to name them in the archive, in the order
+/// they're stored. Directories are only included when they're empty. The `archive` being written
+/// is left out, if it's among them.
+fn collect_files(
+    opts: &Opts,
+    args: &[std::path::PathBuf],
+    archive: Option<&str>,
+) -> Result<Vec<(std::ffi::OsString, std::path::PathBuf, bool)>, MarkError> {
+    let mut existing_args = vec![];
+    for a in args {
+        if a.exists() {
+            existing_args.push(a);
+        } else if opts.ignore_missing {
+            eprintln!("Warning: skipping \"{}\", it does not exist", a.display());
+        } else {
+            return Err(MarkError::Usage(format!(
+                "\"{}\" does not exist",
+                a.display()
+            )));
+        }
+    }
+
+    let glob_set = |flag: &str, patterns: &[String]| {
+        let mut set = globset::GlobSetBuilder::new();
+        for pattern in patterns {
+            set.add(globset::Glob::new(pattern).map_err(|e| {
+                MarkError::Usage(format!("Invalid {flag} pattern \"{pattern}\": {e}"))
+            })?);
+        }
+        set.build()
+            .map_err(|e| MarkError::Usage(format!("Invalid {flag} patterns: {e}")))
+    };
+    let include = glob_set("-include", &opts.include)?;
+    let exclude = glob_set("-exclude", &opts.exclude)?;
+
+    let mut files = vec![];
+    let mut gitignores = Gitignores::default();
+    let packed_before = match &opts.newer_than_archive {
+        Some(archive) => Some(modification_times(archive)?),
+        None => None,
+    };
+    let base_dir = match &opts.base {
+        Some(base) => Some(
+            std::fs::canonicalize(base).context(|| format!("Failed to read -base \"{base}\""))?,
+        ),
+        None => None,
+    };
+    for a in existing_args {
+        let path = a.as_path();
+        if !opts.include_dotfiles
+            && path
+                .file_name()
+                .is_some_and(|n| n.as_encoded_bytes()[0] == b'.')
+        {
+            continue;
+        }
+        if path.is_dir() && has_exclusion_marker(opts, path) {
+            continue;
+        }
+        let root = path;
+        // Names are relative to the directory the path is in, found the same way as the paths
+        // files are told apart by. That way `..` and symlinked directories leading up to it are
+        // resolved, instead of ending up in the names.
+        let canonical_root =
+            canonicalize_entry(path).context(|| format!("Failed to read \"{}\"", a.display()))?;
+        let base = match &base_dir {
+            Some(base) if !canonical_root.starts_with(base) => {
+                return Err(MarkError::Usage(format!(
+                    "\"{}\" isn't inside of -base \"{}\"",
+                    a.display(),
+                    base.display()
+                )));
+            }
+            Some(base) => base,
+            None => canonical_root.parent().unwrap_or(&canonical_root),
+        };
+        // Takes the canonical path of a file. Only real directories are walked into, so they're
+        // all inside of `canonical_root` unless one was replaced with a symlink in the meantime.
+        let entry_name = |path: &std::path::Path| {
+            let Ok(name) = path.strip_prefix(base) else {
+                return Err(io::Error::other(format!(
+                    "\"{}\" moved out of \"{}\" while it was being packed",
+                    path.display(),
+                    canonical_root.display()
+                )));
+            };
+            Ok(name.as_os_str().to_os_string())
+        };
+        // In case it's empty, since the walk only covers what's inside it. The root directory,
+        // and the -base directory, don't have a name of their own to store them under.
+        if path.is_dir()
+            && (opts.dereference || !path.is_symlink())
+            && canonical_root.parent().is_some()
+            && canonical_root != *base
+        {
+            let reading = || format!("Failed to read \"{}\"", a.display());
+            let name = entry_name(&canonical_root).context(reading)?;
+            if changed(opts, packed_before.as_ref(), &name, path).context(reading)? {
+                files.push((name, canonical_root.clone(), true));
+            }
+        }
+        walk(
+            path,
+            opts.max_depth,
+            opts.dereference,
+            &mut |is_dir, path| {
+                if is_dir
+                    && opts.exclude_vcs
+                    && path
+                        .file_name()
+                        .is_some_and(|name| VCS_DIRS.iter().any(|vcs| name == *vcs))
+                {
+                    return Ok(false);
+                }
+                if !opts.include_dotfiles
+                    && path
+                        .file_name()
+                        .is_some_and(|n| n.as_encoded_bytes()[0] == b'.')
+                {
+                    return Ok(false);
+                }
+                if is_dir && has_exclusion_marker(opts, path) {
+                    return Ok(false);
+                }
+                if !opts.no_gitignore && gitignores.ignored(root, path, is_dir) {
+                    return Ok(false);
+                }
+                if !is_dir && !size_in_range(opts, path)? {
+                    return Ok(false);
+                }
+                // Excluded directories aren't walked, but with -include the rest are walked for the
+                // files inside of them that match, without being stored themselves
+                let canonical = match opts.dereference {
+                    // Symlinked directories lead outside of the root, but their files are stored as
+                    // if they were inside of it
+                    true => match path.strip_prefix(root) {
+                        Ok(inside) if !inside.as_os_str().is_empty() => canonical_root.join(inside),
+                        _ => canonical_root.clone(),
+                    },
+                    false => canonicalize_entry(path)?,
+                };
+                let name = entry_name(&canonical)?;
+                if exclude.is_match(&name) {
+                    return Ok(false);
+                }
+                if !opts.include.is_empty() && !include.is_match(&name) {
+                    return Ok(is_dir);
+                }
+                // Unchanged directories are still walked for the files in them that changed
+                if !changed(opts, packed_before.as_ref(), &name, path)? {
+                    return Ok(is_dir);
+                }
+                files.push((name, canonical, is_dir));
+                Ok(true)
+            },
+        )
+        .context(|| format!("Failed to collect the files in \"{}\"", a.display()))?;
+    }
+    // Names are stored as the bytes they are, UTF-8 or not
+    if let Some((name, ..)) = files
+        .iter()
+        .find(|(name, ..)| opts.require_utf8_names && name.to_str().is_none())
+    {
+        return Err(MarkError::Usage(format!(
+            "File name isn't valid UTF-8, and -require-utf8-names is given: {} (bytes: {})",
+            std::path::Path::new(name).display(),
+            hex_bytes(name.as_encoded_bytes())
+        )));
+    }
+    files.sort_by(|l, r| l.1.cmp(&r.1));
+    files.dedup_by(|l, r| l.1 == r.1);
+    // Packing the archive into itself would store whatever had been written of it so far
+    let archive = archive.map(std::path::Path::new).and_then(|archive| {
+        std::fs::canonicalize(archive)
+            .or_else(|_| canonicalize_entry(archive))
+            .ok()
+    });
+    if let Some(archive) = archive {
+        files.retain(|(_, path, _)| {
+            if *path != archive {
+                return true;
+            }
+            eprintln!(
+                "Warning: skipping \"{}\", it's the archive being written",
+                path.display()
+            );
+            false
+        });
+    }
+    // Directories are only stored when they're empty, which is when the entry sorted right after
+    // one isn't inside of it
+    let keep = (0..files.len())
+        .map(|i| {
+            !files[i].2
+                || files
+                    .get(i + 1)
+                    .is_none_or(|x| !x.1.starts_with(&files[i].1))
+        })
+        .collect::<Vec<_>>();
+    let mut keep = keep.into_iter();
+    files.retain(|_| keep.next().unwrap());
+    // Different files stored under the same name couldn't all be unpacked
+    let mut names = std::collections::HashMap::new();
+    for (name, path, _) in &files {
+        if let Some(other) = names.insert(name, path) {
+            return Err(MarkError::Usage(format!(
+                "\"{}\" and \"{}\" would both be stored as \"{}\"",
+                other.display(),
+                path.display(),
+                std::path::Path::new(name).display()
+            )));
+        }
+    }
+    // Where the files are on disk differs between machines, but not their names
+    if opts.reproducible.is_some() || opts.sort == SortOrder::Name {
+        files.sort_by(|l, r| l.0.cmp(&r.0));
+    }
+    // The sorts are stable, so files that tie stay in the order they're already in
+    match opts.sort {
+        SortOrder::Path | SortOrder::Name => {}
+        SortOrder::Size => files.sort_by_cached_key(|(_, path, is_dir)| match is_dir {
+            true => 0,
+            false => opts.metadata(path).map_or(0, |x| x.len()),
+        }),
+        SortOrder::Extension => files.sort_by_cached_key(|(name, ..)| {
+            std::path::Path::new(name)
+                .extension()
+                .map(|x| x.to_os_string())
+        }),
+    }
+    Ok(files)
+}
+
+/// The order `pack` stores files in. Similar files next to each other can compress better.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum SortOrder {
+    /// By where they are on disk, or with `-reproducible` by name.
+    #[default]
+    Path,
+    /// By their names in the archive.
+    Name,
+    /// From smallest to largest.
+    Size,
+    /// Grouped by extension, with files that have none first.
+    Extension,
+}
+
+impl std::str::FromStr for SortOrder {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "path" => Self::Path,
+            "name" => Self::Name,
+            "size" => Self::Size,
+            "ext" | "extension" => Self::Extension,
+            _ => return Err("unsupported sort order"),
+        })
+    }
+}
+
+/// When each file in an archive was modified, by name, see [`modification_times`].
+type PackedTimes = std::collections::HashMap<Vec<u8>, Option<(u64, u32)>>;
+
+/// Reads when each file in the archive at `path` was modified, by name, for
+/// `-newer-than-archive`. The directories the files are in have no time, since they're only
+/// stored by way of their files.
+fn modification_times(path: &str) -> Result<PackedTimes, MarkError> {
+    let opts = Opts {
+        input: Some(path.to_string()),
+        ..Default::default()
+    };
+    let reading = || format!("Failed to read \"{path}\"");
+    let mut input = open_input(&opts)?;
+    let mut archive = ArchiveReader::new(&mut *input)
+        .map(reporting_warnings)
+        .context(reading)?;
+    let options = ReadOptions {
+        skip_data: true,
+        ..Default::default()
+    };
+    let mut times = std::collections::HashMap::new();
+    while let Some(file) = archive.next_header(options).context(reading)? {
+        let modified = (file.inner.modified, file.modified_nanos);
+        let name = file.name_bytes();
+        for (i, _) in name.iter().enumerate().filter(|&(_, &x)| x == b'/') {
+            times.entry(name[..i].to_vec()).or_insert(None);
+        }
+        times.insert(name.to_vec(), Some(modified));
+    }
+    Ok(times)
+}
+
+/// Whether the file at `path`, to be stored as `name`, was modified since `-newer-than`, and
+/// since it was packed into the `-newer-than-archive` if it's among the `packed_before` files.
+/// A directory that was only packed by way of the files in it is taken to be unchanged, and one
+/// that isn't among them at all is new.
+fn changed(
+    opts: &Opts,
+    packed_before: Option<&PackedTimes>,
+    name: &std::ffi::OsStr,
+    path: &std::path::Path,
+) -> io::Result<bool> {
+    if opts.newer_than.is_none() && packed_before.is_none() {
+        return Ok(true);
+    }
+    let modified = opts
+        .metadata(path)?
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    if opts.newer_than.is_some_and(|time| modified <= time) {
+        return Ok(false);
+    }
+    let Some(packed_before) = packed_before else {
+        return Ok(true);
+    };
+    Ok(match packed_before.get(name.as_encoded_bytes()) {
+        // Without subsecond times, only the seconds can be compared
+        Some(&Some((secs, 0))) => modified.as_secs() > secs,
+        Some(&Some((secs, nanos))) => modified > Duration::new(secs, nanos),
+        Some(None) => false,
+        None => true,
+    })
+}
+
+/// Whether the file at `path` is within `-min-size` and `-max-size`.
+fn size_in_range(opts: &Opts, path: &std::path::Path) -> io::Result<bool> {
+    if opts.min_size.is_none() && opts.max_size.is_none() {
+        return Ok(true);
+    }
+    let len = opts.metadata(path)?.len();
+    let in_range =
+        opts.min_size.is_none_or(|min| len >= min) && opts.max_size.is_none_or(|max| len <= max);
+    if !in_range && opts.verbose {
+        eprintln!(
+            "Skipping \"{}\", its size of {len} bytes is outside of -min-size and -max-size",
+            path.display()
+        );
+    }
+    Ok(in_range)
+}
+
+/// Compresses `files` into `archive`, streaming their data into it if `streaming` (because the
+/// archive can be seeked in) or they're large.
+fn write_files<W: ArchiveOutput>(
+    opts: &Opts,
+    archive: &mut RawArchiveWriter<W>,
+    files: &[(std::ffi::OsString, std::path::PathBuf, bool)],
+    streaming: bool,
+    walking: Duration,
+) -> Result<(), MarkError> {
+    if let Some(max) = opts.max_memory {
+        for (name, path, _) in files {
+            let len = match opts.metadata(path) {
+                Ok(metadata) => metadata.len(),
+                // It's left out when it's packed
+                Err(_) if opts.skip_errors => continue,
+                Err(e) => Err(e).context(|| format!("Failed to read \"{}\"", path.display()))?,
+            };
+            // The file's contents and compressed data are held in memory, and converting line
+            // endings needs the original contents too
+            // A length too large to add up is over any limit
+            let needed = match opts.line_endings {
+                Some(_) => len.checked_mul(3),
+                None if streaming || len >= PARALLEL_MAX_LEN => Some(0),
+                None => len.checked_mul(2),
+            }
+            .and_then(|x| x.checked_add(AUTO_COMPRESS_SAMPLE_SIZE as u64))
+            .unwrap_or(u64::MAX);
+            if needed > max {
+                return Err(MarkError::Usage(format!("Packing \"{}\" needs about {needed} bytes of memory, more than the -max-memory limit of {max}", name.to_string_lossy())));
+            }
+        }
+    }
+
+    // Hard links, and with -dedup files with the same contents as an earlier one, only refer to
+    // the data of the first
+    let hard_links = find_hard_links(opts, files)?;
+    let links = match opts.dedup {
+        true => find_duplicates(opts, files, &hard_links)?,
+        false => vec![None; files.len()],
+    };
+
+    let mut progress = Progress::new(opts, Some(files.len() as u64));
+    let writing = || "Failed to write the archive".to_string();
+    let (mut looked_compressed, mut stored_uncompressed) = (0usize, 0usize);
+    let (mut deduplicated, mut hard_linked) = (0usize, 0usize);
+    let mut skipped = 0usize;
+    let mut normalized = 0usize;
+    let mut chosen_compression = [0usize; DataCompression::ALL.len()];
+    // The size of the files written, and how much of the archive their data takes
+    let (mut total_size, mut total_stored) = (0u64, 0u64);
+    // For -stats, the time spent reading and compressing files (or waiting on the workers doing
+    // that) and the time spent writing them, which are interleaved for streamed files
+    let (mut compressing, mut writing_time) = (Duration::ZERO, Duration::ZERO);
+    let jobs = opts.jobs();
+    // Files are handed to the workers in order, and a worker waits before getting too far ahead
+    // of what's been written so that only a handful of compressed files are held at once. Large
+    // files are left to be compressed straight into the archive.
+    let window = jobs * 2;
+    let large = files
+        .iter()
+        .map(|(_, path, _)| {
+            opts.metadata(path)
+                .is_ok_and(|x| x.len() >= PARALLEL_MAX_LEN)
+        })
+        .collect::<Vec<_>>();
+    let in_order = (0..files.len())
+        .map(|i| jobs == 1 || hard_links[i].is_some() || links[i].is_some() || large[i])
+        .collect::<Vec<_>>();
+    let next = AtomicUsize::new(0);
+    let written = (Mutex::new(0usize), Condvar::new());
+    let (sender, receiver) = mpsc::sync_channel(window);
+    std::thread::scope(|scope| {
+        if jobs > 1 {
+            for _ in 0..jobs {
+                let sender = sender.clone();
+                let (next, written, files, in_order, opts) =
+                    (&next, &written, &files, &in_order, &opts);
+                scope.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= files.len() {
+                        break;
+                    }
+                    if in_order[i] {
+                        continue;
+                    }
+                    let mut done = written.0.lock().unwrap();
+                    while i >= *done + window {
+                        done = written.1.wait(done).unwrap();
+                    }
+                    drop(done);
+                    let (name, path, _) = &files[i];
+                    // A panic is sent on as the file's error, rather than leaving it unwritten
+                    let entry = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        pack_entry(opts, name.clone(), path, false)
+                    }))
+                    .unwrap_or_else(|_| Err(worker_failed(path)))
+                    .map(|x| x.0);
+                    if sender.send((i, entry)).is_err() {
+                        break;
+                    }
+                });
+            }
+        }
+        drop(sender);
+
+        let mut ready = std::collections::HashMap::new();
+        let store_opts = Opts {
+            compression_method: DataCompression::None,
+            compress_map: vec![],
+            auto_compress: false,
+            ..opts.clone()
+        };
+        // Only the metadata of a file that shares an earlier one's data needs to be read
+        let link_opts = Opts {
+            line_endings: None,
+            ..store_opts.clone()
+        };
+        // The index in the archive and header of each file written so far, or `None` for the ones
+        // left out with -skip-errors
+        let mut written_files: Vec<Option<(u32, FileHeader)>> = vec![];
+        let result = (|| {
+            for (i, (name, path, _)) in files.iter().enumerate() {
+                // A file whose data would be shared with one that was left out is packed itself
+                let target = hard_links[i].or(links[i]).and_then(|j| written_files[j]);
+                let start = std::time::Instant::now();
+                let packed = if let Some((index, target)) = target {
+                    pack_entry(&link_opts, name.clone(), path, true).map(|(mut entry, _)| {
+                        let header = &mut entry.file.inner;
+                        header.link = index;
+                        header.hard_link = hard_links[i].is_some();
+                        header.data_compression = target.data_compression;
+                        header.uncompressed_len = target.uncompressed_len;
+                        (entry, None)
+                    })
+                } else if in_order[i] {
+                    pack_entry(opts, name.clone(), path, streaming || large[i])
+                } else {
+                    let entry = loop {
+                        if let Some(entry) = ready.remove(&i) {
+                            break entry;
+                        }
+                        let (j, entry) = receiver.recv().map_err(|_| worker_failed(path))?;
+                        ready.insert(j, entry);
+                    };
+                    entry.map(|entry| (entry, None))
+                };
+                compressing += start.elapsed();
+                let (entry, stream) = match packed {
+                    Err(e @ MarkError::Io { .. }) if opts.skip_errors => {
+                        eprintln!("Warning: {e}, leaving it out");
+                        skipped += 1;
+                        written_files.push(None);
+                        *written.0.lock().unwrap() = i + 1;
+                        written.1.notify_all();
+                        continue;
+                    }
+                    packed => packed?,
+                };
+                if target.is_some() {
+                    match entry.file.inner.hard_link {
+                        true => hard_linked += 1,
+                        false => deduplicated += 1,
+                    }
+                }
+                let mut f = entry.file;
+                looked_compressed += entry.looked_compressed as usize;
+                stored_uncompressed += entry.stored_uncompressed as usize;
+                normalized += entry.normalized as usize;
+                if let Some(chosen) = entry.chosen_compression {
+                    chosen_compression[chosen as usize] += 1;
+                }
+                let writing_file =
+                    || format!("Failed to write \"{}\" to the archive", path.display());
+                let start = std::time::Instant::now();
+                let mut streamed_reading = Duration::ZERO;
+                match stream {
+                    Some(data) => {
+                        // Streamed data is compressed as it's read
+                        let mut data = TimedReader::new(data);
+                        archive
+                            .add_streamed(&mut f, &mut data)
+                            .context(writing_file)?;
+                        streamed_reading += data.elapsed;
+                        // How well it compresses is only known now, so if it came out bigger
+                        // it's read again to store it as it is instead. Frames already written
+                        // to an archive that can't be seeked in are there to stay.
+                        let stored_len = match f.inner.is_encrypted() {
+                            #[cfg(feature = "encryption")]
+                            true => encryption::encrypted_len(f.inner.uncompressed_len),
+                            _ => f.inner.uncompressed_len,
+                        };
+                        if streaming
+                            && f.inner
+                                .compression()
+                                .is_ok_and(|x| x != DataCompression::None)
+                            && !opts.no_auto_store
+                            && f.inner.len > stored_len
+                        {
+                            let (entry, data) = pack_entry(&store_opts, name.clone(), path, true)?;
+                            f = entry.file;
+                            if let Some(data) = data {
+                                let mut data = TimedReader::new(data);
+                                archive
+                                    .replace_streamed(&mut f, &mut data)
+                                    .context(writing_file)?;
+                                streamed_reading += data.elapsed;
+                            }
+                            stored_uncompressed += 1;
+                        }
+                    }
+                    None => archive.add(&f).context(writing)?,
+                }
+                compressing += streamed_reading;
+                writing_time += start.elapsed().saturating_sub(streamed_reading);
+                if !opts.quiet {
+                    eprintln!("Wrote: {} :: {:?}", f.name, f.inner);
+                }
+                total_size += f.size();
+                total_stored += f.inner.len;
+                written_files.push(Some((archive.files, f.inner)));
+                progress.file(&f.name, f.inner.uncompressed_len);
+                *written.0.lock().unwrap() = i + 1;
+                written.1.notify_all();
+            }
+            Ok::<_, MarkError>(())
+        })();
+        // Don't leave any worker waiting on a file that will never be written
+        *written.0.lock().unwrap() = files.len();
+        written.1.notify_all();
+        drop(receiver);
+        result
+    })?;
+    progress.finish();
+
+    if looked_compressed > 0 && !opts.quiet {
+        eprintln!("Stored {looked_compressed} already-compressed file(s) without compression");
+    }
+    if stored_uncompressed > 0 && !opts.quiet {
+        eprintln!(
+            "Stored {stored_uncompressed} file(s) without compression, since it wouldn't have made them smaller"
+        );
+    }
+    if opts.line_endings.is_some() && !opts.quiet {
+        eprintln!("Normalized the line endings of {normalized} text file(s)");
+    }
+    if opts.dedup && !opts.quiet {
+        eprintln!("Stored {deduplicated} file(s) as references to identical earlier ones");
+    }
+    if hard_linked > 0 && !opts.quiet {
+        eprintln!("Stored {hard_linked} hard link(s) to files packed before them");
+    }
+    if skipped > 0 {
+        eprintln!("Left out {skipped} file(s) that couldn't be read");
+    }
+    if opts.auto_compress && !opts.quiet {
+        let distribution = DataCompression::ALL
+            .iter()
+            .map(|&c| format!("{c:?}: {}", chosen_compression[c as usize]))
+            .collect::<Vec<_>>()
+            .join(", ");
+        eprintln!("Compression chosen per file: {distribution}");
+    }
+    if total_size > 0 && !opts.quiet {
+        eprintln!(
+            "Stored {total_size} bytes of files in {total_stored} bytes, {:.1}% of their size",
+            total_stored as f64 * 100.0 / total_size as f64
+        );
+    }
+    if opts.stats {
+        let throughput = |bytes: u64, time: Duration| match time.as_secs_f64() {
+            secs if secs > 0.0 => format!(", {:.1} MB/s", bytes as f64 / secs / 1e6),
+            _ => String::new(),
+        };
+        eprintln!(
+            "Walking:     {:.3}s, {} file(s) found",
+            walking.as_secs_f64(),
+            files.len()
+        );
+        eprintln!(
+            "Compressing: {:.3}s, {total_size} bytes read{}",
+            compressing.as_secs_f64(),
+            throughput(total_size, compressing)
+        );
+        eprintln!(
+            "Writing:     {:.3}s, {total_stored} bytes written{}",
+            writing_time.as_secs_f64(),
+            throughput(total_stored, writing_time)
+        );
+    }
+    Ok(())
+}
+
+/// The error for a file that a worker stopped partway through compressing.
+fn worker_failed(path: &std::path::Path) -> MarkError {
+    MarkError::Io {
+        context: Some(format!("Failed to compress \"{}\"", path.display())),
+        source: io::Error::other("the thread compressing it stopped"),
+    }
+}
+
+/// Finds the regular files among `files` that are hard links to an earlier one, giving the index
+/// of the first link to the same file for each.
+fn find_hard_links(
+    opts: &Opts,
+    files: &[(std::ffi::OsString, std::path::PathBuf, bool)],
+) -> Result<Vec<Option<usize>>, MarkError> {
+    let mut first_with_inode = std::collections::HashMap::new();
+    let mut links = vec![];
+    for (i, (_, path, _)) in files.iter().enumerate() {
+        let metadata = match opts.metadata(path) {
+            Ok(metadata) => metadata,
+            // It's left out when it's packed
+            Err(_) if opts.skip_errors => {
+                links.push(None);
+                continue;
+            }
+            Err(e) => Err(e).context(|| format!("Failed to read \"{}\"", path.display()))?,
+        };
+        let identity = platform::identity(&metadata);
+        let Some((inode, _)) = identity.filter(|(_, links)| metadata.is_file() && *links >= 2)
+        else {
+            links.push(None);
+            continue;
+        };
+        let first = *first_with_inode.entry(inode).or_insert(i);
+        links.push((first != i).then_some(first));
+    }
+    Ok(links)
+}
+
+/// Finds the regular files among `files` with the same contents as an earlier one, giving the
+/// index of the first with those contents for each. This reads every file in full, except for
+/// those in `hard_links`.
+fn find_duplicates(
+    opts: &Opts,
+    files: &[(std::ffi::OsString, std::path::PathBuf, bool)],
+    hard_links: &[Option<usize>],
+) -> Result<Vec<Option<usize>>, MarkError> {
+    use sha2::Digest;
+
+    let mut first_with_hash = std::collections::HashMap::new();
+    let mut links = vec![];
+    for (i, (name, path, _)) in files.iter().enumerate() {
+        let hash = || {
+            let metadata = opts.metadata(path)?;
+            if !metadata.is_file() || metadata.len() == 0 || hard_links[i].is_some() {
+                return Ok(None);
+            }
+            let mut file = std::fs::File::open(path)?;
+            let mut sample = vec![];
+            (&mut file)
+                .take(SAMPLE_SIZE as u64)
+                .read_to_end(&mut sample)?;
+            let mut hasher = sha2::Sha256::new();
+            hasher.update(&sample);
+            io::copy(&mut file, &mut hasher)?;
+            // Whether the line endings of a text file are converted depends on its name too
+            let text = opts.line_endings.is_some() && is_text(std::path::Path::new(name), &sample);
+            let hash: [u8; 32] = hasher.finalize().into();
+            Ok(Some((text, hash)))
+        };
+        let hash = match hash() {
+            Ok(hash) => hash,
+            // It's left out when it's packed
+            Err(_) if opts.skip_errors => None,
+            Err(e) => Err(e).context(|| format!("Failed to read \"{}\"", path.display()))?,
+        };
+        let Some(hash) = hash else {
+            links.push(None);
+            continue;
+        };
+        let first = *first_with_hash.entry(hash).or_insert(i);
+        links.push((first != i).then_some(first));
+    }
+    Ok(links)
+}
+
+/// Files at least this large are compressed straight into the archive rather than by a worker,
+/// which would have to hold all of their compressed data. An archive that can't be seeked in gets
+/// them in frames.
+const PARALLEL_MAX_LEN: u64 = 4 * 1024 * 1024;
+
+/// A file read and compressed for the archive, along with what was decided about it.
+struct PackedEntry {
+    file: FileHeaderRepr,
+    /// It looked already compressed, so it's stored as it is without trying to compress it
+    looked_compressed: bool,
+    /// Compressing it made it bigger, so it's stored as it is
+    stored_uncompressed: bool,
+    /// Its line endings were converted
+    normalized: bool,
+    /// The compression method `-auto-compress` picked for it
+    chosen_compression: Option<DataCompression>,
+}
+
+/// Filesystems allocate space in blocks of about this size, so holes are at least this long.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// Finds the blocks of `file` that are all zeros, which `-sparse` leaves out as holes, returning
+/// `None` if there aren't any. The file is left at its start.
+fn find_holes(file: &mut std::fs::File) -> io::Result<Option<SparseMap>> {
+    use io::Seek;
+
+    let mut segments: Vec<(u64, u64)> = vec![];
+    let mut block = vec![0u8; SPARSE_BLOCK_SIZE];
+    let mut reader = BufReader::new(&mut *file);
+    let mut offset = 0;
+    let mut holes = false;
+    loop {
+        let n = read_up_to(&mut reader, &mut block)?;
+        if n == 0 {
+            break;
+        }
+        // A partial block at the end is kept as data, it's too short to be a hole
+        if n == block.len() && block.iter().all(|&b| b == 0) {
+            holes = true;
+        } else {
+            match segments.last_mut() {
+                Some((start, len)) if *start + *len == offset => *len += n as u64,
+                _ => segments.push((offset, n as u64)),
+            }
+        }
+        offset += n as u64;
+    }
+    file.seek(io::SeekFrom::Start(0))?;
+    Ok(holes.then_some(SparseMap {
+        size: offset,
+        segments,
+    }))
+}
+
+/// Reads the file at `path` and compresses it to be stored as `name`. With `stream`, regular
+/// files are left to be compressed as they're written, through the returned reader.
+fn pack_entry(
+    opts: &Opts,
+    name: std::ffi::OsString,
+    path: &std::path::Path,
+    stream: bool,
+) -> Result<(PackedEntry, Option<Box<dyn Read>>), MarkError> {
+    let reading = || format!("Failed to read \"{}\"", path.display());
+    let mut buf = vec![];
+    let metadata = opts.metadata(path).context(reading)?;
+    let (mut looked_compressed, mut stored_uncompressed) = (false, false);
+    let mut normalized = false;
+    let mut chosen_compression = None;
+    // The data to compress into the archive as it's written, when streaming
+    let mut data_stream = None;
+    let mut sparse = None;
+    let mut blocks = false;
+    let (compression_method, uncompressed_size, compressed_size) = if metadata.is_dir() {
+        (DataCompression::None, 0, 0)
+    } else if metadata.is_symlink() {
+        // A symlink's data is its target, stored verbatim
+        buf = std::fs::read_link(path)
+            .context(reading)?
+            .into_os_string()
+            .into_encoded_bytes();
+        (DataCompression::None, buf.len() as u64, buf.len())
+    } else {
+        let mut uncompressed_size = metadata.len();
+        let mut file = std::fs::File::open(path).context(reading)?;
+        let holes = match opts.sparse {
+            true => find_holes(&mut file).context(reading)?,
+            false => None,
+        };
+
+        // Peek at the first block so that already-compressed files aren't run through the
+        // compressor again, and so text files can be recognized. The sample is chained back in
+        // front of the rest of the file.
+        let mut sample = vec![];
+        let mut compression_method = compression_for(opts, std::path::Path::new(&name));
+        let detect_compressed = compression_method != DataCompression::None
+            && !opts.force_compress
+            && !opts.auto_compress;
+        let sample_size = if opts.auto_compress {
+            AUTO_COMPRESS_SAMPLE_SIZE
+        } else if detect_compressed || opts.line_endings.is_some() {
+            SAMPLE_SIZE
+        } else {
+            0
+        };
+        (&mut file)
+            .take(sample_size as u64)
+            .read_to_end(&mut sample)
+            .context(reading)?;
+        let first_block = &sample[..sample.len().min(SAMPLE_SIZE)];
+        if detect_compressed && looks_compressed(std::path::Path::new(&name), first_block) {
+            compression_method = DataCompression::None;
+            looked_compressed = true;
+        }
+        if opts.auto_compress {
+            compression_method = best_compression(opts, &sample).context(reading)?;
+            chosen_compression = Some(compression_method);
+        }
+        let text = opts.line_endings.is_some() && is_text(std::path::Path::new(&name), first_block);
+        // Converting line endings would move the data out from under the sparse map, so text
+        // files keep their holes as zeros
+        let mut data: Box<dyn Read> = match holes {
+            Some(map) if !text => {
+                uncompressed_size = map.data_len();
+                let segments = map.segments.clone();
+                sparse = Some(map);
+                Box::new(SegmentsReader::new(file, segments))
+            }
+            _ => Box::new(io::Cursor::new(sample).chain(file)),
+        };
+
+        if let (Some(line_endings), true) = (opts.line_endings, text) {
+            let mut contents = vec![];
+            data.read_to_end(&mut contents).context(reading)?;
+            let converted = convert_line_endings(&contents, line_endings.crlf_on_pack());
+            normalized = converted != contents;
+            uncompressed_size = converted.len() as u64;
+            data = Box::new(io::Cursor::new(converted));
+        }
+
+        let level = level_for(opts, std::path::Path::new(&name));
+        blocks = compression_method != DataCompression::None
+            && opts
+                .block_size
+                .is_some_and(|size| uncompressed_size > size as u64);
+        let compressed_size = if stream {
+            let data = match blocks {
+                true => block_compressor(opts, compression_method, level, data),
+                false => compressor(opts, compression_method, level, data),
+            };
+            data_stream = Some(data.context(reading)?);
+            0
+        } else {
+            let mut contents = vec![];
+            data.read_to_end(&mut contents).context(reading)?;
+            match blocks {
+                true => block_compressor(opts, compression_method, level, Box::new(&contents[..]))
+                    .and_then(|mut data| data.read_to_end(&mut buf)),
+                false => compress(
+                    opts,
+                    compression_method,
+                    level,
+                    &mut &contents[..],
+                    &mut buf,
+                ),
+            }
+            .context(reading)?;
+            if compression_method != DataCompression::None
+                && !opts.no_auto_store
+                && buf.len() > contents.len()
+            {
+                // Compressing it made it bigger
+                compression_method = DataCompression::None;
+                stored_uncompressed = true;
+                blocks = false;
+                buf = contents;
+            }
+            buf.len()
+        };
+        (compression_method, uncompressed_size, compressed_size)
+    };
+    let data_compression = match blocks {
+        true => compression_method as u8 | BLOCKS_DATA,
+        false => compression_method as u8,
+    };
+    // The data is encrypted after it's compressed, since encrypted data doesn't compress
+    #[cfg(feature = "encryption")]
+    let (data_compression, compressed_size) = match &opts.cipher {
+        Some(cipher) if !metadata.is_dir() => {
+            let compressed_size = match data_stream.take() {
+                Some(data) => {
+                    data_stream = Some(cipher.encryptor(name.as_encoded_bytes(), data)?);
+                    compressed_size
+                }
+                None => {
+                    let data = Box::new(io::Cursor::new(std::mem::take(&mut buf)));
+                    cipher
+                        .encryptor(name.as_encoded_bytes(), data)?
+                        .read_to_end(&mut buf)
+                        .context(reading)?;
+                    buf.len()
+                }
+            };
+            (data_compression | ENCRYPTED_DATA, compressed_size)
+        }
+        _ => (data_compression, compressed_size),
+    };
+    // A timestamp that can't be read (or is before 1970) is stored as 0 rather than failing the
+    // whole archive
+    let since_epoch = |time: io::Result<SystemTime>, which: &str| {
+        time.and_then(|time| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(io::Error::other)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "Warning: couldn't get the {which} time of \"{}\", storing 0 instead: {e}",
+                path.display()
+            );
+            Duration::ZERO
+        })
+    };
+    let modified = since_epoch(metadata.modified(), "modification");
+    let accessed = since_epoch(metadata.accessed(), "access");
+    let mode = platform::mode(&metadata);
+    let (uid, gid) = platform::owner(&metadata);
+    let mut file = FileHeaderRepr::new(
+        FileHeader {
+            modified: modified.as_secs(),
+            accessed: accessed.as_secs(),
+            mode,
+            uid,
+            gid,
+            data_compression,
+            uncompressed_len: uncompressed_size,
+            len: compressed_size as u64,
+            ..Default::default()
+        },
+        name.into_encoded_bytes(),
+        buf,
+    );
+    file.sparse = sparse;
+    if opts.subsec_times {
+        file.modified_nanos = modified.subsec_nanos();
+        file.accessed_nanos = accessed.subsec_nanos();
+    }
+    if let Some(epoch) = opts.reproducible {
+        // Of the mode, only the file type and whether it's executable are kept
+        let permissions = if metadata.is_symlink() {
+            0o777
+        } else if metadata.is_dir() || mode & 0o111 != 0 {
+            0o755
+        } else {
+            0o644
+        };
+        let header = &mut file.inner;
+        header.mode = header.mode & S_IFMT | permissions;
+        (header.uid, header.gid) = (0, 0);
+        header.modified = header.modified.min(epoch);
+        header.accessed = header.modified;
+        (file.modified_nanos, file.accessed_nanos) = (0, 0);
+    }
+    let entry = PackedEntry {
+        file,
+        looked_compressed,
+        stored_uncompressed,
+        normalized,
+        chosen_compression,
+    };
+    Ok((entry, data_stream))
+}
+
+/// Compresses the `contents` of a file to be stored as `path`, deciding how like [`pack_entry`]
+/// does. Returns the compression method used, which is none if compressing wouldn't help.
+pub(crate) fn compress_contents(
+    opts: &Opts,
+    path: &std::path::Path,
+    contents: Vec<u8>,
+) -> io::Result<(DataCompression, Vec<u8>)> {
+    let sample = &contents[..contents.len().min(SAMPLE_SIZE)];
+    let mut compression = compression_for(opts, path);
+    if opts.auto_compress {
+        let sample = &contents[..contents.len().min(AUTO_COMPRESS_SAMPLE_SIZE)];
+        compression = best_compression(opts, sample)?;
+    } else if !opts.force_compress && looks_compressed(path, sample) {
+        compression = DataCompression::None;
+    }
+    let mut data = vec![];
+    compress(
+        opts,
+        compression,
+        level_for(opts, path),
+        &mut &contents[..],
+        &mut data,
+    )?;
+    if !opts.no_auto_store && data.len() > contents.len() {
+        return Ok((DataCompression::None, contents));
+    }
+    Ok((compression, data))
+}
+
+/// Packs everything read from stdin as a single regular file named `name`, for `-name`.
+fn write_stdin<W: ArchiveOutput>(
+    opts: &Opts,
+    archive: &mut RawArchiveWriter<W>,
+    name: &str,
+) -> Result<(), MarkError> {
+    let reading = || "Failed to read stdin".to_string();
+    // The contents and their compressed data are both in memory at once
+    let limit = opts.max_memory.map_or(u64::MAX, |max| max / 2);
+    let mut contents = vec![];
+    io::stdin()
+        .lock()
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut contents)
+        .context(reading)?;
+    if contents.len() as u64 > limit {
+        return Err(MarkError::Usage(format!(
+            "Packing stdin needs more than the -max-memory limit of {} bytes of memory",
+            opts.max_memory.unwrap_or_default()
+        )));
+    }
+    let file =
+        new_file(opts, name, contents).context(|| format!("Failed to pack stdin as \"{name}\""))?;
+    archive
+        .add(&file)
+        .context(|| "Failed to write the archive".into())?;
+    if !opts.quiet {
+        eprintln!("Wrote: {} :: {:?}", file.name, file.inner);
+    }
+    Ok(())
+}
@@ -0,0 +1,644 @@
+//! Reading archives file by file, see [`ArchiveReader`].
+
+use super::*;
+
+/// Where an archive is read from. Archives that can be seeked in can be read out of order, see
+/// [`ArchiveReader::seek_to`].
+pub(crate) enum ArchiveInput<'a> {
+    Stream(&'a mut dyn Read),
+    Seekable(&'a mut dyn ReadSeek),
+}
+
+impl Read for ArchiveInput<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Stream(reader) => reader.read(buf),
+            Self::Seekable(reader) => reader.read(buf),
+        }
+    }
+}
+
+pub(crate) trait ReadSeek: Read + io::Seek {}
+
+impl<T: Read + io::Seek> ReadSeek for T {}
+
+/// Reads the files of an archive one at a time. Other programs can go through them by iterating
+/// over it.
+pub struct ArchiveReader<'a> {
+    pub(crate) reader: ArchiveInput<'a>,
+    pub(crate) header: ArchiveHeader,
+    pub(crate) layout: &'static Layout,
+    /// The dictionary that follows the header, if it has [`DICTIONARY`].
+    pub(crate) dictionary: Option<Vec<u8>>,
+    /// How many files have been read so far.
+    pub(crate) read: u32,
+    pub(crate) done: bool,
+    /// Whether the archive ended the way its header says it should, once `done`.
+    pub(crate) ended_cleanly: bool,
+    /// How much of the last file's data is left to read, see [`ArchiveReader::next_header`].
+    /// For data in frames, that's what's left of the current frame.
+    unread: u64,
+    /// Whether the last file's data is in frames that haven't all been read, see
+    /// [`FRAMED_DATA`].
+    pub(crate) framed: bool,
+    /// The CRC32 after the frames of the last file's data, once all of them have been read.
+    pub(crate) frames_crc: Option<u32>,
+    /// How long the frames of the last file's data have said it is so far.
+    frames_len: u64,
+    /// The [`ReadOptions::max_member_size`] the last file was read with, which its frames are
+    /// held to as they're read.
+    pub(crate) max_member_size: Option<u64>,
+    /// The CRC32 of the last file's data read so far.
+    pub(crate) crc: crc32fast::Hasher,
+    /// What was wrong with the archive that didn't keep it from being read, see
+    /// [`ArchiveReader::warnings`].
+    warnings: Vec<String>,
+    /// Where each of the `warnings` is also reported as it's found, for the `mark` command.
+    pub(crate) report: Option<fn(&str)>,
+    /// The key the contents of encrypted files are decrypted with, see
+    /// [`ArchiveReader::unlock`].
+    #[cfg(feature = "encryption")]
+    cipher: Option<encryption::Cipher>,
+}
+
+impl<'a> ArchiveReader<'a> {
+    /// Reads the archive header from `reader`.
+    pub fn new(reader: &'a mut dyn Read) -> io::Result<Self> {
+        Self::from_input(ArchiveInput::Stream(reader))
+    }
+
+    /// Like [`ArchiveReader::new`], for an archive that can be read out of order.
+    pub(crate) fn new_seekable(reader: &'a mut dyn ReadSeek) -> io::Result<Self> {
+        Self::from_input(ArchiveInput::Seekable(reader))
+    }
+
+    fn from_input(mut reader: ArchiveInput<'a>) -> io::Result<Self> {
+        let header = ArchiveHeader::read(&mut reader)?;
+        let dictionary = match header.has_dictionary() {
+            true => Some(read_dictionary(&mut reader)?),
+            false => None,
+        };
+        Ok(Self {
+            reader,
+            header,
+            dictionary,
+            layout: Layout::for_version(header.version),
+            read: 0,
+            done: false,
+            ended_cleanly: false,
+            unread: 0,
+            framed: false,
+            frames_crc: None,
+            frames_len: 0,
+            max_member_size: None,
+            crc: crc32fast::Hasher::new(),
+            warnings: vec![],
+            report: None,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        })
+    }
+
+    /// Decrypts the contents of the files in an archive packed with `-passphrase`, with the key
+    /// derived from the same `passphrase`. Fails if it's the wrong one, or if the archive isn't
+    /// encrypted.
+    #[cfg(feature = "encryption")]
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), MarkError> {
+        let Some(encryption) = self.header.encryption else {
+            return Err(MarkError::Usage("The archive isn't encrypted".into()));
+        };
+        self.cipher = Some(cipher_for(passphrase, encryption)?);
+        Ok(())
+    }
+
+    /// What was wrong with the archive that didn't keep it from being read so far, like a
+    /// missing end-of-archive marker in an archive that may have been cut short.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    fn warn(&mut self, warning: String) {
+        if let Some(report) = self.report {
+            report(&warning);
+        }
+        self.warnings.push(warning);
+    }
+
+    /// Reads the next file in the archive, or returns `None` once all of them have been read.
+    pub(crate) fn next_file(&mut self, options: ReadOptions) -> io::Result<Option<FileHeaderRepr>> {
+        match self.next_header(options)? {
+            Some(file) => self.with_data(file, options).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the data of `file`, the file last returned by [`ArchiveReader::next_header`], into
+    /// it.
+    pub(crate) fn with_data(
+        &mut self,
+        mut file: FileHeaderRepr,
+        options: ReadOptions,
+    ) -> io::Result<FileHeaderRepr> {
+        let options = ReadOptions {
+            skip_data: options.skip_data || !options.wants(&file.name),
+            ..options
+        };
+        if self.framed {
+            let len = match options.skip_data {
+                true => io::copy(&mut self.data(), &mut io::sink())?,
+                false => {
+                    file.data = FileHeaderRepr::read_data(
+                        &mut self.data(),
+                        &file.inner,
+                        &file.name,
+                        options,
+                    )?;
+                    file.data.len() as u64
+                }
+            };
+            // What the header couldn't say is filled in from the frames, as if the data had been
+            // written without them
+            file.inner.len = len;
+            file.inner.crc32 = self.frames_crc.take().unwrap_or_default();
+            file.inner.data_compression &= !FRAMED_DATA;
+        } else if self.unread > 0 {
+            file.data =
+                FileHeaderRepr::read_data(&mut self.reader, &file.inner, &file.name, options)?;
+            self.unread = 0;
+        }
+        Ok(file)
+    }
+
+    /// Reads the archive's [`ArchiveIndex`], if it has one and can be seeked in, without
+    /// moving on from where it is in the archive. If the index can't be read, that's only a
+    /// warning, since the archive can still be read from start to end.
+    pub(crate) fn index(&mut self) -> io::Result<Option<ArchiveIndex>> {
+        let ArchiveInput::Seekable(reader) = &mut self.reader else {
+            return Ok(None);
+        };
+        if !self.header.is_indexed() {
+            return Ok(None);
+        }
+        let position = reader.stream_position()?;
+        let index = ArchiveIndex::find(*reader, self.header);
+        reader.seek(io::SeekFrom::Start(position))?;
+        match index {
+            Ok(index) => Ok(Some(index)),
+            Err(e) => {
+                self.warn(format!(
+                    "the archive's index can't be read, reading all of the archive instead: {e}"
+                ));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Goes to the `index`th file of the archive (counting from 0), which starts at `offset`.
+    /// It's read by the next call to [`ArchiveReader::next_header`].
+    pub(crate) fn seek_to(&mut self, offset: u64, index: u32) -> io::Result<()> {
+        let ArchiveInput::Seekable(reader) = &mut self.reader else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the archive can't be seeked in",
+            ));
+        };
+        reader.seek(io::SeekFrom::Start(offset))?;
+        self.read = index;
+        self.unread = 0;
+        self.framed = false;
+        self.frames_crc = None;
+        self.done = false;
+        self.crc = crc32fast::Hasher::new();
+        Ok(())
+    }
+
+    /// Like [`ArchiveReader::next_header`], but goes straight to the next of the files `jumps`
+    /// gives from the archive's index, along with their position in it. Returns `None` once
+    /// `jumps` runs out.
+    pub(crate) fn next_indexed_header(
+        &mut self,
+        jumps: &mut dyn Iterator<Item = (usize, IndexEntry)>,
+        options: ReadOptions,
+    ) -> io::Result<Option<FileHeaderRepr>> {
+        let Some((i, entry)) = jumps.next() else {
+            return Ok(None);
+        };
+        self.seek_to(entry.offset, i as u32)?;
+        match self.next_header(options)? {
+            Some(file) if file.name_bytes() == entry.name => Ok(Some(file)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the archive's index doesn't match its files",
+            )),
+        }
+    }
+
+    /// Like [`ArchiveReader::next_file`], but leaves the file's data to be read from
+    /// [`ArchiveReader::data`] instead of reading it into memory. Any of it that isn't read is
+    /// skipped over by the next call.
+    ///
+    /// Layouts that store the name after the data still read it into memory.
+    pub(crate) fn next_header(
+        &mut self,
+        options: ReadOptions,
+    ) -> io::Result<Option<FileHeaderRepr>> {
+        let truncated = || {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the archive ends partway through the data of this file, it may be truncated",
+            )
+        };
+        loop {
+            let skipped = io::copy(&mut (&mut self.reader).take(self.unread), &mut io::sink())?;
+            if skipped < self.unread {
+                return Err(truncated());
+            }
+            self.unread = 0;
+            if !self.framed {
+                break;
+            }
+            self.next_frame().map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => truncated(),
+                _ => e,
+            })?;
+        }
+        self.frames_crc = None;
+        self.frames_len = 0;
+        self.max_member_size = options.max_member_size;
+        self.crc = crc32fast::Hasher::new();
+        if self.done {
+            return Ok(None);
+        }
+        let streamed = self.header.is_streamed();
+        if !streamed && self.read == self.header.file_count {
+            self.done = true;
+            match check_end_of_archive(&mut self.reader, self.header)? {
+                Some(problem) => self.warn(problem.into()),
+                None => self.ended_cleanly = true,
+            }
+            return Ok(None);
+        }
+
+        // Records other than files are told apart by a tag in place of the next file header.
+        // Without a count, that's also the only way to find the end of the archive.
+        let mut tag = [0u8; 8];
+        let n = read_up_to(&mut self.reader, &mut tag)?;
+        if !streamed && n == 0 {
+            self.read += 1;
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "the archive ends after {} of its {} files, it may be truncated",
+                    self.read - 1,
+                    self.header.file_count
+                ),
+            ));
+        }
+        if streamed && n == 0 {
+            self.done = true;
+            self.warn(MISSING_END_OF_ARCHIVE.into());
+            return Ok(None);
+        }
+        if streamed && tag == END_OF_ARCHIVE {
+            self.done = true;
+            self.ended_cleanly = true;
+            return Ok(None);
+        }
+        self.read += 1;
+        if self.header.version >= EXTENDED_METADATA_VERSION && tag == EXTENDED_METADATA {
+            let extended = ExtendedMetadata::read(&mut self.reader, options.max_member_size)?;
+            let file = FileHeaderRepr::read_header_only(
+                &mut self.reader,
+                self.layout,
+                Some(extended),
+                options,
+            )?;
+            self.check_version(&file)?;
+            return Ok(Some(self.unread_data(file)));
+        }
+        // A file header is always longer than the tag, so nothing is left of it once the header
+        // has been read
+        let mut reader = io::Cursor::new(&tag[..n]).chain(&mut self.reader);
+        let file = FileHeaderRepr::read_header_only(&mut reader, self.layout, None, options)?;
+        self.check_version(&file)?;
+        Ok(Some(self.unread_data(file)))
+    }
+
+    /// Fails if `file` has something the archive's format version can't have.
+    fn check_version(&self, file: &FileHeaderRepr) -> io::Result<()> {
+        let version = self.header.version;
+        let what = if file.sparse.is_some() && version < SPARSE_VERSION {
+            "sparse"
+        } else if file.inner.has_blocks() && version < BLOCKS_VERSION {
+            "compressed in blocks"
+        } else if file.inner.is_framed() && version < FRAMES_VERSION {
+            "written in frames"
+        } else {
+            return Ok(());
+        };
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "\"{}\" is {what}, but files in version {version} archives can't be",
+                file.name
+            ),
+        ))
+    }
+
+    /// Records that the data of `file` is next in the archive, unless it was read already.
+    fn unread_data(&mut self, file: FileHeaderRepr) -> FileHeaderRepr {
+        if self.layout.name_before_data {
+            self.framed = file.inner.is_framed();
+            self.unread = match self.framed {
+                true => 0,
+                false => file.inner.len,
+            };
+        }
+        file
+    }
+
+    /// Moves on to the next frame of the last file's data, or past the end of them.
+    fn next_frame(&mut self) -> io::Result<()> {
+        self.unread = self.reader.read_u32::<LittleEndian>()?.into();
+        self.frames_len = self.frames_len.saturating_add(self.unread);
+        if let Some(max) = self.max_member_size.filter(|&max| self.frames_len > max) {
+            return Err(beyond_max_member_size(self.frames_len, max));
+        }
+        if self.unread == 0 {
+            self.frames_crc = Some(self.reader.read_u32::<LittleEndian>()?);
+            self.framed = false;
+        }
+        Ok(())
+    }
+
+    /// Reads the data of the file last returned by [`ArchiveReader::next_header`].
+    pub(crate) fn data(&mut self) -> FileData<'_, 'a> {
+        FileData { archive: self }
+    }
+
+    /// Skips whatever is left of the data of `file`, the file last returned by
+    /// [`ArchiveReader::next_header`], and checks that all of it matches its CRC32.
+    pub(crate) fn finish_data(&mut self, file: &FileHeaderRepr) -> io::Result<bool> {
+        io::copy(&mut self.data(), &mut io::sink())?;
+        let crc = std::mem::replace(&mut self.crc, crc32fast::Hasher::new()).finalize();
+        let expected = self.frames_crc.take().unwrap_or(file.inner.crc32);
+        Ok(!self.layout.fields.contains(&Field::Crc32) || crc == expected)
+    }
+}
+
+impl ArchiveReader<'_> {
+    /// Where the archive is being read, if it can be seeked in.
+    pub(crate) fn position(&mut self) -> io::Result<Option<u64>> {
+        match &mut self.reader {
+            ArchiveInput::Seekable(reader) => reader.stream_position().map(Some),
+            ArchiveInput::Stream(_) => Ok(None),
+        }
+    }
+
+    /// Goes back to the `target`th file (counting from 0), whose data the file `name` shares,
+    /// and reads its header, leaving its data to be read. It's found through `index` if the
+    /// archive has one, and otherwise by reading the archive again from `start`, where its first
+    /// file is.
+    pub(crate) fn go_back_to(
+        &mut self,
+        target: usize,
+        index: Option<&ArchiveIndex>,
+        start: u64,
+        name: &str,
+        options: ReadOptions,
+    ) -> Result<FileHeaderRepr, MarkError> {
+        let reading = || "Failed to read a file from the archive".to_string();
+        let (offset, i) = match index {
+            Some(index) => match index.entries.get(target) {
+                Some(entry) => (entry.offset, target as u32),
+                None => {
+                    return Err(MarkError::Invalid(
+                        "The archive's index doesn't match its files".into(),
+                    ))
+                }
+            },
+            None => (start, 0),
+        };
+        self.seek_to(offset, i).context(reading)?;
+        loop {
+            match self.next_header(options).context(reading)? {
+                Some(earlier) if self.read as usize > target => return Ok(earlier),
+                Some(_) => {}
+                None => {
+                    return Err(MarkError::Invalid(format!(
+                        "\"{name}\" shares its data with a file that isn't in the archive"
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Reads the next file's header for [`ArchiveReader::next`].
+    pub(crate) fn next_member(&mut self) -> Result<Option<Member>, MarkError> {
+        let file = match self.next_header(ReadOptions::default()) {
+            Ok(Some(file)) => file,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                // Nothing after the error can be trusted, so it's the last item
+                self.done = true;
+                return Err(e).context(|| format!("Failed to read file {}", self.read.max(1)));
+            }
+        };
+        Ok(Some(Member {
+            name: file.name.clone(),
+            mode: file.inner.mode,
+            modified: file.modified_time(),
+            index: self.read as usize - 1,
+            link: file.inner.link(),
+            size: file.size(),
+            file,
+        }))
+    }
+
+    /// Reads the contents of `member` into memory, see [`ArchiveReader::copy_contents`].
+    pub fn contents(&mut self, member: &Member) -> Result<Vec<u8>, MarkError> {
+        let mut contents = vec![];
+        self.copy_contents(member, &mut contents)?;
+        Ok(contents)
+    }
+
+    /// Decompresses the contents of `member` to `writer`, returning how long they are. Only the
+    /// last member read can be, and only once, since the rest of the archive comes after it. A
+    /// member that shares the contents of an earlier one has none of its own to read.
+    pub fn copy_contents(
+        &mut self,
+        member: &Member,
+        writer: &mut dyn Write,
+    ) -> Result<u64, MarkError> {
+        let file = &member.file;
+        if member.index + 1 != self.read as usize {
+            return Err(MarkError::Usage(format!(
+                "The contents of \"{}\" can't be read anymore, only those of the last file read",
+                file.name
+            )));
+        }
+        let reading = || format!("Failed to decompress \"{}\"", file.name);
+        if member.link.is_some() {
+            return Ok(0);
+        }
+        // The dictionary and key are lent to the options rather than copied for every file
+        let opts = Opts {
+            dictionary: self.dictionary.take(),
+            #[cfg(feature = "encryption")]
+            cipher: self.cipher.take(),
+            ..Default::default()
+        };
+        let copied = decrypted(&opts, file, self.data())
+            .and_then(|data| decompressor(&opts, &file.inner, data))
+            .and_then(|data| match &file.sparse {
+                Some(sparse) => io::copy(&mut Unsparse::new(data, sparse), writer),
+                None => io::copy(&mut { data }, writer),
+            });
+        self.dictionary = opts.dictionary;
+        #[cfg(feature = "encryption")]
+        {
+            self.cipher = opts.cipher;
+        }
+        let copied = copied.context(reading)?;
+        if !self.finish_data(file).context(reading)? {
+            return Err(MarkError::Corrupt(format!(
+                "The checksum of \"{}\" doesn't match",
+                file.name
+            )));
+        }
+        Ok(copied)
+    }
+}
+
+/// Lets other programs go through the files of an archive, with [`ArchiveReader::contents`]
+/// reading those they want. Whatever isn't read of a file is skipped on the way to the next.
+impl Iterator for ArchiveReader<'_> {
+    type Item = Result<Member, MarkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_member().transpose()
+    }
+}
+
+/// A file read by [`ArchiveReader`], without its contents.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    /// The file type and permissions, like `st_mode`.
+    pub mode: u32,
+    pub modified: SystemTime,
+    /// Where it is in the archive, counting from 0.
+    pub index: usize,
+    /// The index of the earlier file whose contents this one shares, if it was packed with
+    /// `-dedup` or is a hard link. Its own contents are empty.
+    pub link: Option<usize>,
+    /// The length of its contents, which for a symlink are its target.
+    pub size: u64,
+    pub(crate) file: FileHeaderRepr,
+}
+
+impl Member {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+/// The data of a file in an archive, see [`ArchiveReader::data`].
+pub(crate) struct FileData<'r, 'a> {
+    pub(crate) archive: &'r mut ArchiveReader<'a>,
+}
+
+impl Read for FileData<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let archive = &mut *self.archive;
+        while archive.unread == 0 && archive.framed {
+            archive.next_frame()?;
+        }
+        let len = buf
+            .len()
+            .min(archive.unread.try_into().unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = archive.reader.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        archive.crc.update(&buf[..n]);
+        archive.unread -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Like [`Read::read_exact`], but stops early at the end of the stream instead of failing.
+/// Returns how much of `buf` was filled.
+pub(crate) fn read_up_to(reader: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// The warning for an archive that ends before its [`END_OF_ARCHIVE`].
+const MISSING_END_OF_ARCHIVE: &str =
+    "the end-of-archive marker is missing, the archive may be truncated";
+
+/// Checks that an archive that should end with [`END_OF_ARCHIVE`] does, since otherwise it was
+/// cut short even if all of the files it claims to contain were read. Returns what's wrong if it
+/// doesn't.
+fn check_end_of_archive(
+    reader: &mut dyn Read,
+    header: ArchiveHeader,
+) -> io::Result<Option<&'static str>> {
+    if header.version < END_OF_ARCHIVE_VERSION {
+        return Ok(None);
+    }
+    let mut marker = [0u8; END_OF_ARCHIVE.len()];
+    match reader.read_exact(&mut marker) {
+        Ok(()) if marker == END_OF_ARCHIVE => Ok(None),
+        Ok(()) => Ok(Some(
+            "expected the end-of-archive marker, found other data instead",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(Some(MISSING_END_OF_ARCHIVE)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Fails unless the archive, whose files have all been read, ends right after them: with the
+/// end-of-archive marker, its index if it has one, and nothing else.
+pub(crate) fn check_rest(archive: &mut ArchiveReader) -> Result<(), MarkError> {
+    if !archive.ended_cleanly {
+        return Err(MarkError::Invalid(format!(
+            "The archive doesn't end right after file {}",
+            archive.read
+        )));
+    }
+    if archive.header.is_indexed() {
+        ArchiveIndex::read(&mut archive.reader)
+            .context(|| "Failed to read the archive's index".into())?;
+    }
+    if archive.header.has_digest() {
+        read_digest(&mut archive.reader)
+            .context(|| "Failed to read the archive's digest".into())?;
+    }
+    let mut trailing = [0u8; 1];
+    if read_up_to(&mut archive.reader, &mut trailing)
+        .context(|| "Failed to read the archive".into())?
+        > 0
+    {
+        return Err(MarkError::Invalid(
+            "There's more data after the end of the archive".into(),
+        ));
+    }
+    Ok(())
+}
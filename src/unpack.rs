@@ -0,0 +1,729 @@
+//! Unpacking archives into a directory, without writing outside of it.
+
+use super::*;
+
+pub(crate) fn unpack(mut opts: Opts, names: &[String]) -> Result<(), MarkError> {
+    let mut input = open_archive(&opts)?;
+    let output_dir = match &opts.output {
+        Some(o) => std::path::PathBuf::from(o),
+        None => std::env::current_dir()?,
+    };
+
+    let (mut created, mut overwritten, mut untouched) = (0usize, 0usize, 0usize);
+    let (mut corrupt, mut unlinked, mut conflicting) = (0usize, 0usize, 0usize);
+    // Whether each name something was unpacked at, and every directory above it, is a
+    // directory, for the files after it named as if it were something else
+    let mut kinds = std::collections::HashMap::new();
+    // Where each regular file was unpacked to, by its index in the archive, for the files that
+    // share its data
+    let mut unpacked = std::collections::HashMap::new();
+    // Symlinks are only created once everything else has been written, so that nothing in the
+    // archive can be written through one. Until then, an empty file holds each one's place.
+    let mut symlinks: Vec<(std::path::PathBuf, std::path::PathBuf, FileHeader, bool)> = vec![];
+    let mut names_read = vec![];
+    let mut archive = input
+        .reader()
+        .context(|| "Failed to read the archive header".into())?;
+    unlock(&mut opts, archive.header, true)?;
+    opts.dictionary = archive.dictionary.clone();
+    // Older archives don't know who owned their files
+    let preserve_owner = opts.preserve_owner && archive.layout.fields.contains(&Field::Uid);
+    let mut owner_denied = false;
+    let total = if !names.is_empty() {
+        Some(names.len() as u64)
+    } else {
+        (!archive.header.is_streamed()).then_some(archive.header.file_count as u64)
+    };
+    let mut progress = Progress::new(&opts, total);
+    let options = ReadOptions {
+        skip_data: false,
+        require_utf8_names: opts.require_utf8_names,
+        max_data_len: opts.max_memory,
+        max_member_size: opts.max_member_size,
+        only: (!names.is_empty()).then_some(names),
+    };
+    let mut found = vec![false; names.len()];
+    let file_count = match archive.header.is_streamed() {
+        true => String::new(),
+        false => format!(" of {}", archive.header.file_count),
+    };
+    // Where the first file starts, to go back to for the data of a file that wasn't unpacked
+    let start = archive
+        .position()
+        .context(|| "Failed to read the archive".into())?;
+    // After going back, unpacking carries on from here
+    let mut resume = None;
+    // With an index, only the files asked for are read, unless all of the archive has to be
+    // checked or salvaged
+    let index = match names.is_empty() || opts.strict || opts.salvage {
+        true => None,
+        false => archive
+            .index()
+            .context(|| "Failed to read the archive's index".into())?,
+    };
+    let mut jumps = index.as_ref().map(|index| {
+        names_read = index
+            .entries
+            .iter()
+            .map(|x| String::from_utf8_lossy(&x.name).into_owned())
+            .collect();
+        index
+            .entries
+            .clone()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, x)| options.wants(&String::from_utf8_lossy(&x.name)))
+    });
+    // With -salvage, the archive ending early stops unpacking there. This is why, and how many
+    // whole files came before that.
+    let mut truncated = None;
+    let cut_short = |e: &MarkError| {
+        opts.salvage
+            && matches!(e, MarkError::Io { source, .. } if source.kind() == io::ErrorKind::UnexpectedEof)
+    };
+    loop {
+        if let Some((position, read)) = resume.take() {
+            archive
+                .seek_to(position, read)
+                .context(|| "Failed to read the archive".into())?;
+        }
+        let file = match &mut jumps {
+            Some(jumps) => archive.next_indexed_header(jumps, options),
+            None => archive.next_header(options),
+        };
+        let file = match file
+            .context(|| format!("Failed to read file {}{file_count}", archive.read.max(1)))
+        {
+            Err(e) if cut_short(&e) => {
+                truncated = Some((e, archive.read - 1));
+                break;
+            }
+            file => file?,
+        };
+        let Some(mut file) = file else {
+            break;
+        };
+        if jumps.is_none() {
+            names_read.push(file.name.clone());
+        }
+        if !options.wants(&file.name) {
+            continue;
+        }
+        if let Some(i) = names.iter().position(|x| *x == file.name) {
+            found[i] = true;
+        }
+        progress.file(&file.name, file.inner.uncompressed_len);
+        // The contents of regular files are decompressed straight from the archive as they're
+        // written. Everything else is small, or (to convert line endings) needs all of its data
+        // at once anyway, so it's read and checked up front.
+        let streamed =
+            !file.inner.is_dir() && !file.inner.is_symlink() && opts.line_endings.is_none();
+        if !streamed {
+            let reading = || format!("Failed to read \"{}\" from the archive", file.name);
+            let data =
+                FileHeaderRepr::read_data(&mut archive.data(), &file.inner, &file.name, options);
+            let intact = match data {
+                Ok(data) => {
+                    file.data = data;
+                    archive.finish_data(&file)
+                }
+                Err(e) => Err(e),
+            };
+            let intact = match intact.context(reading) {
+                Err(e) if cut_short(&e) => {
+                    truncated = Some((e, archive.read - 1));
+                    break;
+                }
+                intact => intact?,
+            };
+            if !intact {
+                eprintln!("Not writing \"{}\", its checksum doesn't match", file.name);
+                corrupt += 1;
+                continue;
+            }
+            // Files sharing the data of an earlier one have none of their own
+            if file.inner.is_encrypted() && file.inner.link().is_none() {
+                let mut data = vec![];
+                decrypted(&opts, &file, &file.data[..])
+                    .and_then(|mut x| x.read_to_end(&mut data))
+                    .context(reading)?;
+                file.data = data;
+            }
+        }
+        // The data of a file that wasn't unpacked is read from the archive again, going back to
+        // where it is
+        let mut go_back = None;
+        let source = match file.inner.link() {
+            Some(target) => match (unpacked.get(&target), start) {
+                (Some(source), _) => Some(std::path::PathBuf::clone(source)),
+                (None, Some(_)) => {
+                    go_back = Some(target);
+                    None
+                }
+                (None, None) => {
+                    let target = names_read.get(target).map_or("?", |x| x.as_str());
+                    eprintln!(
+                        "Not writing \"{}\", it shares its data with \"{target}\", which wasn't unpacked and can't be gone back to in an archive read from stdin or with -decode",
+                        file.name
+                    );
+                    unlinked += 1;
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let Some(member) = sanitize_member_name(file.path()) else {
+            eprintln!(
+                "Not writing \"{}\", it would end up outside of the output directory",
+                file.name
+            );
+            continue;
+        };
+        let member: std::path::PathBuf = member.components().skip(opts.strip_components).collect();
+        if member.as_os_str().is_empty() {
+            if !opts.quiet {
+                eprintln!(
+                    "Skipping \"{}\", nothing is left of its name after -strip-components",
+                    file.name
+                );
+            }
+            continue;
+        }
+        // Only a directory can be named like one
+        if !file.inner.is_dir() && file.name.ends_with('/') {
+            eprintln!(
+                "Not writing \"{}\", its name ends with a \"/\" but it isn't a directory",
+                file.name
+            );
+            conflicting += 1;
+            continue;
+        }
+        match kinds.get(&member) {
+            Some(true) if !file.inner.is_dir() => {
+                eprintln!(
+                    "Not writing \"{}\", it's a directory of other files in the archive",
+                    file.name
+                );
+                conflicting += 1;
+                continue;
+            }
+            Some(false) if file.inner.is_dir() => {
+                eprintln!(
+                    "Not creating the directory \"{}\", a file in the archive was written there",
+                    file.name
+                );
+                conflicting += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let file_path = output_dir.join(&member);
+        if let Some(blocking) = non_directory_above(&output_dir, &member) {
+            if blocking.is_symlink() {
+                eprintln!(
+                    "Not writing \"{}\", \"{}\" is a symbolic link and it isn't written through one",
+                    file.name,
+                    blocking.display()
+                );
+            } else {
+                eprintln!(
+                    "Not writing \"{}\", \"{}\" is in the way and isn't a directory",
+                    file.name,
+                    blocking.display()
+                );
+            }
+            conflicting += 1;
+            continue;
+        }
+        let existing = file_path.symlink_metadata().ok();
+        if file.inner.is_dir() && existing.as_ref().is_some_and(|x| x.is_dir()) {
+            kinds.insert(member, true);
+            continue;
+        }
+        if let Some(existing) = &existing {
+            if !opts.on_conflict.replaces(existing, &file.inner) {
+                if !opts.quiet {
+                    eprintln!("Not overwriting \"{}\"!", file_path.display());
+                }
+                untouched += 1;
+                continue;
+            }
+            if opts.dry_run {
+                eprintln!("Would overwrite \"{}\"", file_path.display());
+                unpacked.insert(archive.read as usize - 1, file_path);
+                overwritten += 1;
+                continue;
+            }
+            // Remove whatever is in the way rather than writing through it, which would follow
+            // a symlink. Regular files are renamed over anything but a directory once they're
+            // complete instead, so a failure leaves the old file as it was.
+            let hard_link = file.inner.hard_link && source.is_some();
+            let renamed_over = !file.inner.is_dir() && !file.inner.is_symlink() && !hard_link;
+            if existing.is_dir() {
+                std::fs::remove_dir_all(&file_path)
+            } else if renamed_over {
+                Ok(())
+            } else {
+                std::fs::remove_file(&file_path)
+            }
+            .context(|| format!("Failed to remove \"{}\"", file_path.display()))?;
+            symlinks.retain(|(path, ..)| !path.starts_with(&file_path));
+        }
+        for dir in member.ancestors().skip(1) {
+            kinds.insert(dir.to_path_buf(), true);
+        }
+        kinds.insert(member.clone(), file.inner.is_dir());
+        let counter = match existing {
+            Some(_) => &mut overwritten,
+            None => &mut created,
+        };
+        // The data of files that aren't written is skipped over by the next call
+        if opts.dry_run {
+            eprintln!("Would create \"{}\"", file_path.display());
+            unpacked.insert(archive.read as usize - 1, file_path);
+            *counter += 1;
+            continue;
+        }
+        if let Some(parent) = file_path.parent() {
+            if !parent.exists() {
+                create_parents(parent, opts.dir_mode)
+                    .context(|| format!("Failed to create \"{}\"", parent.display()))?;
+            }
+        }
+        let writing = || format!("Failed to write \"{}\"", file_path.display());
+        let reading = || format!("Failed to decompress \"{}\"", file.name);
+        file.inner.compression().context(reading)?;
+
+        if file.inner.is_dir() {
+            if !opts.quiet {
+                eprintln!("Creating \"{}\"", file_path.display());
+            }
+            std::fs::create_dir(&file_path).context(writing)?;
+            if preserve_owner {
+                restore_owner(&file_path, &file.inner, &mut owner_denied).context(writing)?;
+            }
+            if !opts.no_preserve_mode {
+                platform::set_mode(&file_path, file.inner.mode & !opts.mode_mask)
+                    .context(writing)?;
+            }
+            *counter += 1;
+            continue;
+        }
+
+        if file.inner.is_symlink() {
+            let mut target = vec![];
+            decompress(&opts, &file.inner, &file.data, &mut target).context(reading)?;
+            let target = std::path::PathBuf::from(platform::os_string(target));
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&file_path)
+                .context(writing)?;
+            symlinks.push((file_path, target, file.inner, existing.is_some()));
+            *counter += 1;
+            continue;
+        }
+
+        if let (Some(source), true) = (&source, file.inner.hard_link) {
+            if !opts.quiet {
+                eprintln!(
+                    "Linking \"{}\" => \"{}\"",
+                    file_path.display(),
+                    source.display()
+                );
+            }
+            std::fs::hard_link(source, &file_path).context(writing)?;
+            unpacked.insert(archive.read as usize - 1, file_path);
+            *counter += 1;
+            continue;
+        }
+
+        let earlier = match (go_back, start) {
+            (Some(target), Some(start)) => {
+                let position = archive.position().context(reading)?;
+                resume = position.map(|x| (x, archive.read));
+                let options = ReadOptions {
+                    only: None,
+                    ..options
+                };
+                Some(archive.go_back_to(target, index.as_ref(), start, &file.name, options)?)
+            }
+            _ => None,
+        };
+        // The header to read the data by. Once it's written, the earlier file counts as unpacked
+        // here, for the other files that share its data.
+        let data_file = earlier.as_ref().unwrap_or(&file);
+
+        // The file is written next to where it goes and only renamed into place once it's
+        // complete, so a failure never leaves part of it behind to be mistaken for all of it
+        let partial = PartialFile::new(&file_path);
+        let mut output = std::fs::File::create(&partial.path).context(writing)?;
+        // Changing the owner clears the setuid and setgid bits, so it goes first
+        if preserve_owner {
+            restore_owner(&partial.path, &file.inner, &mut owner_denied).context(writing)?;
+        }
+        if !opts.no_preserve_mode {
+            platform::set_mode(&partial.path, file.inner.mode & !opts.mode_mask)
+                .context(writing)?;
+        }
+
+        if !opts.quiet {
+            eprintln!("Writing \"{}\" -> \"{}\"", file.name, file_path.display());
+        }
+        match (source, opts.line_endings) {
+            (Some(source), _) => {
+                let mut source = std::fs::File::open(&source)
+                    .context(|| format!("Failed to open \"{}\"", source.display()))?;
+                io::copy(&mut source, &mut output).context(writing)?;
+            }
+            (None, Some(line_endings)) => {
+                // The data and its decompressed contents are both in memory at once
+                // The lengths come from the archive, and ones too large to add up are over any
+                // limit
+                let needed = data_file
+                    .size()
+                    .checked_mul(2)
+                    .and_then(|x| x.checked_add(data_file.inner.len))
+                    .unwrap_or(u64::MAX);
+                if let Some(max) = opts.max_memory.filter(|&max| needed > max) {
+                    return Err(MarkError::Usage(format!("Converting the line endings of \"{}\" needs {needed} bytes of memory, more than the -max-memory limit of {max}",
+                        file.name)));
+                }
+                let mut contents = vec![];
+                match &earlier {
+                    // Only the data read up front has been checked already
+                    Some(earlier) => {
+                        decrypted(&opts, earlier, archive.data())
+                            .and_then(|mut data| {
+                                decompress_from(&opts, &earlier.inner, &mut data, &mut contents)
+                            })
+                            .context(reading)?;
+                        if !archive.finish_data(earlier).context(reading)? {
+                            eprintln!("Not writing \"{}\", its checksum doesn't match", file.name);
+                            corrupt += 1;
+                            continue;
+                        }
+                    }
+                    None => decompress(&opts, &file.inner, &file.data, &mut contents)
+                        .context(reading)?,
+                }
+                if let Some(sparse) = &data_file.sparse {
+                    let mut expanded = vec![];
+                    Unsparse::new(&contents[..], sparse)
+                        .read_to_end(&mut expanded)
+                        .context(reading)?;
+                    contents = expanded;
+                }
+                let sample = &contents[..contents.len().min(SAMPLE_SIZE)];
+                if is_text(std::path::Path::new(&file.name), sample) {
+                    contents = convert_line_endings(&contents, line_endings.crlf_on_unpack());
+                }
+                output.write_all(&contents).context(writing)?;
+            }
+            (None, None) => {
+                let result = decrypted(&opts, data_file, archive.data()).and_then(|mut data| {
+                    match &data_file.sparse {
+                        // Seeking over the holes leaves them as holes
+                        Some(sparse) => {
+                            let mut writer = SparseWriter::new(&mut output, sparse);
+                            decompress_from(&opts, &data_file.inner, &mut data, &mut writer)?;
+                            output.set_len(sparse.size)
+                        }
+                        None => decompress_from(&opts, &data_file.inner, &mut data, &mut output),
+                    }
+                });
+                let intact = archive.finish_data(data_file);
+                if result.is_err() || !matches!(intact, Ok(true)) {
+                    // The partial file is removed on the way out
+                    if let Ok(false) = intact {
+                        eprintln!("Not writing \"{}\", its checksum doesn't match", file.name);
+                        corrupt += 1;
+                        continue;
+                    }
+                    match intact.context(reading) {
+                        Err(e) if cut_short(&e) => {
+                            truncated = Some((e, archive.read - 1));
+                            break;
+                        }
+                        intact => intact?,
+                    };
+                    return result.context(writing);
+                }
+            }
+        }
+
+        // set these after all the modifications are done so the changes stick
+        output
+            .set_times(
+                fs::FileTimes::new()
+                    .set_accessed(file.accessed_time())
+                    .set_modified(file.modified_time()),
+            )
+            .context(writing)?;
+        drop(output);
+        partial.finish().context(writing)?;
+        unpacked.insert(archive.read as usize - 1, file_path);
+        *counter += 1;
+    }
+    // Whether a link escapes can depend on the links it goes through, wherever they are in the
+    // archive, so it's only checked once all of them are known
+    let links: Vec<_> = symlinks.iter().map(|(path, ..)| path.clone()).collect();
+    for (file_path, target, file, existed) in symlinks {
+        let writing = || format!("Failed to write \"{}\"", file_path.display());
+        let member = file_path.strip_prefix(&output_dir).unwrap_or(&file_path);
+        if !opts.allow_external_symlinks && symlink_escapes(&output_dir, member, &target, &links) {
+            eprintln!(
+                "Not creating \"{}\", it links outside of the output directory (to \"{}\")",
+                file_path.display(),
+                target.display()
+            );
+            std::fs::remove_file(&file_path).context(writing)?;
+            match existed {
+                true => overwritten -= 1,
+                false => created -= 1,
+            }
+            continue;
+        }
+        if !opts.quiet {
+            eprintln!(
+                "Linking \"{}\" -> \"{}\"",
+                file_path.display(),
+                target.display()
+            );
+        }
+        std::fs::remove_file(&file_path).context(writing)?;
+        platform::symlink(&target, &file_path).context(writing)?;
+        if preserve_owner {
+            restore_owner(&file_path, &file, &mut owner_denied).context(writing)?;
+        }
+    }
+    progress.finish();
+
+    if opts.dry_run {
+        eprintln!(
+            "Would create {created} file(s), overwrite {overwritten}, and leave {untouched} existing file(s) untouched"
+        );
+    } else if !opts.quiet {
+        eprintln!(
+            "Created {created} file(s), overwrote {overwritten}, and left {untouched} existing file(s) untouched"
+        );
+    }
+    // A streamed archive can also end early right between two files
+    if opts.salvage && truncated.is_none() && !archive.ended_cleanly {
+        truncated = Some((
+            MarkError::Invalid("The archive ends before its end-of-archive marker".into()),
+            archive.read,
+        ));
+    }
+    if let Some((e, recovered)) = truncated {
+        return Err(MarkError::Truncated(format!(
+            "{e}\nOnly the {recovered} whole file(s) before that could be unpacked"
+        )));
+    }
+    if opts.strict {
+        check_rest(&mut archive)?;
+    }
+    let missing = names
+        .iter()
+        .zip(found)
+        .filter(|(_, found)| !found)
+        .map(|(name, _)| format!("\"{name}\""))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(MarkError::Usage(format!(
+            "The archive doesn't contain {}",
+            missing.join(", ")
+        )));
+    }
+    if corrupt > 0 {
+        return Err(MarkError::Corrupt(format!(
+            "{corrupt} file(s) were corrupt and weren't written"
+        )));
+    }
+    if unlinked > 0 {
+        return Err(MarkError::Usage(format!(
+            "{unlinked} file(s) share their data with files that weren't unpacked, so they weren't written either"
+        )));
+    }
+    if conflicting > 0 {
+        return Err(MarkError::Invalid(format!(
+            "{conflicting} file(s) weren't written, their names clash with directories or files"
+        )));
+    }
+    Ok(())
+}
+
+/// A file being unpacked under a temporary name next to `target`, which is removed when it's
+/// dropped unless it was renamed to `target` with [`PartialFile::finish`].
+struct PartialFile {
+    pub(crate) path: std::path::PathBuf,
+    pub(crate) target: std::path::PathBuf,
+    pub(crate) finished: bool,
+}
+
+impl PartialFile {
+    pub(crate) fn new(target: &std::path::Path) -> Self {
+        let mut name = std::ffi::OsString::from(".");
+        name.push(target.file_name().unwrap_or_default());
+        name.push(format!(".mark-partial-{}", std::process::id()));
+        Self {
+            path: target.with_file_name(name),
+            target: target.to_path_buf(),
+            finished: false,
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        std::fs::rename(&self.path, &self.target)?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Gives `path` (or the symlink itself) the owner and group in `file`. Only root can do that, so
+/// anyone else gets a single warning, through `denied`, and keeps owning what they unpack.
+fn restore_owner(path: &std::path::Path, file: &FileHeader, denied: &mut bool) -> io::Result<()> {
+    match platform::lchown(path, file.uid, file.gid) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            if !*denied {
+                eprintln!(
+                    "Warning: not restoring the owners of unpacked files, which needs root ({e})"
+                );
+                *denied = true;
+            }
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// What `unpack` does when a file it's extracting already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum OnConflict {
+    /// Leave the existing file alone.
+    #[default]
+    Skip,
+    /// Replace whatever is in the way, even a directory.
+    Overwrite,
+    /// Replace existing files, but never a directory, so the archive is merged into an existing
+    /// tree.
+    Merge,
+    /// Like [`OnConflict::Merge`], but only replace files older than the one in the archive.
+    KeepNewer,
+}
+
+impl OnConflict {
+    /// Whether `existing` should be replaced by the archived `file`.
+    pub(crate) fn replaces(self, existing: &std::fs::Metadata, file: &FileHeader) -> bool {
+        match self {
+            Self::Skip => false,
+            Self::Overwrite => true,
+            Self::Merge => !existing.is_dir(),
+            Self::KeepNewer => timestamp(file.modified, 0).is_some_and(|modified| {
+                !existing.is_dir() && existing.modified().is_ok_and(|x| x < modified)
+            }),
+        }
+    }
+}
+
+impl std::str::FromStr for OnConflict {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "skip" => Self::Skip,
+            "overwrite" => Self::Overwrite,
+            "merge" => Self::Merge,
+            "keep-newer" => Self::KeepNewer,
+            _ => return Err("unsupported conflict policy"),
+        })
+    }
+}
+
+/// Turns the name of a file in an archive into a path relative to the output directory, or
+/// `None` if it's absolute or has `..` components that could take it outside of the output
+/// directory.
+pub(crate) fn sanitize_member_name(name: &std::path::Path) -> Option<std::path::PathBuf> {
+    use std::path::Component;
+
+    let mut member = std::path::PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(component) => member.push(component),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!member.as_os_str().is_empty()).then_some(member)
+}
+
+/// Creates `dir` and any of its parents that are missing, giving the ones it creates the
+/// permissions in `mode` if there are any.
+fn create_parents(dir: &std::path::Path, mode: Option<u32>) -> io::Result<()> {
+    // Innermost first, so that permissions without search access don't get in the way
+    let missing = dir
+        .ancestors()
+        .take_while(|x| !x.as_os_str().is_empty() && !x.exists())
+        .collect::<Vec<_>>();
+    std::fs::create_dir_all(dir)?;
+    if let Some(mode) = mode {
+        for dir in missing {
+            platform::set_mode(dir, mode)?;
+        }
+    }
+    Ok(())
+}
+
+/// Finds something other than a directory at one of the parents of `member` in `output_dir`,
+/// which would keep it from being written there. A symlink counts even if it points to a
+/// directory, since writing through one could end up anywhere.
+fn non_directory_above(
+    output_dir: &std::path::Path,
+    member: &std::path::Path,
+) -> Option<std::path::PathBuf> {
+    member
+        .ancestors()
+        .skip(1)
+        .filter(|x| !x.as_os_str().is_empty())
+        .map(|x| output_dir.join(x))
+        .find(|x| x.symlink_metadata().is_ok_and(|x| !x.is_dir()))
+}
+
+/// Checks whether a symlink at `member` (relative to `output_dir`) pointing to `target` would
+/// point outside of the output directory. Absolute targets always count as outside, since
+/// there's no telling where the output directory will be. So does going up out of another
+/// symlink, whether it's already in `output_dir` or one of the `links` still to be made, since
+/// where that ends up depends on where the other link points.
+fn symlink_escapes(
+    output_dir: &std::path::Path,
+    member: &std::path::Path,
+    target: &std::path::Path,
+    links: &[std::path::PathBuf],
+) -> bool {
+    use std::path::Component;
+
+    // The path being resolved, relative to the output directory
+    let mut path = member.parent().unwrap_or(member).to_path_buf();
+    let mut through_link = false;
+    for component in target.components() {
+        match component {
+            Component::Normal(name) => {
+                path.push(name);
+                let full = output_dir.join(&path);
+                through_link |= links.contains(&full) || full.is_symlink();
+            }
+            Component::CurDir => {}
+            Component::ParentDir if !through_link && path.pop() => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
@@ -0,0 +1,445 @@
+//! Writing archives file by file, into outputs that may or may not be seekable.
+
+use super::*;
+
+/// Writes the files of an archive one at a time, as they're given, for `pack` and
+/// [`StreamingArchiveWriter`]. Whether the archive has a file count is up to its header.
+pub(crate) struct RawArchiveWriter<W: Write> {
+    pub(crate) writer: W,
+    /// Where the header of the file last added by [`RawArchiveWriter::add_streamed`] is.
+    last_streamed: u64,
+    /// How many files are in the archive so far.
+    pub(crate) files: u32,
+    /// Where in the archive the writer is.
+    pub(crate) position: u64,
+    /// The index written after the files, if the archive has one.
+    pub(crate) index: Option<ArchiveIndex>,
+}
+
+impl<W: Write> RawArchiveWriter<W> {
+    /// Continues an archive whose header has already been written, with `writer` right after its
+    /// last file, the `files`th, which ends at `position`.
+    pub(crate) fn resume(writer: W, files: u32, position: u64) -> Self {
+        Self {
+            writer,
+            last_streamed: 0,
+            files,
+            position,
+            index: None,
+        }
+    }
+
+    /// Starts an archive with `header`, which decides whether it has a file count (if not, it's
+    /// [`UNKNOWN_FILE_COUNT`]) and an [`ArchiveIndex`], followed by `dictionary` if it has
+    /// [`DICTIONARY`].
+    pub(crate) fn with_header(
+        mut writer: W,
+        header: ArchiveHeader,
+        dictionary: Option<&[u8]>,
+    ) -> io::Result<Self> {
+        header.write(&mut writer)?;
+        let mut position = header.size();
+        if let Some(dictionary) = dictionary {
+            position += write_dictionary(&mut writer, dictionary)?;
+        }
+        let mut archive = Self::resume(writer, 0, position);
+        if header.is_indexed() {
+            archive.index = Some(ArchiveIndex::default());
+        }
+        Ok(archive)
+    }
+
+    pub(crate) fn add(&mut self, file: &FileHeaderRepr) -> io::Result<()> {
+        let mut writer = CountingWriter::new(&mut self.writer);
+        file.write(&mut writer, Layout::for_version(CURRENT_VERSION))?;
+        let offset = self.position;
+        self.position += writer.count;
+        self.files += 1;
+        if let Some(index) = &mut self.index {
+            index.push(file, offset);
+        }
+        Ok(())
+    }
+}
+
+impl<W: ArchiveOutput> RawArchiveWriter<W> {
+    /// Adds `file` with its data read from `data` as it's written, instead of from `file.data`,
+    /// then seeks back to fill in the length and checksum of the data in its header. If the
+    /// archive can't be seeked in, the data is written in frames instead, see [`FRAMED_DATA`].
+    pub(crate) fn add_streamed(
+        &mut self,
+        file: &mut FileHeaderRepr,
+        data: &mut dyn Read,
+    ) -> io::Result<()> {
+        let offset = self.position;
+        if self.writer.seekable().is_none() {
+            return self.add_framed(file, data);
+        }
+        let writer = self.seekable()?;
+        if let Some(extended) = file.extended() {
+            extended.write(writer)?;
+        }
+        self.last_streamed = writer.stream_position()?;
+        self.stream_file(file, data)?;
+        self.files += 1;
+        if let Some(index) = &mut self.index {
+            index.push(file, offset);
+        }
+        Ok(())
+    }
+
+    /// Adds `file` with its data read from `data` in frames, for [`Self::add_streamed`]. The
+    /// header of `file` is left as if it had been written without them.
+    fn add_framed(&mut self, file: &mut FileHeaderRepr, data: &mut dyn Read) -> io::Result<()> {
+        assert!(file.data.is_empty());
+        let offset = self.position;
+        file.inner.data_compression |= FRAMED_DATA;
+        (file.inner.len, file.inner.crc32) = (0, 0);
+        let mut writer = CountingWriter::new(&mut self.writer);
+        file.write_header_only(&mut writer, Layout::for_version(CURRENT_VERSION))?;
+        let (len, crc) = write_frames(data, &mut writer)?;
+        writer.write_all(&crc.to_le_bytes())?;
+        self.position += writer.count;
+        file.inner.data_compression &= !FRAMED_DATA;
+        (file.inner.len, file.inner.crc32) = (len, crc);
+        self.files += 1;
+        if let Some(index) = &mut self.index {
+            index.push(file, offset);
+        }
+        Ok(())
+    }
+
+    /// Writes `file` over the one last added by [`RawArchiveWriter::add_streamed`], which
+    /// must have had the same extended metadata. Whatever's left of the old file past the new
+    /// one is overwritten by the files after it, or cut off by [`RawArchiveWriter::finish`].
+    pub(crate) fn replace_streamed(
+        &mut self,
+        file: &mut FileHeaderRepr,
+        data: &mut dyn Read,
+    ) -> io::Result<()> {
+        let header_position = self.last_streamed;
+        self.seekable()?
+            .seek(io::SeekFrom::Start(header_position))?;
+        self.stream_file(file, data)?;
+        if let Some(index) = &mut self.index {
+            let offset = index.entries.pop().map_or(0, |x| x.offset);
+            index.push(file, offset);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn seekable(&mut self) -> io::Result<&mut dyn WriteSeek> {
+        self.writer.seekable().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the archive can't be seeked in to stream files into it",
+            )
+        })
+    }
+
+    /// Writes the header, name, and data of `file`, starting at [`Self::last_streamed`].
+    fn stream_file(&mut self, file: &mut FileHeaderRepr, data: &mut dyn Read) -> io::Result<()> {
+        let layout = Layout::for_version(CURRENT_VERSION);
+        let header_position = self.last_streamed;
+        let writer = self.seekable()?;
+        assert!(layout.name_before_data && file.data.is_empty());
+        // The extended metadata is already written, before `header_position`
+        file.inner.write(writer, layout)?;
+        writer.write_all(file.stored_name())?;
+
+        let mut crc = crc32fast::Hasher::new();
+        let mut len = 0u64;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = match data.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            crc.update(&buf[..n]);
+            writer.write_all(&buf[..n])?;
+            len += n as u64;
+        }
+        file.inner.len = len;
+        file.inner.crc32 = crc.finalize();
+
+        let end = writer.stream_position()?;
+        writer.seek(io::SeekFrom::Start(header_position))?;
+        file.inner.write(writer, layout)?;
+        writer.seek(io::SeekFrom::Start(end))?;
+        self.position = end;
+        Ok(())
+    }
+
+    /// Ends the archive, writing its index if it has one, and returns the underlying writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(&END_OF_ARCHIVE)?;
+        if let Some(index) = &self.index {
+            index.write(
+                &mut self.writer,
+                self.position + END_OF_ARCHIVE.len() as u64,
+            )?;
+        }
+        self.writer.truncate()?;
+        Ok(self.writer)
+    }
+}
+
+/// Writes an archive from other programs, one file at a time. The files are regular files with
+/// the default permissions and the current time, compressed in memory with brotli unless told
+/// otherwise.
+///
+/// The archive has no file count up front, like one packed with `-stream`, so files can be
+/// written as they come in without knowing how many there will be. Readers find its end by its
+/// end-of-archive marker instead, which means that, unlike an archive with a file count, it
+/// can't be checked for completeness before reading all of it.
+pub struct StreamingArchiveWriter<W: Write> {
+    pub(crate) archive: RawArchiveWriter<Unseekable<W>>,
+    pub(crate) opts: Opts,
+}
+
+/// Writes an archive from other programs, see [`StreamingArchiveWriter`]. Archives written
+/// without knowing their files up front can't record how many there are, so every archive it
+/// writes is a streaming one.
+///
+/// ```
+/// let archive = mark::ArchiveWriter::new(Vec::new())?
+///     .add_file("a.txt", b"first")?
+///     .add_file("b/c.txt", b"second")?
+///     .finish()?;
+/// assert!(!archive.is_empty());
+/// # Ok::<(), mark::MarkError>(())
+/// ```
+pub type ArchiveWriter<W> = StreamingArchiveWriter<W>;
+
+impl<W: Write> StreamingArchiveWriter<W> {
+    /// Writes the archive header to `writer`.
+    pub fn new(writer: W) -> Result<Self, MarkError> {
+        let header = ArchiveHeader {
+            version: CURRENT_VERSION,
+            file_count: UNKNOWN_FILE_COUNT,
+            flags: 0,
+            encryption: None,
+        };
+        let archive = RawArchiveWriter::with_header(Unseekable(writer), header, None)
+            .context(|| "Failed to write the archive header".into())?;
+        Ok(Self {
+            archive,
+            opts: Opts::default(),
+        })
+    }
+
+    /// Compresses the files added after this with `compression`.
+    pub fn set_compression(&mut self, compression: DataCompression) {
+        self.opts.compression_method = compression;
+    }
+
+    /// Adds a file named `name` with `contents`, handing the writer back for the next one.
+    pub fn add_file(mut self, name: &str, contents: &[u8]) -> Result<Self, MarkError> {
+        let file = new_file(&self.opts, name, contents.to_vec())
+            .context(|| format!("Failed to compress \"{name}\""))?;
+        self.archive
+            .add(&file)
+            .context(|| format!("Failed to write \"{name}\""))?;
+        Ok(self)
+    }
+
+    /// Ends the archive and returns the writer it was written to.
+    pub fn finish(self) -> Result<W, MarkError> {
+        let writing = || "Failed to finish the archive".to_string();
+        let mut writer = self.archive.finish().context(writing)?;
+        writer.flush().context(writing)?;
+        Ok(writer.0)
+    }
+}
+
+/// Where an archive is written. Files are streamed into outputs that can seek, see
+/// [`RawArchiveWriter::add_streamed`].
+pub(crate) trait ArchiveOutput: Write {
+    fn seekable(&mut self) -> Option<&mut dyn WriteSeek> {
+        None
+    }
+
+    /// Cuts off anything past the current position, which can only be there if it seeked back.
+    fn truncate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Flushes everything written, ending the [`Container`] it's compressed in if there is one.
+    /// Nothing more can be written after.
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+pub(crate) trait WriteSeek: Write + io::Seek {}
+
+impl<T: Write + io::Seek> WriteSeek for T {}
+
+impl ArchiveOutput for BufWriter<std::fs::File> {
+    fn seekable(&mut self) -> Option<&mut dyn WriteSeek> {
+        // Pipes and the like can't seek
+        let is_file = self.get_ref().metadata().is_ok_and(|x| x.is_file());
+        is_file.then_some(self)
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        use io::Seek;
+
+        if self.seekable().is_none() {
+            return Ok(());
+        }
+        let len = self.stream_position()?;
+        self.flush()?;
+        self.get_ref().set_len(len)
+    }
+}
+
+impl ArchiveOutput for BufWriter<io::StdoutLock<'_>> {}
+
+/// How an archive file is compressed as a whole, going by its extension: `.gz` for gzip and
+/// `.zst` for zstd. That's on top of however the files in it are compressed, and keeps the
+/// archive from being seeked in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Container {
+    Gzip,
+    Zstd,
+}
+
+impl Container {
+    pub(crate) fn of(path: &str) -> Option<Self> {
+        match std::path::Path::new(path).extension()?.to_str()? {
+            "gz" => Some(Self::Gzip),
+            "zst" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// An archive file being written, compressed if its name says it's in a [`Container`].
+pub(crate) enum OutputFile {
+    Plain(BufWriter<std::fs::File>),
+    Gzip(flate2::write::GzEncoder<BufWriter<std::fs::File>>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<std::fs::File>>),
+}
+
+impl OutputFile {
+    pub(crate) fn create(path: &str) -> Result<Self, MarkError> {
+        let creating = || format!("Failed to create \"{path}\"");
+        let file = BufWriter::new(std::fs::File::create(path).context(creating)?);
+        Ok(match Container::of(path) {
+            Some(Container::Gzip) => Self::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+            Some(Container::Zstd) => {
+                Self::Zstd(zstd::stream::write::Encoder::new(file, 0).context(creating)?)
+            }
+            None => Self::Plain(file),
+        })
+    }
+
+    pub(crate) fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Self::Plain(file) => file,
+            Self::Gzip(encoder) => encoder,
+            Self::Zstd(encoder) => encoder,
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer().flush()
+    }
+}
+
+impl ArchiveOutput for OutputFile {
+    fn seekable(&mut self) -> Option<&mut dyn WriteSeek> {
+        match self {
+            Self::Plain(file) => file.seekable(),
+            _ => None,
+        }
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.truncate(),
+            _ => Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush(),
+            Self::Gzip(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut().flush()
+            }
+            Self::Zstd(encoder) => {
+                encoder.do_finish()?;
+                encoder.get_mut().flush()
+            }
+        }
+    }
+}
+
+impl<W: Write> ArchiveOutput for TextEncoder<W> {}
+
+/// Passes writes on to a writer that's never seeked in, see [`StreamingArchiveWriter`].
+pub(crate) struct Unseekable<W: Write>(pub(crate) W);
+
+impl<W: Write> Write for Unseekable<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> ArchiveOutput for Unseekable<W> {}
+
+impl<W: Write> ArchiveOutput for HashingWriter<W> {}
+
+impl<T: ArchiveOutput + ?Sized> ArchiveOutput for &mut T {
+    fn seekable(&mut self) -> Option<&mut dyn WriteSeek> {
+        (**self).seekable()
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        (**self).truncate()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        (**self).close()
+    }
+}
+
+/// Writes `data` to `writer` in frames up to the one that ends them, leaving out the CRC32 that
+/// follows, see [`FRAMED_DATA`]. Returns how long the data is and its CRC32.
+pub(crate) fn write_frames(data: &mut dyn Read, writer: &mut dyn Write) -> io::Result<(u64, u32)> {
+    let mut crc = crc32fast::Hasher::new();
+    let mut len = 0u64;
+    let mut frame = vec![0u8; FRAME_LEN];
+    loop {
+        let n = read_up_to(data, &mut frame)?;
+        if n > 0 {
+            writer.write_all(&(n as u32).to_le_bytes())?;
+            writer.write_all(&frame[..n])?;
+            crc.update(&frame[..n]);
+            len += n as u64;
+        }
+        if n < frame.len() {
+            break;
+        }
+    }
+    writer.write_all(&0u32.to_le_bytes())?;
+    Ok((len, crc.finalize()))
+}